@@ -0,0 +1,9 @@
+/// A limit whose state can be handed to the program replacing it, so the
+/// replacement neither starts with a fresh burst nor forgets a debt
+pub trait BucketLevel {
+    /// Bytes that could be sent at `now` without waiting
+    fn level(&self, now: u64) -> u64;
+
+    /// Starts the limit at `level` bytes instead of full, as of `now`
+    fn seed(&mut self, level: u64, now: u64);
+}
@@ -0,0 +1,31 @@
+/// Slots of the `CONFIG` array shared by every program in the object
+pub const CONFIG_LOG_LEVEL: u32 = 0;
+/// Bytes of link layer framing or encapsulation added to every packet
+pub const CONFIG_OVERHEAD: u32 = 1;
+/// Minimum bytes a packet is charged, after the overhead was added
+pub const CONFIG_MPU: u32 = 2;
+/// Non zero when the limit only applies to the interfaces in `IFACE_FILTER`
+pub const CONFIG_IFACE_FILTER: u32 = 3;
+/// Non zero for the global limit, which lets packets a limit further down
+/// the cgroup tree dropped through without charging them
+pub const CONFIG_SKIP_DROPPED: u32 = 4;
+
+/// Number of slots in the `CONFIG` array
+pub const CONFIG_ENTRIES: u32 = 8;
+
+/// Mark a cgroup skb program leaves on the packets it drops. The kernel runs
+/// the programs of the packet's own cgroup before those of its parents, so the
+/// global limit on the root cgroup sees it. HTB classes leave minor 0xffff
+/// free, so no class mark looks like it.
+pub const DROPPED_MARK: u32 = 0x5254_ffff;
+
+/// Most interfaces a single limit can be scoped to
+pub const MAX_INTERFACES: u32 = 16;
+
+/// Log levels, a program logs everything at or below the configured level
+pub const LOG_OFF: u64 = 0;
+pub const LOG_ERROR: u64 = 1;
+pub const LOG_WARN: u64 = 2;
+pub const LOG_INFO: u64 = 3;
+pub const LOG_DEBUG: u64 = 4;
+pub const LOG_TRACE: u64 = 5;
@@ -0,0 +1,82 @@
+use crate::mode::{MODE_ENFORCE, MODE_OBSERVE};
+
+/// Earliest departure time pacing state for one cgroup.
+///
+/// Instead of dropping, packets are stamped with the time they may leave the
+/// host and the `fq` qdisc holds them back until then.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EdtLimit {
+    /// Bytes per second
+    pub rate: u64,
+
+    /// Packets scheduled further than this many nanoseconds ahead are dropped
+    pub horizon_ns: u64,
+
+    /// Departure timestamp in nanoseconds of the last scheduled packet
+    pub next_tns: u64,
+
+    pub id: u64,
+
+    /// `MODE_ENFORCE` or `MODE_OBSERVE`
+    pub mode: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for EdtLimit {}
+
+/// Same drop horizon the `fq` qdisc uses by default
+pub const DEFAULT_HORIZON_NS: u64 = 2_000_000_000;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+impl EdtLimit {
+    pub fn new(id: u64, rate: u64, horizon_ns: u64) -> Self {
+        Self {
+            id,
+            rate,
+            horizon_ns,
+            next_tns: 0,
+            mode: MODE_ENFORCE,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: u64) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn is_observing(&self) -> bool {
+        self.mode == MODE_OBSERVE
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Returns the departure time for a packet of `len` bytes that would
+    /// otherwise leave at `tstamp`, or `None` when it is past the horizon.
+    pub fn schedule(&mut self, now: u64, tstamp: u64, len: u64) -> Option<u64> {
+        if self.rate == 0 {
+            return Some(tstamp);
+        }
+
+        let delay = len.saturating_mul(NSEC_PER_SEC) / self.rate;
+        let next = self.next_tns.saturating_add(delay);
+        if next <= tstamp {
+            self.next_tns = tstamp;
+            return Some(tstamp);
+        }
+
+        if next.saturating_sub(now) > self.horizon_ns {
+            return None;
+        }
+
+        self.next_tns = next;
+        Some(next)
+    }
+}
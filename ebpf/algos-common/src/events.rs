@@ -0,0 +1,71 @@
+/// Sampled record of a packet a limiter program dropped.
+///
+/// Addresses are in network byte order, IPv4 addresses only use the first
+/// four bytes.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DropEvent {
+    /// Timestamp in nanoseconds since boot
+    pub tns: u64,
+
+    pub id: u64,
+
+    pub packet_len: u64,
+
+    /// Available bytes in the bucket when the packet was dropped
+    pub bucket: u64,
+
+    /// Capacity of the bucket in bytes per second
+    pub capacity: u64,
+
+    pub src_addr: [u8; 16],
+
+    pub dst_addr: [u8; 16],
+
+    /// `INGRESS_STATS` or `EGRESS_STATS`
+    pub direction: u32,
+
+    pub src_port: u16,
+
+    pub dst_port: u16,
+
+    /// 4 or 6, 0 when the header could not be read
+    pub ip_version: u8,
+
+    /// IP protocol number of the transport header
+    pub protocol: u8,
+
+    /// 1 when the bucket is observing and the packet was let through
+    pub observed: u8,
+
+    pub _pad: [u8; 5],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for DropEvent {}
+
+/// Upper bound of events each cpu writes into the ring buffer per second
+pub const MAX_DROP_EVENTS_PER_SEC: u64 = 64;
+
+/// Per cpu window used to rate limit drop events
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EventBudget {
+    pub window_tns: u64,
+    pub sent: u64,
+}
+
+impl EventBudget {
+    pub fn take(&mut self, now: u64) -> bool {
+        if now.saturating_sub(self.window_tns) >= 1_000_000_000 {
+            self.window_tns = now;
+            self.sent = 0;
+        }
+
+        if self.sent >= MAX_DROP_EVENTS_PER_SEC {
+            return false;
+        }
+        self.sent += 1;
+        true
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{
+    bucket::BucketLevel,
+    mode::{MODE_ENFORCE, MODE_OBSERVE},
+};
+
+/// Generic cell rate algorithm (virtual scheduling), a leaky bucket that only
+/// tracks the theoretical arrival time of the next packet.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GcraLimit {
+    /// Bytes per second
+    pub rate: u64,
+
+    /// Nanoseconds a packet may arrive ahead of its theoretical arrival time
+    pub tolerance_ns: u64,
+
+    /// Theoretical arrival time in nanoseconds of the next packet
+    pub tat: u64,
+
+    pub id: u64,
+
+    /// `MODE_ENFORCE` or `MODE_OBSERVE`
+    pub mode: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for GcraLimit {}
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+impl GcraLimit {
+    pub fn new(id: u64, rate: u64, tolerance_ns: u64) -> Self {
+        Self {
+            id,
+            rate,
+            tolerance_ns,
+            tat: 0,
+            mode: MODE_ENFORCE,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: u64) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn is_observing(&self) -> bool {
+        self.mode == MODE_OBSERVE
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns whether a packet of `len` bytes arriving at `now` conforms and
+    /// if so pushes the theoretical arrival time forward by its emission time.
+    pub fn conform(&mut self, now: u64, len: u64) -> bool {
+        if self.rate == 0 {
+            return false;
+        }
+
+        let tat = core::cmp::max(self.tat, now);
+        if tat - now > self.tolerance_ns {
+            return false;
+        }
+
+        self.tat = tat.saturating_add(len.saturating_mul(NSEC_PER_SEC) / self.rate);
+        true
+    }
+}
+
+impl BucketLevel for GcraLimit {
+    /// Bytes that could arrive back to back at `now` and still conform
+    fn level(&self, now: u64) -> u64 {
+        let ahead = core::cmp::min(self.tat.saturating_sub(now), self.tolerance_ns);
+        ((self.tolerance_ns - ahead) as u128 * self.rate as u128 / NSEC_PER_SEC as u128) as u64
+    }
+
+    /// Moves the theoretical arrival time so only `level` bytes conform at
+    /// `now`
+    fn seed(&mut self, level: u64, now: u64) {
+        if self.rate == 0 {
+            return;
+        }
+        let allowance = (level as u128 * NSEC_PER_SEC as u128 / self.rate as u128) as u64;
+        let ahead = self.tolerance_ns - core::cmp::min(allowance, self.tolerance_ns);
+        self.tat = now.saturating_add(ahead);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One byte per microsecond, 10µs ahead of schedule at most
+    fn limit() -> GcraLimit {
+        GcraLimit::new(1, 1_000_000, 10_000)
+    }
+
+    #[test]
+    fn tolerance_allows_a_burst() {
+        let mut limit = limit();
+        let now = 1_000_000;
+        assert!(limit.conform(now, 5));
+        assert!(limit.conform(now, 5));
+        assert!(limit.conform(now, 5));
+        assert_eq!(limit.tat, now + 15_000);
+
+        assert!(!limit.conform(now, 5));
+        assert_eq!(limit.tat, now + 15_000);
+        assert!(limit.conform(now + 5_000, 5));
+    }
+
+    #[test]
+    fn idle_time_is_not_saved_up() {
+        let mut limit = limit();
+        assert!(limit.conform(0, 5));
+        let now = 1_000_000_000;
+        assert!(limit.conform(now, 5));
+        assert_eq!(limit.tat, now + 5_000);
+    }
+
+    #[test]
+    fn zero_rate_never_conforms() {
+        let mut limit = GcraLimit::new(1, 0, 10_000);
+        assert!(!limit.conform(0, 1));
+        assert_eq!(limit.level(0), 0);
+    }
+
+    #[test]
+    fn level_round_trips_through_seed() {
+        let mut limit = limit();
+        let now = 1_000_000;
+        assert_eq!(limit.level(now), 10);
+
+        limit.seed(4, now);
+        assert_eq!(limit.tat, now + 6_000);
+        assert_eq!(limit.level(now), 4);
+        assert_eq!(limit.level(now + 6_000), 10);
+
+        limit.seed(1000, now);
+        assert_eq!(limit.level(now), 10);
+    }
+}
@@ -0,0 +1,29 @@
+/// Key every versioned map reserves for its `LayoutHeader`. Programs only
+/// look up their own keys, so the entry is invisible to them.
+pub const LAYOUT_KEY: u64 = u64::MAX;
+
+/// Marks the value under `LAYOUT_KEY` as a header rather than a bucket
+pub const LAYOUT_MAGIC: u64 = u64::from_le_bytes(*b"rtfglayt");
+
+/// Stored at the start of the value under `LAYOUT_KEY`, the rest of the value
+/// is zeroed. Lets a newer build tell which layout a pinned map was written
+/// with.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LayoutHeader {
+    pub magic: u64,
+    pub version: u64,
+}
+
+impl LayoutHeader {
+    pub fn new(version: u64) -> Self {
+        Self {
+            magic: LAYOUT_MAGIC,
+            version,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.magic == LAYOUT_MAGIC
+    }
+}
@@ -1,2 +1,11 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+pub mod bucket;
+pub mod config;
+pub mod edt;
+pub mod events;
+pub mod gcra;
+pub mod layout;
+pub mod mode;
+pub mod stats;
 pub mod token_bucket;
+pub mod tr_tcm;
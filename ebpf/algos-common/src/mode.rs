@@ -0,0 +1,6 @@
+/// Bucket drops packets that exceed the limit
+pub const MODE_ENFORCE: u64 = 0;
+
+/// Bucket runs the accounting but lets every packet pass, counting the ones
+/// it would have dropped
+pub const MODE_OBSERVE: u64 = 1;
@@ -0,0 +1,66 @@
+/// Per direction counters kept by every limiter program
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BucketStats {
+    pub passed_packets: u64,
+    pub passed_bytes: u64,
+    pub dropped_packets: u64,
+    pub dropped_bytes: u64,
+    /// Packets an observing bucket let through that exceeded the limit
+    pub would_drop_packets: u64,
+    pub would_drop_bytes: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Drop,
+    WouldDrop,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for BucketStats {}
+
+pub const INGRESS_STATS: u32 = 0;
+
+pub const EGRESS_STATS: u32 = 1;
+
+impl Verdict {
+    /// Verdict for a packet that is over the limit
+    pub fn exceeded(observing: bool) -> Self {
+        match observing {
+            true => Verdict::WouldDrop,
+            false => Verdict::Drop,
+        }
+    }
+}
+
+impl BucketStats {
+    pub fn record(&mut self, len: u64, verdict: Verdict) {
+        match verdict {
+            Verdict::Pass => {
+                self.passed_packets += 1;
+                self.passed_bytes += len;
+            }
+            Verdict::Drop => {
+                self.dropped_packets += 1;
+                self.dropped_bytes += len;
+            }
+            Verdict::WouldDrop => {
+                self.passed_packets += 1;
+                self.passed_bytes += len;
+                self.would_drop_packets += 1;
+                self.would_drop_bytes += len;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &BucketStats) {
+        self.passed_packets += other.passed_packets;
+        self.passed_bytes += other.passed_bytes;
+        self.dropped_packets += other.dropped_packets;
+        self.dropped_bytes += other.dropped_bytes;
+        self.would_drop_packets += other.would_drop_packets;
+        self.would_drop_bytes += other.would_drop_bytes;
+    }
+}
@@ -1,3 +1,13 @@
+use crate::{
+    bucket::BucketLevel,
+    mode::{MODE_ENFORCE, MODE_OBSERVE},
+};
+
+/// Layout of `TokenLimit`. Bump it whenever a field is added and keep the
+/// previous layout in `legacy`, so maps pinned by older builds can be
+/// migrated.
+pub const TOKEN_LIMIT_VERSION: u64 = 3;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default)]
 pub struct TokenLimit {
@@ -14,11 +24,80 @@ pub struct TokenLimit {
     pub last_tns: u64,
 
     pub id: u64,
+
+    /// `MODE_ENFORCE` or `MODE_OBSERVE`
+    pub mode: u64,
+
+    /// Bytes owed for a packet larger than the whole bucket, paid back from
+    /// refills before the bucket fills up again
+    pub debt: u64,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for TokenLimit {}
 
+/// Layouts of `TokenLimit` written by older builds
+pub mod legacy {
+    use super::TokenLimit;
+    use crate::mode::MODE_ENFORCE;
+
+    /// Layout 1, before observe mode
+    #[repr(C)]
+    #[derive(Copy, Clone, Debug, Default)]
+    pub struct TokenLimitV1 {
+        pub token_capacity: u64,
+        pub token_bucket: u64,
+        pub burst_period: u64,
+        pub last_tns: u64,
+        pub id: u64,
+    }
+
+    /// Layout 2, before oversized packets could leave the bucket in debt
+    #[repr(C)]
+    #[derive(Copy, Clone, Debug, Default)]
+    pub struct TokenLimitV2 {
+        pub token_capacity: u64,
+        pub token_bucket: u64,
+        pub burst_period: u64,
+        pub last_tns: u64,
+        pub id: u64,
+        pub mode: u64,
+    }
+
+    #[cfg(feature = "user")]
+    unsafe impl aya::Pod for TokenLimitV1 {}
+
+    #[cfg(feature = "user")]
+    unsafe impl aya::Pod for TokenLimitV2 {}
+
+    impl From<TokenLimitV1> for TokenLimitV2 {
+        fn from(limit: TokenLimitV1) -> Self {
+            Self {
+                token_capacity: limit.token_capacity,
+                token_bucket: limit.token_bucket,
+                burst_period: limit.burst_period,
+                last_tns: limit.last_tns,
+                id: limit.id,
+                mode: MODE_ENFORCE,
+            }
+        }
+    }
+
+    impl From<TokenLimitV2> for TokenLimit {
+        fn from(limit: TokenLimitV2) -> Self {
+            Self {
+                token_capacity: limit.token_capacity,
+                token_bucket: limit.token_bucket,
+                burst_period: limit.burst_period,
+                last_tns: limit.last_tns,
+                id: limit.id,
+                mode: limit.mode,
+                debt: 0,
+            }
+        }
+    }
+}
+
 pub const EGRESS_BUCKET: &str = "MANAGED_BUCKETS";
 
 pub const INGRESS_BUCKET: &str = "MANAGED_BUCKETS";
@@ -35,8 +114,19 @@ impl TokenLimit {
             burst_period: burst,
             token_bucket: token_capacity,
             last_tns: 0,
+            mode: MODE_ENFORCE,
+            debt: 0,
         }
     }
+
+    pub fn with_mode(mut self, mode: u64) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn is_observing(&self) -> bool {
+        self.mode == MODE_OBSERVE
+    }
     pub fn capacity(&self) -> u64 {
         self.token_capacity
     }
@@ -52,17 +142,144 @@ impl TokenLimit {
     pub fn last_tns(&self) -> u64 {
         self.last_tns
     }
+    pub fn debt(&self) -> u64 {
+        self.debt
+    }
+
+    /// Whether a packet of `len` bytes may pass. A packet that can never fit,
+    /// like a 64 KB GSO skb at a low rate, passes once the bucket is full and
+    /// leaves it in debt instead of being refused forever.
+    pub fn fits(&self, len: u64) -> bool {
+        if self.token_bucket >= len {
+            return true;
+        }
+        self.debt == 0 && self.token_capacity > 0 && self.token_bucket == self.token_capacity
+    }
 
     pub fn update_last_tns(&mut self, now: u64) {
         self.last_tns = now;
     }
 
     pub fn consume(&mut self, count: u64) {
+        if count > self.token_bucket {
+            self.debt = self.debt.saturating_add(count - self.token_bucket);
+        }
         self.token_bucket = self.token_bucket.saturating_sub(count);
     }
 
     pub fn refill(&mut self, count: u64) {
-        self.token_bucket =
-            core::cmp::min(self.token_capacity, self.token_bucket.saturating_add(count));
+        let repaid = core::cmp::min(self.debt, count);
+        self.debt -= repaid;
+        self.token_bucket = core::cmp::min(
+            self.token_capacity,
+            self.token_bucket.saturating_add(count - repaid),
+        );
+    }
+}
+
+impl BucketLevel for TokenLimit {
+    /// Bytes the bucket could send right now, debt counts as empty. Refills
+    /// are only accounted by the program, so `now` is not needed.
+    fn level(&self, _now: u64) -> u64 {
+        match self.debt {
+            0 => self.token_bucket,
+            _ => 0,
+        }
+    }
+
+    fn seed(&mut self, level: u64, now: u64) {
+        self.token_bucket = core::cmp::min(level, self.token_capacity);
+        self.debt = 0;
+        self.last_tns = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        legacy::{TokenLimitV1, TokenLimitV2},
+        *,
+    };
+
+    #[test]
+    fn refills_up_to_capacity() {
+        let mut limit = TokenLimit::new(1, 1000, 0);
+        assert!(limit.fits(600));
+        limit.consume(600);
+        assert_eq!(limit.bucket(), 400);
+        assert!(!limit.fits(600));
+
+        limit.refill(2000);
+        assert_eq!(limit.bucket(), 1000);
+        assert_eq!(limit.debt(), 0);
+    }
+
+    #[test]
+    fn oversized_packets_pass_a_full_bucket_into_debt() {
+        let mut limit = TokenLimit::new(1, 1000, 0);
+        assert!(limit.fits(1500));
+        limit.consume(1500);
+        assert_eq!((limit.bucket(), limit.debt()), (0, 500));
+        assert!(!limit.fits(1));
+
+        // Refills pay the debt back before the bucket fills
+        limit.refill(300);
+        assert_eq!((limit.bucket(), limit.debt()), (0, 200));
+        limit.refill(1500);
+        assert_eq!((limit.bucket(), limit.debt()), (1000, 0));
+        assert!(limit.fits(1500));
+    }
+
+    #[test]
+    fn partly_full_bucket_refuses_oversized_packets() {
+        let mut limit = TokenLimit::new(1, 1000, 0);
+        limit.consume(1);
+        assert!(!limit.fits(1500));
+        assert!(!TokenLimit::new(1, 0, 0).fits(1));
+    }
+
+    #[test]
+    fn level_counts_debt_as_empty() {
+        let mut limit = TokenLimit::new(1, 1000, 0);
+        limit.consume(400);
+        assert_eq!(limit.level(0), 600);
+        limit.consume(1000);
+        assert_eq!(limit.level(0), 0);
+
+        limit.seed(5000, 42);
+        assert_eq!(
+            (limit.bucket(), limit.debt(), limit.last_tns()),
+            (1000, 0, 42)
+        );
+        limit.seed(300, 43);
+        assert_eq!(limit.level(43), 300);
+    }
+
+    #[test]
+    fn legacy_layouts_upgrade_to_enforcing_without_debt() {
+        let v1 = TokenLimitV1 {
+            token_capacity: 1000,
+            token_bucket: 700,
+            burst_period: 5,
+            last_tns: 42,
+            id: 7,
+        };
+        let limit = TokenLimit::from(TokenLimitV2::from(v1));
+        assert_eq!(
+            (
+                limit.capacity(),
+                limit.bucket(),
+                limit.burst(),
+                limit.last_tns()
+            ),
+            (1000, 700, 5, 42)
+        );
+        assert_eq!((limit.id(), limit.mode, limit.debt()), (7, MODE_ENFORCE, 0));
+
+        let v2 = TokenLimitV2 {
+            mode: MODE_OBSERVE,
+            ..TokenLimitV2::from(v1)
+        };
+        assert!(TokenLimit::from(v2).is_observing());
     }
 }
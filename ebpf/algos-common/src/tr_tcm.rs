@@ -0,0 +1,262 @@
+use crate::{
+    bucket::BucketLevel,
+    mode::{MODE_ENFORCE, MODE_OBSERVE},
+};
+
+/// Two rate three color marker (RFC 2698).
+///
+/// Packets that fit the committed bucket are green, packets that only fit the
+/// peak bucket are yellow and everything else is red.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TrTcmLimit {
+    /// Committed information rate in bytes per second
+    pub committed_rate: u64,
+
+    /// Committed burst size in bytes
+    pub committed_burst: u64,
+
+    /// Current available bytes in the committed bucket
+    pub committed_tokens: u64,
+
+    /// Peak information rate in bytes per second
+    pub peak_rate: u64,
+
+    /// Peak burst size in bytes
+    pub peak_burst: u64,
+
+    /// Current available bytes in the peak bucket
+    pub peak_tokens: u64,
+
+    /// Timestamp in nanoseconds for last refill
+    pub last_tns: u64,
+
+    pub id: u64,
+
+    /// `MODE_ENFORCE` or `MODE_OBSERVE`
+    pub mode: u64,
+
+    /// Bytes owed to each bucket for a packet larger than the whole bucket,
+    /// paid back from refills before the bucket fills up again
+    pub committed_debt: u64,
+    pub peak_debt: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for TrTcmLimit {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Color {
+    Green,
+    Yellow,
+    Red,
+}
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+impl TrTcmLimit {
+    pub fn new(
+        id: u64,
+        committed_rate: u64,
+        committed_burst: u64,
+        peak_rate: u64,
+        peak_burst: u64,
+    ) -> Self {
+        Self {
+            id,
+            committed_rate,
+            committed_burst,
+            committed_tokens: committed_burst,
+            peak_rate,
+            peak_burst,
+            peak_tokens: peak_burst,
+            last_tns: 0,
+            mode: MODE_ENFORCE,
+            committed_debt: 0,
+            peak_debt: 0,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: u64) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn is_observing(&self) -> bool {
+        self.mode == MODE_OBSERVE
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn last_tns(&self) -> u64 {
+        self.last_tns
+    }
+
+    pub fn refill(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.last_tns);
+        let committed = self.committed_rate.saturating_mul(elapsed) / NSEC_PER_SEC;
+        let peak = self.peak_rate.saturating_mul(elapsed) / NSEC_PER_SEC;
+
+        refill(
+            &mut self.committed_tokens,
+            &mut self.committed_debt,
+            self.committed_burst,
+            committed,
+        );
+        refill(
+            &mut self.peak_tokens,
+            &mut self.peak_debt,
+            self.peak_burst,
+            peak,
+        );
+        self.last_tns = now;
+    }
+
+    /// A packet larger than a bucket, like a 64 KB GSO skb, fits it once the
+    /// bucket is full and leaves it in debt instead of being red forever
+    pub fn color(&self, len: u64) -> Color {
+        if !fits(self.peak_tokens, self.peak_debt, self.peak_burst, len) {
+            Color::Red
+        } else if !fits(
+            self.committed_tokens,
+            self.committed_debt,
+            self.committed_burst,
+            len,
+        ) {
+            Color::Yellow
+        } else {
+            Color::Green
+        }
+    }
+
+    /// Removes `len` bytes from the buckets a packet of the given color was
+    /// admitted by. Red packets are never charged.
+    pub fn consume(&mut self, color: Color, len: u64) {
+        match color {
+            Color::Green => {
+                consume(&mut self.committed_tokens, &mut self.committed_debt, len);
+                consume(&mut self.peak_tokens, &mut self.peak_debt, len);
+            }
+            Color::Yellow => {
+                consume(&mut self.peak_tokens, &mut self.peak_debt, len);
+            }
+            Color::Red => {}
+        }
+    }
+}
+
+fn fits(tokens: u64, debt: u64, burst: u64, len: u64) -> bool {
+    if tokens >= len {
+        return true;
+    }
+    debt == 0 && burst > 0 && tokens == burst
+}
+
+fn consume(tokens: &mut u64, debt: &mut u64, len: u64) {
+    if len > *tokens {
+        *debt = debt.saturating_add(len - *tokens);
+    }
+    *tokens = tokens.saturating_sub(len);
+}
+
+fn refill(tokens: &mut u64, debt: &mut u64, burst: u64, count: u64) {
+    let repaid = core::cmp::min(*debt, count);
+    *debt -= repaid;
+    *tokens = core::cmp::min(burst, tokens.saturating_add(count - repaid));
+}
+
+impl BucketLevel for TrTcmLimit {
+    /// Bytes that would still be green right now, debt counts as empty
+    fn level(&self, _now: u64) -> u64 {
+        match self.committed_debt {
+            0 => self.committed_tokens,
+            _ => 0,
+        }
+    }
+
+    /// Starts both buckets at no more than `level` bytes
+    fn seed(&mut self, level: u64, now: u64) {
+        self.committed_tokens = core::cmp::min(level, self.committed_burst);
+        self.peak_tokens = core::cmp::min(level, self.peak_burst);
+        self.committed_debt = 0;
+        self.peak_debt = 0;
+        self.last_tns = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1000 B/s committed with 100 bytes of burst, 2000 B/s peak with 200
+    fn limit() -> TrTcmLimit {
+        TrTcmLimit::new(1, 1000, 100, 2000, 200)
+    }
+
+    #[test]
+    fn colors_by_the_buckets_a_packet_fits() {
+        let mut limit = limit();
+        assert_eq!(limit.color(50), Color::Green);
+        limit.consume(Color::Green, 50);
+        assert_eq!((limit.committed_tokens, limit.peak_tokens), (50, 150));
+
+        assert_eq!(limit.color(100), Color::Yellow);
+        limit.consume(Color::Yellow, 100);
+        assert_eq!((limit.committed_tokens, limit.peak_tokens), (50, 50));
+
+        assert_eq!(limit.color(100), Color::Red);
+        limit.consume(Color::Red, 100);
+        assert_eq!((limit.committed_tokens, limit.peak_tokens), (50, 50));
+    }
+
+    #[test]
+    fn refills_each_bucket_at_its_rate() {
+        let mut limit = limit();
+        limit.consume(Color::Green, 100);
+        // A twentieth of a second
+        limit.refill(50_000_000);
+        assert_eq!((limit.committed_tokens, limit.peak_tokens), (50, 200));
+        limit.refill(1_000_000_000);
+        assert_eq!((limit.committed_tokens, limit.peak_tokens), (100, 200));
+        assert_eq!(limit.last_tns(), 1_000_000_000);
+    }
+
+    #[test]
+    fn oversized_packets_pass_full_buckets_into_debt() {
+        let mut limit = limit();
+        assert_eq!(limit.color(500), Color::Green);
+        limit.consume(Color::Green, 500);
+        assert_eq!((limit.committed_debt, limit.peak_debt), (400, 300));
+        assert_eq!(limit.color(1), Color::Red);
+
+        // 200 bytes committed and 400 bytes peak pay the debts back first
+        limit.refill(200_000_000);
+        assert_eq!((limit.committed_tokens, limit.committed_debt), (0, 200));
+        assert_eq!((limit.peak_tokens, limit.peak_debt), (100, 0));
+        assert_eq!(limit.color(50), Color::Yellow);
+    }
+
+    #[test]
+    fn oversized_for_the_committed_bucket_only_is_yellow() {
+        let mut limit = limit();
+        limit.consume(Color::Green, 1);
+        assert_eq!(limit.color(150), Color::Yellow);
+        limit.consume(Color::Yellow, 150);
+        assert_eq!(limit.peak_debt, 0);
+    }
+
+    #[test]
+    fn level_counts_debt_as_empty() {
+        let mut limit = limit();
+        assert_eq!(limit.level(0), 100);
+        limit.consume(Color::Green, 500);
+        assert_eq!(limit.level(0), 0);
+
+        limit.seed(150, 42);
+        assert_eq!((limit.committed_tokens, limit.peak_tokens), (100, 150));
+        assert_eq!((limit.committed_debt, limit.peak_debt), (0, 0));
+        assert_eq!(limit.last_tns(), 42);
+    }
+}
@@ -0,0 +1,105 @@
+use algos_common::{
+    config::{LOG_DEBUG, LOG_INFO, LOG_TRACE},
+    gcra::GcraLimit,
+    stats::Verdict,
+};
+use aya_ebpf::{
+    bindings::sk_action,
+    helpers::gen::{bpf_get_current_pid_tgid, bpf_ktime_get_ns},
+    macros::{cgroup_skb, map},
+    maps::HashMap,
+    programs::SkBuffContext,
+};
+use aya_log_ebpf::{debug, info, trace};
+
+use super::{
+    config::log_enabled,
+    events::{emit_drop, BucketState},
+    iface,
+    skb::{action, charged_len, dropped_below, NETWORK_OFFSET_CGROUP},
+    stats,
+};
+
+#[map]
+static GCRA_BUCKET: GcraBucket = GcraBucket::with_max_entries(4, 0);
+
+type GcraBucket = HashMap<u64, GcraLimit>;
+
+#[cgroup_skb]
+pub fn cgroup_egress_gcra(ctx: SkBuffContext) -> i32 {
+    match try_gcra(ctx, &GCRA_BUCKET, 1) {
+        Ok(ret) => ret,
+        Err(_) => sk_action::SK_PASS as i32,
+    }
+}
+#[cgroup_skb]
+pub fn cgroup_ingress_gcra(ctx: SkBuffContext) -> i32 {
+    match try_gcra(ctx, &GCRA_BUCKET, 0) {
+        Ok(ret) => ret,
+        Err(_) => sk_action::SK_PASS as i32,
+    }
+}
+
+fn try_gcra(ctx: SkBuffContext, bucket: &GcraBucket, bucket_id: u64) -> Result<i32, ()> {
+    if !iface::applies(&ctx.skb) {
+        return Ok(sk_action::SK_PASS as i32);
+    }
+    // Passing keeps the drop, the kernel only lets a packet through when
+    // every program does
+    if dropped_below(&ctx.skb) {
+        return Ok(sk_action::SK_PASS as i32);
+    }
+
+    if log_enabled(LOG_TRACE) {
+        trace!(
+            &ctx,
+            "direction:{} pid:{}",
+            bucket_id,
+            unsafe { bpf_get_current_pid_tgid() } >> 32
+        );
+    }
+
+    let Some(limit) = bucket.get_ptr_mut(&bucket_id) else {
+        return Ok(sk_action::SK_PASS as i32);
+    };
+
+    let packet_len = charged_len(&ctx.skb, NETWORK_OFFSET_CGROUP);
+    // The whole state is a single timestamp, so a racing cpu can at worst let
+    // one extra packet through instead of corrupting the bucket.
+    let now = unsafe { bpf_ktime_get_ns() };
+    let passed = unsafe { (*limit).conform(now, packet_len) };
+
+    if log_enabled(LOG_DEBUG) {
+        let (id, tat) = unsafe { ((*limit).id(), (*limit).tat) };
+        debug!(
+            &ctx,
+            "id:{} len:{} ahead:{} passed:{}",
+            id,
+            packet_len,
+            tat.saturating_sub(now),
+            passed as u8
+        );
+    }
+    if passed {
+        stats::record(bucket_id as u32, packet_len, Verdict::Pass);
+        return Ok(sk_action::SK_PASS as i32);
+    }
+
+    // There are no tokens to report, the bucket is empty by definition
+    let (verdict, state) = unsafe {
+        if log_enabled(LOG_INFO) {
+            info!(&ctx, "DROP id:{} len:{}", (*limit).id(), packet_len);
+        }
+        let verdict = Verdict::exceeded((*limit).is_observing());
+        let state = BucketState {
+            id: (*limit).id(),
+            bucket: 0,
+            capacity: (*limit).rate,
+            observed: verdict == Verdict::WouldDrop,
+        };
+        (verdict, state)
+    };
+    stats::record(bucket_id as u32, packet_len, verdict);
+    emit_drop(&ctx, bucket_id as u32, packet_len, state);
+    Ok(action(&ctx.skb, verdict))
+}
@@ -1,12 +1,24 @@
-use algos_common::token_bucket::TokenLimit;
+use algos_common::{
+    config::{LOG_DEBUG, LOG_INFO, LOG_TRACE},
+    stats::Verdict,
+    token_bucket::TokenLimit,
+};
 use aya_ebpf::{
     bindings::sk_action,
-    helpers::gen::{bpf_get_current_cgroup_id, bpf_get_current_pid_tgid, bpf_ktime_get_ns},
+    helpers::gen::{bpf_get_current_pid_tgid, bpf_ktime_get_ns},
     macros::{cgroup_skb, map},
     maps::HashMap,
     programs::SkBuffContext,
 };
-use aya_log_ebpf::info;
+use aya_log_ebpf::{debug, info, trace};
+
+use super::{
+    config::log_enabled,
+    events::{emit_drop, BucketState},
+    iface,
+    skb::{action, charged_len, dropped_below, NETWORK_OFFSET_CGROUP},
+    stats,
+};
 
 #[map]
 static TOKEN_BUCKET: RateBucket = RateBucket::with_max_entries(4, 0);
@@ -15,7 +27,6 @@ type RateBucket = HashMap<u64, TokenLimit>;
 
 #[cgroup_skb]
 pub fn cgroup_egress_tknb(ctx: SkBuffContext) -> i32 {
-    info!(&ctx, "EGRESS");
     match try_token(ctx, &TOKEN_BUCKET, 1) {
         Ok(ret) => ret,
         Err(_) => sk_action::SK_PASS as i32,
@@ -23,7 +34,6 @@ pub fn cgroup_egress_tknb(ctx: SkBuffContext) -> i32 {
 }
 #[cgroup_skb]
 pub fn cgroup_ingress_tknb(ctx: SkBuffContext) -> i32 {
-    info!(&ctx, "INGRESS");
     match try_token(ctx, &TOKEN_BUCKET, 0) {
         Ok(ret) => ret,
         Err(_) => sk_action::SK_PASS as i32,
@@ -31,58 +41,72 @@ pub fn cgroup_ingress_tknb(ctx: SkBuffContext) -> i32 {
 }
 
 fn try_token(ctx: SkBuffContext, bucket: &RateBucket, bucket_id: u64) -> Result<i32, ()> {
-    unsafe {
-        info!(
-            &ctx,
-            "--------------------------------------------------------------"
-        );
+    if !iface::applies(&ctx.skb) {
+        return Ok(sk_action::SK_PASS as i32);
+    }
+    // Passing keeps the drop, the kernel only lets a packet through when
+    // every program does
+    if dropped_below(&ctx.skb) {
+        return Ok(sk_action::SK_PASS as i32);
+    }
 
-        let cid = bpf_get_current_cgroup_id();
-        let x = bpf_get_current_cgroup_id();
-        info!(&ctx, "PID: {}", bpf_get_current_pid_tgid() >> 32);
-        info!(&ctx, "CID: {}", bpf_get_current_cgroup_id());
-        info!(&ctx, "CTX: {}", x);
+    unsafe {
+        if log_enabled(LOG_TRACE) {
+            trace!(
+                &ctx,
+                "direction:{} pid:{}",
+                bucket_id,
+                bpf_get_current_pid_tgid() >> 32
+            );
+        }
 
         let state = bucket.get_ptr_mut(&bucket_id);
         if let Some(token) = state {
             let now = bpf_ktime_get_ns();
             let elapsed = now.saturating_sub((*token).last_tns());
-            info!(&ctx, "Elapsed ns:{}", elapsed);
-
-            info!(
-                &ctx,
-                "\nToken bucket : id:{} capacity:{}\nbucket:{}\nlast_tns:{}\nburst:{}",
-                (*token).id(),
-                (*token).capacity(),
-                (*token).bucket(),
-                (*token).last_tns(),
-                (*token).burst()
-            );
 
             if elapsed > (*token).burst() {
                 let tokens_add = (*token).capacity() * elapsed / 1000000000;
-                info!(&ctx, "Tokens add:{}", tokens_add);
-                info!(&ctx, "Avaiable :{}", (*token).bucket());
                 (*token).update_last_tns(now);
                 (*token).refill(tokens_add);
             }
 
-            let packet_len = ctx.len() as u64;
+            let packet_len = charged_len(&ctx.skb, NETWORK_OFFSET_CGROUP);
 
-            info!(
-                &ctx,
-                " Packet len: {} vs Bucket {} vs Remaining {}",
-                packet_len,
-                (*token).capacity(),
-                (*token).bucket()
-            );
+            if log_enabled(LOG_DEBUG) {
+                debug!(
+                    &ctx,
+                    "id:{} len:{} bucket:{}/{} elapsed:{}",
+                    (*token).id(),
+                    packet_len,
+                    (*token).bucket(),
+                    (*token).capacity(),
+                    elapsed
+                );
+            }
 
-            if (*token).bucket() < packet_len {
-                info!(&ctx, "DROP");
-                return Ok(sk_action::SK_DROP as i32); // Drop packet
+            if !(*token).fits(packet_len) {
+                if log_enabled(LOG_INFO) {
+                    info!(&ctx, "DROP id:{} len:{}", (*token).id(), packet_len);
+                }
+                let verdict = Verdict::exceeded((*token).is_observing());
+                stats::record(bucket_id as u32, packet_len, verdict);
+                emit_drop(
+                    &ctx,
+                    bucket_id as u32,
+                    packet_len,
+                    BucketState {
+                        id: (*token).id(),
+                        bucket: (*token).bucket(),
+                        capacity: (*token).capacity(),
+                        observed: verdict == Verdict::WouldDrop,
+                    },
+                );
+                return Ok(action(&ctx.skb, verdict)); // Drop packet unless observing
             }
 
             (*token).consume(packet_len);
+            stats::record(bucket_id as u32, packet_len, Verdict::Pass);
         }
     }
     Ok(sk_action::SK_PASS as i32)
@@ -0,0 +1,112 @@
+use algos_common::{
+    config::{LOG_DEBUG, LOG_INFO, LOG_TRACE},
+    stats::Verdict,
+    tr_tcm::{Color, TrTcmLimit},
+};
+use aya_ebpf::{
+    bindings::sk_action,
+    helpers::gen::{bpf_get_current_pid_tgid, bpf_ktime_get_ns},
+    macros::{cgroup_skb, map},
+    maps::HashMap,
+    programs::SkBuffContext,
+};
+use aya_log_ebpf::{debug, info, trace};
+
+use super::{
+    config::log_enabled,
+    events::{emit_drop, BucketState},
+    iface,
+    skb::{action, charged_len, dropped_below, NETWORK_OFFSET_CGROUP},
+    stats,
+};
+
+#[map]
+static TRTCM_BUCKET: TrTcmBucket = TrTcmBucket::with_max_entries(4, 0);
+
+type TrTcmBucket = HashMap<u64, TrTcmLimit>;
+
+#[cgroup_skb]
+pub fn cgroup_egress_trtcm(ctx: SkBuffContext) -> i32 {
+    match try_trtcm(ctx, &TRTCM_BUCKET, 1) {
+        Ok(ret) => ret,
+        Err(_) => sk_action::SK_PASS as i32,
+    }
+}
+#[cgroup_skb]
+pub fn cgroup_ingress_trtcm(ctx: SkBuffContext) -> i32 {
+    match try_trtcm(ctx, &TRTCM_BUCKET, 0) {
+        Ok(ret) => ret,
+        Err(_) => sk_action::SK_PASS as i32,
+    }
+}
+
+fn try_trtcm(ctx: SkBuffContext, bucket: &TrTcmBucket, bucket_id: u64) -> Result<i32, ()> {
+    if !iface::applies(&ctx.skb) {
+        return Ok(sk_action::SK_PASS as i32);
+    }
+    // Passing keeps the drop, the kernel only lets a packet through when
+    // every program does
+    if dropped_below(&ctx.skb) {
+        return Ok(sk_action::SK_PASS as i32);
+    }
+
+    if log_enabled(LOG_TRACE) {
+        trace!(
+            &ctx,
+            "direction:{} pid:{}",
+            bucket_id,
+            unsafe { bpf_get_current_pid_tgid() } >> 32
+        );
+    }
+
+    let Some(limit) = bucket.get_ptr_mut(&bucket_id) else {
+        return Ok(sk_action::SK_PASS as i32);
+    };
+
+    unsafe {
+        (*limit).refill(bpf_ktime_get_ns());
+
+        let packet_len = charged_len(&ctx.skb, NETWORK_OFFSET_CGROUP);
+        let color = (*limit).color(packet_len);
+        (*limit).consume(color, packet_len);
+
+        if log_enabled(LOG_DEBUG) {
+            debug!(
+                &ctx,
+                "id:{} len:{} color:{} committed:{}/{} peak:{}/{}",
+                (*limit).id(),
+                packet_len,
+                color as u8,
+                (*limit).committed_tokens,
+                (*limit).committed_burst,
+                (*limit).peak_tokens,
+                (*limit).peak_burst
+            );
+        }
+
+        // Yellow traffic is above the committed rate but still within the peak
+        // rate, so only red traffic is dropped.
+        if color != Color::Red {
+            stats::record(bucket_id as u32, packet_len, Verdict::Pass);
+            return Ok(sk_action::SK_PASS as i32);
+        }
+
+        if log_enabled(LOG_INFO) {
+            info!(&ctx, "DROP id:{} len:{}", (*limit).id(), packet_len);
+        }
+        let verdict = Verdict::exceeded((*limit).is_observing());
+        stats::record(bucket_id as u32, packet_len, verdict);
+        emit_drop(
+            &ctx,
+            bucket_id as u32,
+            packet_len,
+            BucketState {
+                id: (*limit).id(),
+                bucket: (*limit).peak_tokens,
+                capacity: (*limit).peak_burst,
+                observed: verdict == Verdict::WouldDrop,
+            },
+        );
+        Ok(action(&ctx.skb, verdict))
+    }
+}
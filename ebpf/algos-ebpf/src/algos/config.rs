@@ -0,0 +1,16 @@
+use algos_common::config::{CONFIG_ENTRIES, CONFIG_LOG_LEVEL};
+use aya_ebpf::{macros::map, maps::Array};
+
+/// Written from userspace, read on every packet
+#[map]
+static CONFIG: Array<u64> = Array::with_max_entries(CONFIG_ENTRIES, 0);
+
+pub fn get(index: u32) -> u64 {
+    CONFIG.get(index).copied().unwrap_or(0)
+}
+
+/// Logging goes through a perf buffer, so check before formatting anything
+#[inline(always)]
+pub fn log_enabled(level: u64) -> bool {
+    get(CONFIG_LOG_LEVEL) >= level
+}
@@ -0,0 +1,97 @@
+use algos_common::events::{DropEvent, EventBudget};
+use aya_ebpf::{
+    helpers::gen::bpf_ktime_get_ns,
+    macros::map,
+    maps::{PerCpuArray, RingBuf},
+    programs::SkBuffContext,
+};
+
+#[map]
+static DROP_EVENTS: RingBuf = RingBuf::with_byte_size(64 * 1024, 0);
+
+#[map]
+static EVENT_BUDGET: PerCpuArray<EventBudget> = PerCpuArray::with_max_entries(1, 0);
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+/// Bucket state at the moment of the drop
+pub struct BucketState {
+    pub id: u64,
+    pub bucket: u64,
+    pub capacity: u64,
+    /// The bucket is observing and let the packet through
+    pub observed: bool,
+}
+
+/// Writes a drop event unless this cpu already used up its budget for the
+/// current second, so a flood of drops can't flood userspace as well.
+pub fn emit_drop(ctx: &SkBuffContext, direction: u32, packet_len: u64, state: BucketState) {
+    let now = unsafe { bpf_ktime_get_ns() };
+    let Some(budget) = EVENT_BUDGET.get_ptr_mut(0) else {
+        return;
+    };
+    if !unsafe { (*budget).take(now) } {
+        return;
+    }
+
+    let mut event = DropEvent {
+        tns: now,
+        id: state.id,
+        packet_len,
+        bucket: state.bucket,
+        capacity: state.capacity,
+        direction,
+        observed: state.observed as u8,
+        ..Default::default()
+    };
+    read_tuple(ctx, &mut event);
+
+    let _ = DROP_EVENTS.output(&event, 0);
+}
+
+/// cgroup_skb programs see the packet starting at the IP header
+fn read_tuple(ctx: &SkBuffContext, event: &mut DropEvent) {
+    let Ok(first) = ctx.load::<u8>(0) else {
+        return;
+    };
+
+    let transport = match first >> 4 {
+        4 => {
+            let (Ok(protocol), Ok(src), Ok(dst)) = (
+                ctx.load::<u8>(9),
+                ctx.load::<[u8; 4]>(12),
+                ctx.load::<[u8; 4]>(16),
+            ) else {
+                return;
+            };
+            event.protocol = protocol;
+            event.src_addr[..4].copy_from_slice(&src);
+            event.dst_addr[..4].copy_from_slice(&dst);
+            ((first & 0x0f) as usize) * 4
+        }
+        6 => {
+            let (Ok(protocol), Ok(src), Ok(dst)) = (
+                ctx.load::<u8>(6),
+                ctx.load::<[u8; 16]>(8),
+                ctx.load::<[u8; 16]>(24),
+            ) else {
+                return;
+            };
+            event.protocol = protocol;
+            event.src_addr = src;
+            event.dst_addr = dst;
+            // Extension headers are not followed
+            40
+        }
+        _ => return,
+    };
+    event.ip_version = first >> 4;
+
+    if event.protocol == IPPROTO_TCP || event.protocol == IPPROTO_UDP {
+        if let (Ok(src), Ok(dst)) = (ctx.load::<u16>(transport), ctx.load::<u16>(transport + 2)) {
+            event.src_port = u16::from_be(src);
+            event.dst_port = u16::from_be(dst);
+        }
+    }
+}
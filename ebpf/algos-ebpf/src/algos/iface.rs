@@ -0,0 +1,19 @@
+use algos_common::config::{CONFIG_IFACE_FILTER, MAX_INTERFACES};
+use aya_ebpf::{macros::map, maps::HashMap, programs::sk_buff::SkBuff};
+
+use super::config;
+
+/// Indexes of the interfaces a limit is scoped to
+#[map]
+static IFACE_FILTER: HashMap<u32, u8> = HashMap::with_max_entries(MAX_INTERFACES, 0);
+
+/// Whether the limit applies to the interface the packet goes through. A
+/// limit without interfaces applies everywhere.
+#[inline(always)]
+pub fn applies(skb: &SkBuff) -> bool {
+    if config::get(CONFIG_IFACE_FILTER) == 0 {
+        return true;
+    }
+    let ifindex = unsafe { (*skb.skb).ifindex };
+    unsafe { IFACE_FILTER.get(&ifindex).is_some() }
+}
@@ -1 +1,9 @@
+mod cgroup_gcra;
 mod cgroup_tknb;
+mod cgroup_trtcm;
+mod config;
+mod events;
+mod iface;
+mod skb;
+mod stats;
+mod tc_edt;
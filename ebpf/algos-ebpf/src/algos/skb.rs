@@ -0,0 +1,95 @@
+use algos_common::{
+    config::{CONFIG_MPU, CONFIG_OVERHEAD, CONFIG_SKIP_DROPPED, DROPPED_MARK},
+    stats::Verdict,
+};
+use aya_ebpf::{bindings::sk_action, programs::sk_buff::SkBuff};
+
+use super::config;
+
+/// Where the IP header starts in the data a program sees. cgroup skb programs
+/// start at the IP header, tc programs at the ethernet header.
+pub const NETWORK_OFFSET_CGROUP: usize = 0;
+pub const NETWORK_OFFSET_TC: usize = 14;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+/// Segments the skb leaves as, more than one for GSO packets
+#[inline(always)]
+fn segments(skb: &SkBuff) -> u64 {
+    let segs = unsafe { (*skb.skb).gso_segs as u64 };
+    core::cmp::max(segs, 1)
+}
+
+/// Bytes in front of the payload of every segment: everything up to the IP
+/// header, the IP header and the TCP or UDP header. `None` for anything that
+/// can't be parsed.
+#[inline(always)]
+fn header_len(skb: &SkBuff, network: usize) -> Option<u64> {
+    let first = skb.load::<u8>(network).ok()?;
+    let (ip_len, protocol) = match first >> 4 {
+        4 => (
+            ((first & 0x0f) as usize) * 4,
+            skb.load::<u8>(network + 9).ok()?,
+        ),
+        // Extension headers are not followed
+        6 => (40, skb.load::<u8>(network + 6).ok()?),
+        _ => return None,
+    };
+
+    let transport = network + ip_len;
+    let transport_len = match protocol {
+        IPPROTO_TCP => ((skb.load::<u8>(transport + 12).ok()? >> 4) as usize) * 4,
+        IPPROTO_UDP => 8,
+        _ => return None,
+    };
+    Some((transport + transport_len) as u64)
+}
+
+/// Bytes a packet takes on the wire. A GSO skb holds `gso_segs` segments
+/// behind a single copy of the headers, so the headers of every other
+/// segment are added back, the way the kernel's `qdisc_pkt_len_init` does.
+/// `network` is where the IP header starts.
+#[inline(always)]
+pub fn wire_len(skb: &SkBuff, network: usize) -> u64 {
+    let len = skb.len() as u64;
+    let segs = segments(skb);
+    if segs == 1 {
+        return len;
+    }
+
+    match header_len(skb, network) {
+        Some(headers) => len.saturating_add(headers.saturating_mul(segs - 1)),
+        None => len,
+    }
+}
+
+/// Bytes charged against a limit. Like cake's `overhead` and `mpu`, every
+/// segment pays the configured overhead and at least the minimum packet unit.
+#[inline(always)]
+pub fn charged_len(skb: &SkBuff, network: usize) -> u64 {
+    let segs = segments(skb);
+    let overhead = config::get(CONFIG_OVERHEAD).saturating_mul(segs);
+    let mpu = config::get(CONFIG_MPU).saturating_mul(segs);
+    core::cmp::max(wire_len(skb, network).saturating_add(overhead), mpu)
+}
+
+/// Whether a limit further down the cgroup tree dropped the packet already.
+/// Only the global limit looks, every other limit charges the packet anyway.
+#[inline(always)]
+pub fn dropped_below(skb: &SkBuff) -> bool {
+    config::get(CONFIG_SKIP_DROPPED) != 0 && unsafe { (*skb.skb).mark } == DROPPED_MARK
+}
+
+/// cgroup_skb return code for a verdict. A dropped packet is marked, so the
+/// global limit doesn't charge it for bandwidth it never uses.
+#[inline(always)]
+pub fn action(skb: &SkBuff, verdict: Verdict) -> i32 {
+    match verdict {
+        Verdict::Drop => {
+            unsafe { (*skb.skb).mark = DROPPED_MARK };
+            sk_action::SK_DROP as i32
+        }
+        Verdict::Pass | Verdict::WouldDrop => sk_action::SK_PASS as i32,
+    }
+}
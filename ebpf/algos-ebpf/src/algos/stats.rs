@@ -0,0 +1,12 @@
+use algos_common::stats::{BucketStats, Verdict};
+use aya_ebpf::{macros::map, maps::PerCpuArray};
+
+#[map]
+static STATS: PerCpuArray<BucketStats> = PerCpuArray::with_max_entries(2, 0);
+
+/// `direction` is `INGRESS_STATS` or `EGRESS_STATS`
+pub fn record(direction: u32, len: u64, verdict: Verdict) {
+    if let Some(stats) = STATS.get_ptr_mut(direction) {
+        unsafe { (*stats).record(len, verdict) }
+    }
+}
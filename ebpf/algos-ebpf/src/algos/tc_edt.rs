@@ -0,0 +1,89 @@
+use algos_common::{
+    config::{LOG_DEBUG, LOG_INFO, LOG_TRACE},
+    edt::EdtLimit,
+    stats::{Verdict, EGRESS_STATS},
+};
+use aya_ebpf::{
+    bindings::{TC_ACT_OK, TC_ACT_SHOT},
+    helpers::gen::{bpf_ktime_get_ns, bpf_skb_cgroup_id},
+    macros::{classifier, map},
+    maps::HashMap,
+    programs::TcContext,
+};
+use aya_log_ebpf::{debug, info, trace};
+
+use super::{
+    config::log_enabled,
+    skb::{charged_len, NETWORK_OFFSET_TC},
+    stats,
+};
+
+/// Keyed by the cgroup v2 id of the socket that sent the packet
+#[map]
+static EDT_STATE: EdtState = EdtState::with_max_entries(64, 0);
+
+type EdtState = HashMap<u64, EdtLimit>;
+
+#[classifier]
+pub fn tc_egress_edt(ctx: TcContext) -> i32 {
+    match try_edt(&ctx, &EDT_STATE) {
+        Ok(ret) => ret,
+        Err(_) => TC_ACT_OK,
+    }
+}
+
+fn try_edt(ctx: &TcContext, state: &EdtState) -> Result<i32, ()> {
+    let skb = ctx.skb.skb;
+
+    unsafe {
+        let cgroup_id = bpf_skb_cgroup_id(skb);
+        if log_enabled(LOG_TRACE) {
+            trace!(ctx, "cgroup:{}", cgroup_id);
+        }
+        let Some(limit) = state.get_ptr_mut(&cgroup_id) else {
+            return Ok(TC_ACT_OK);
+        };
+
+        let now = bpf_ktime_get_ns();
+        let tstamp = core::cmp::max((*skb).tstamp, now);
+
+        let packet_len = charged_len(&ctx.skb, NETWORK_OFFSET_TC);
+        let observing = (*limit).is_observing();
+        let scheduled = (*limit).schedule(now, tstamp, packet_len);
+        if log_enabled(LOG_DEBUG) {
+            debug!(
+                ctx,
+                "id:{} len:{} delay:{}",
+                (*limit).id(),
+                packet_len,
+                scheduled.unwrap_or(tstamp).saturating_sub(now)
+            );
+        }
+        match scheduled {
+            Some(departure) => {
+                // Observing pacers keep the schedule but never delay packets
+                if !observing {
+                    (*skb).tstamp = departure;
+                }
+                stats::record(EGRESS_STATS, packet_len, Verdict::Pass);
+                Ok(TC_ACT_OK)
+            }
+            None => {
+                if log_enabled(LOG_INFO) {
+                    info!(
+                        ctx,
+                        "DROP id:{} len:{} past horizon",
+                        (*limit).id(),
+                        packet_len
+                    );
+                }
+                let verdict = Verdict::exceeded(observing);
+                stats::record(EGRESS_STATS, packet_len, verdict);
+                match verdict {
+                    Verdict::Drop => Ok(TC_ACT_SHOT),
+                    _ => Ok(TC_ACT_OK),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,21 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AttachError {
+    #[error("Could not query programs attached to {cgroup}: {source}")]
+    Query {
+        cgroup: String,
+        source: std::io::Error,
+    },
+
+    #[error(
+        "{cgroup} has {programs} attached without multi-attach, attaching would replace them or fail"
+    )]
+    Exclusive { cgroup: String, programs: String },
+
+    #[error("{cgroup} already has {programs} attached with multi-attach, rateforge must attach with AllowMultiple too")]
+    SingleOnShared { cgroup: String, programs: String },
+
+    #[error("Only multi-attach programs can be placed ahead of the others on {cgroup}")]
+    OrderNeedsMultiple { cgroup: String },
+}
@@ -0,0 +1,281 @@
+use std::{
+    ffi::CString,
+    fs::File,
+    io,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+};
+
+use aya::programs::{links::PinnedLink, CgroupAttachMode, CgroupSkb, CgroupSkbAttachType};
+use log::{info, warn};
+
+use super::{check_attach, raw_attach_type, AttachError, AttachOrder};
+use crate::{pins::PinLocation, util::bpf, Error};
+
+const BPF_OBJ_PIN: libc::c_long = 6;
+const BPF_OBJ_GET: libc::c_long = 7;
+const BPF_PROG_GET_FD_BY_ID: libc::c_long = 13;
+const BPF_OBJ_GET_INFO_BY_FD: libc::c_long = 15;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_LINK_UPDATE: libc::c_long = 29;
+const BPF_F_BEFORE: u32 = 1 << 3;
+
+/// The cgroup `link_create` member of `union bpf_attr`
+#[repr(C)]
+#[derive(Default)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_fd: u32,
+    attach_type: u32,
+    flags: u32,
+    /// Program or link the new one goes before or after, none for the
+    /// start or the end of the list
+    relative_fd: u32,
+    _pad: u32,
+    expected_revision: u64,
+}
+
+/// The `link_update` member of `union bpf_attr`
+#[repr(C)]
+#[derive(Default)]
+struct LinkUpdateAttr {
+    link_fd: u32,
+    new_prog_fd: u32,
+    flags: u32,
+    old_prog_fd: u32,
+}
+
+/// The `BPF_OBJ_*` member of `union bpf_attr`
+#[repr(C)]
+#[derive(Default)]
+struct ObjGetAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+    path_fd: i32,
+    _pad: u32,
+}
+
+/// The `BPF_*_GET_FD_BY_ID` member of `union bpf_attr`
+#[repr(C)]
+#[derive(Default)]
+struct GetFdByIdAttr {
+    id: u32,
+    next_id: u32,
+    open_flags: u32,
+}
+
+/// The `info` member of `union bpf_attr`
+#[repr(C)]
+#[derive(Default)]
+struct ObjInfoAttr {
+    bpf_fd: u32,
+    info_len: u32,
+    info: u64,
+}
+
+/// The start of `struct bpf_link_info`, the kernel fills in as much as fits
+#[repr(C)]
+#[derive(Default)]
+struct LinkInfo {
+    link_type: u32,
+    id: u32,
+    prog_id: u32,
+    _pad: u32,
+}
+
+/// How `attach_or_replace` put a program in place, so `undo_attach` can
+/// take it back out
+#[derive(Debug)]
+pub enum Attached {
+    /// A new link was attached and pinned
+    Linked,
+    /// The pinned link ran this program before
+    Replaced(OwnedFd),
+}
+
+/// Name of the pin of the link `cgroup` is attached with in a policy's
+/// namespace. The registry hands every rule an id of its own, so the name
+/// only has to tell the cgroups and directions of one rule apart.
+pub fn link_name(cgroup: &Path, attach_type: CgroupSkbAttachType) -> String {
+    let cgroup = cgroup
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let direction = match attach_type {
+        CgroupSkbAttachType::Ingress => "ingress",
+        CgroupSkbAttachType::Egress => "egress",
+    };
+    format!("link_{}_{}", cgroup, direction)
+}
+
+/// Loads `program` and makes it the one enforcing `cgroup`. When a previous
+/// program is still attached through the link pinned at `location`, the
+/// link is switched over with `BPF_LINK_UPDATE`, so there is no moment
+/// without a limit. Otherwise a new link is attached and pinned there, which
+/// keeps the limit in place after this process exits.
+///
+/// The pin is named after the policy rather than the algorithm, so
+/// whichever program is loaded next for the policy takes the link over.
+/// `order` only applies to new links, a replaced program keeps the place of
+/// the one before it.
+pub fn attach_or_replace(
+    cgroup: &Path,
+    program: &mut CgroupSkb,
+    attach_type: CgroupSkbAttachType,
+    mode: CgroupAttachMode,
+    order: AttachOrder,
+    location: &PinLocation,
+) -> Result<Attached, Error> {
+    program.load()?;
+
+    if location.location().exists() {
+        match replace_program(location, program) {
+            Ok(previous) => {
+                info!("Replaced the program behind {:?}", location);
+                return Ok(Attached::Replaced(previous));
+            }
+            // The cgroup the link was attached to is gone
+            Err(err) if err.raw_os_error() == Some(libc::ENOLINK) => {
+                warn!("Removing defunct link {:?}", location);
+                location.delete()?;
+            }
+            Err(err) => Err(err)?,
+        }
+    }
+
+    check_attach(cgroup, attach_type, mode)?;
+    let file = File::open(cgroup)?;
+    let (flags, feature, since) = match order {
+        AttachOrder::Last => (0, "cgroup bpf links", "5.7"),
+        AttachOrder::First => {
+            if !matches!(mode, CgroupAttachMode::AllowMultiple) {
+                Err(AttachError::OrderNeedsMultiple {
+                    cgroup: cgroup.display().to_string(),
+                })?
+            }
+            (BPF_F_BEFORE, "ordered cgroup links", "6.16")
+        }
+    };
+    let link = match create_link(&file, program, attach_type, flags) {
+        Ok(link) => link,
+        // Older kernels have no cgroup links, or take no flags for them
+        Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+            Err(Error::KernelTooOld { feature, since })?
+        }
+        Err(err) => Err(err)?,
+    };
+    pin_object(&link, location.location())?;
+    info!("Attached and pinned {:?}", location);
+    Ok(Attached::Linked)
+}
+
+/// Takes back what `attach_or_replace` did: a new link is detached, a
+/// replaced link runs its previous program again
+pub fn undo_attach(location: &PinLocation, attached: Attached) -> Result<(), Error> {
+    match attached {
+        Attached::Linked => detach(location),
+        Attached::Replaced(previous) => {
+            let link = open_pinned(location.location())?;
+            update_link(&link, previous.as_fd())?;
+            info!("Put the previous program back behind {:?}", location);
+            Ok(())
+        }
+    }
+}
+
+/// Detaches whatever program the link pinned at `location` runs
+pub fn detach(location: &PinLocation) -> Result<(), Error> {
+    if !location.location().exists() {
+        return Ok(());
+    }
+
+    let link = PinnedLink::from_pin(location)
+        .map_err(|err| Error::General(format!("Could not open {:?}: {}", location, err)))?;
+    // Dropping the last reference to the link detaches it
+    drop(link.unpin()?);
+    info!("Detached {:?}", location);
+    Ok(())
+}
+
+/// Switches the link pinned at `location` over to `program`, returning the
+/// program it ran until now
+fn replace_program(location: &PinLocation, program: &CgroupSkb) -> io::Result<OwnedFd> {
+    let link = open_pinned(location.location())?;
+    let previous = link_program(&link)?;
+    let program = program.fd().map_err(io::Error::other)?;
+    update_link(&link, program.as_fd())?;
+    Ok(previous)
+}
+
+fn update_link(link: &OwnedFd, program: BorrowedFd<'_>) -> io::Result<()> {
+    let mut attr = LinkUpdateAttr {
+        link_fd: link.as_raw_fd() as u32,
+        new_prog_fd: program.as_raw_fd() as u32,
+        ..Default::default()
+    };
+    bpf(BPF_LINK_UPDATE, &mut attr)?;
+    Ok(())
+}
+
+/// The program `link` runs
+fn link_program(link: &OwnedFd) -> io::Result<OwnedFd> {
+    let mut info = LinkInfo::default();
+    let mut attr = ObjInfoAttr {
+        bpf_fd: link.as_raw_fd() as u32,
+        info_len: std::mem::size_of::<LinkInfo>() as u32,
+        info: &mut info as *mut LinkInfo as u64,
+    };
+    bpf(BPF_OBJ_GET_INFO_BY_FD, &mut attr)?;
+
+    let mut attr = GetFdByIdAttr {
+        id: info.prog_id,
+        ..Default::default()
+    };
+    let fd = bpf(BPF_PROG_GET_FD_BY_ID, &mut attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// Attaches `program` to `cgroup` through a new link, after the programs
+/// attached so far or, with `BPF_F_BEFORE`, ahead of them
+fn create_link(
+    cgroup: &File,
+    program: &CgroupSkb,
+    attach_type: CgroupSkbAttachType,
+    flags: u32,
+) -> io::Result<OwnedFd> {
+    let program = program.fd().map_err(io::Error::other)?;
+    let mut attr = LinkCreateAttr {
+        prog_fd: program.as_fd().as_raw_fd() as u32,
+        target_fd: cgroup.as_raw_fd() as u32,
+        attach_type: raw_attach_type(attach_type),
+        flags,
+        ..Default::default()
+    };
+    let fd = bpf(BPF_LINK_CREATE, &mut attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+fn pin_object(object: &OwnedFd, path: &Path) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut attr = ObjGetAttr {
+        pathname: path.as_ptr() as u64,
+        bpf_fd: object.as_raw_fd() as u32,
+        ..Default::default()
+    };
+    bpf(BPF_OBJ_PIN, &mut attr)?;
+    Ok(())
+}
+
+fn open_pinned(path: &Path) -> io::Result<OwnedFd> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut attr = ObjGetAttr {
+        pathname: path.as_ptr() as u64,
+        ..Default::default()
+    };
+    let fd = bpf(BPF_OBJ_GET, &mut attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
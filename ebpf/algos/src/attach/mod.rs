@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+mod errors;
+mod links;
+use aya::programs::{loaded_programs, CgroupAttachMode, CgroupSkbAttachType};
+pub use errors::AttachError;
+pub use links::{attach_or_replace, detach, link_name, undo_attach, Attached};
+use log::warn;
+
+use crate::{ebpf::CGROUP_ROOT, util::bpf};
+
+const BPF_PROG_QUERY: libc::c_long = 16;
+const BPF_CGROUP_INET_INGRESS: u32 = 0;
+const BPF_CGROUP_INET_EGRESS: u32 = 1;
+const BPF_F_ALLOW_OVERRIDE: u32 = 1 << 0;
+const BPF_F_ALLOW_MULTI: u32 = 1 << 1;
+const MAX_QUERIED_PROGRAMS: usize = 64;
+
+/// The `query` member of `union bpf_attr`
+#[repr(C)]
+#[derive(Default)]
+struct ProgQueryAttr {
+    target_fd: u32,
+    attach_type: u32,
+    query_flags: u32,
+    attach_flags: u32,
+    prog_ids: u64,
+    prog_cnt: u32,
+    _pad: u32,
+    prog_attach_flags: u64,
+    link_ids: u64,
+    link_attach_flags: u64,
+    revision: u64,
+}
+
+/// Where a program goes among the programs attached to the same cgroup. They
+/// all see every packet, the order decides whose marks and drops a program
+/// sees.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AttachOrder {
+    /// After the programs attached so far, the only order older kernels have
+    #[default]
+    Last,
+    /// Ahead of the programs attached so far, for example so systemd's IP
+    /// accounting only counts what the limit lets through. Needs multi-attach
+    /// and Linux 6.16.
+    First,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttachedProgram {
+    pub id: u32,
+    /// Kernel name of the program, truncated to 15 characters
+    pub name: String,
+}
+
+/// Programs attached directly to one cgroup for one direction. They run in
+/// attach order, after the programs of descendant cgroups.
+#[derive(Debug, Clone, Default)]
+pub struct CgroupAttachments {
+    /// `BPF_F_ALLOW_OVERRIDE` and `BPF_F_ALLOW_MULTI` of the attachment
+    pub flags: u32,
+    pub programs: Vec<AttachedProgram>,
+}
+
+impl CgroupAttachments {
+    pub fn is_shared(&self) -> bool {
+        self.flags & BPF_F_ALLOW_MULTI != 0
+    }
+
+    /// Whether programs in descendant cgroups can attach below these
+    pub fn allows_descendants(&self) -> bool {
+        self.programs.is_empty() || self.flags & (BPF_F_ALLOW_MULTI | BPF_F_ALLOW_OVERRIDE) != 0
+    }
+
+    fn describe(&self) -> String {
+        self.programs
+            .iter()
+            .map(|program| format!("{}({})", program.name, program.id))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Lists the cgroup_skb programs attached to `cgroup` with BPF_PROG_QUERY
+pub fn query_cgroup(
+    cgroup: &Path,
+    attach_type: CgroupSkbAttachType,
+) -> Result<CgroupAttachments, AttachError> {
+    let query_error = |source| AttachError::Query {
+        cgroup: cgroup.display().to_string(),
+        source,
+    };
+    let file = File::open(cgroup).map_err(query_error)?;
+
+    let mut ids = [0u32; MAX_QUERIED_PROGRAMS];
+    let mut attr = ProgQueryAttr {
+        target_fd: file.as_raw_fd() as u32,
+        attach_type: raw_attach_type(attach_type),
+        prog_ids: ids.as_mut_ptr() as u64,
+        prog_cnt: ids.len() as u32,
+        ..Default::default()
+    };
+    bpf(BPF_PROG_QUERY, &mut attr).map_err(query_error)?;
+
+    let names = program_names();
+    let count = (attr.prog_cnt as usize).min(ids.len());
+    Ok(CgroupAttachments {
+        flags: attr.attach_flags,
+        programs: ids[..count]
+            .iter()
+            .map(|id| AttachedProgram {
+                id: *id,
+                name: names.get(id).cloned().unwrap_or_default(),
+            })
+            .collect(),
+    })
+}
+
+/// `enum bpf_attach_type` value of a cgroup skb direction
+fn raw_attach_type(attach_type: CgroupSkbAttachType) -> u32 {
+    match attach_type {
+        CgroupSkbAttachType::Ingress => BPF_CGROUP_INET_INGRESS,
+        CgroupSkbAttachType::Egress => BPF_CGROUP_INET_EGRESS,
+    }
+}
+
+fn program_names() -> HashMap<u32, String> {
+    loaded_programs()
+        .filter_map(Result::ok)
+        .map(|info| {
+            let name = info.name_as_str().unwrap_or_default().to_string();
+            (info.id(), name)
+        })
+        .collect()
+}
+
+/// Makes sure attaching to `cgroup` neither fails nor silently replaces
+/// programs of systemd or other tools. Programs that share the cgroup with
+/// multi-attach are fine and only reported.
+pub fn check_attach(
+    cgroup: &Path,
+    attach_type: CgroupSkbAttachType,
+    mode: CgroupAttachMode,
+) -> Result<(), AttachError> {
+    let attached = query_cgroup(cgroup, attach_type)?;
+    if !attached.programs.is_empty() {
+        match (attached.is_shared(), mode) {
+            (true, CgroupAttachMode::AllowMultiple) => warn!(
+                "{} also runs {}, traffic has to pass those as well",
+                cgroup.display(),
+                attached.describe()
+            ),
+            (true, _) => Err(AttachError::SingleOnShared {
+                cgroup: cgroup.display().to_string(),
+                programs: attached.describe(),
+            })?,
+            (false, _) => Err(AttachError::Exclusive {
+                cgroup: cgroup.display().to_string(),
+                programs: attached.describe(),
+            })?,
+        }
+    }
+
+    // An ancestor attached without override or multi-attach locks down the
+    // whole subtree
+    for ancestor in ancestors(cgroup) {
+        let attached = query_cgroup(&ancestor, attach_type)?;
+        if !attached.allows_descendants() {
+            Err(AttachError::Exclusive {
+                cgroup: ancestor.display().to_string(),
+                programs: attached.describe(),
+            })?
+        }
+    }
+    Ok(())
+}
+
+fn ancestors(cgroup: &Path) -> Vec<PathBuf> {
+    cgroup
+        .ancestors()
+        .skip(1)
+        .take_while(|path| path.starts_with(CGROUP_ROOT))
+        .map(Path::to_path_buf)
+        .collect()
+}
@@ -0,0 +1,97 @@
+use aya::Ebpf;
+
+use crate::{
+    config::{LinkOverhead, LogLevel},
+    ebpf::{AttachmentKind, CgroupName, ProgramId},
+    events::DropEventStream,
+    factory::{BucketLevels, LimitAlgorithm, LimitSpec, BLOCK},
+    pins::PinnedObject,
+    skb::{CgroupSkbAlgorithm, CgroupSkbProgram},
+    stats::ProgramStats,
+    tokenb::{TokenBucketProgram, TokenLimit},
+    Error,
+};
+
+/// Drops all traffic in the directions a limit is applied to. Runs on the
+/// token bucket program with a bucket that never holds any tokens.
+#[derive(Debug)]
+pub struct BlockProgram {
+    inner: TokenBucketProgram,
+}
+
+impl BlockProgram {
+    pub fn new(id: ProgramId, cgroup: CgroupName, ebpf: Ebpf) -> BlockProgram {
+        Self {
+            inner: TokenBucketProgram::new(id, cgroup, ebpf),
+        }
+    }
+}
+
+impl CgroupSkbAlgorithm for BlockProgram {
+    fn skb_mut(&mut self) -> &mut CgroupSkbProgram {
+        self.inner.skb_mut()
+    }
+}
+
+impl LimitAlgorithm for BlockProgram {
+    fn name(&self) -> &'static str {
+        BLOCK
+    }
+
+    fn apply(&mut self, limit: AttachmentKind<LimitSpec>) -> Result<(), Error> {
+        self.inner
+            .apply_rate(limit.map(|spec| TokenLimit::new(spec.id, 0, 0).with_mode(spec.mode.raw())))
+    }
+
+    fn load(&mut self) -> Result<(), Error> {
+        self.inner.load()
+    }
+
+    fn unload(&mut self) -> Result<(), Error> {
+        self.inner.unload()
+    }
+
+    fn stats(&mut self) -> Result<ProgramStats, Error> {
+        LimitAlgorithm::stats(&mut self.inner)
+    }
+
+    fn set_log_level(&mut self, level: LogLevel) -> Result<(), Error> {
+        LimitAlgorithm::set_log_level(&mut self.inner, level)
+    }
+
+    fn set_overhead(&mut self, overhead: LinkOverhead) -> Result<(), Error> {
+        LimitAlgorithm::set_overhead(&mut self.inner, overhead)
+    }
+
+    fn set_interfaces(&mut self, ifindexes: Option<&[u32]>) -> Result<(), Error> {
+        LimitAlgorithm::set_interfaces(&mut self.inner, ifindexes)
+    }
+
+    fn pin(&mut self) -> Result<PinnedObject, Error> {
+        self.inner.pin()
+    }
+
+    fn drop_events(&mut self) -> Result<DropEventStream, Error> {
+        self.inner.drop_events()
+    }
+
+    fn levels(&mut self) -> Result<BucketLevels, Error> {
+        self.inner.levels()
+    }
+
+    fn seed(&mut self, levels: BucketLevels) -> Result<(), Error> {
+        self.inner.seed(levels)
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        self.inner.release()
+    }
+
+    fn cgroup(&self) -> &CgroupName {
+        self.inner.cgroup()
+    }
+
+    fn cgroup_mut(&mut self) -> &mut CgroupName {
+        self.inner.cgroup_mut()
+    }
+}
@@ -0,0 +1,159 @@
+use std::str::FromStr;
+
+pub use algos_common::config::*;
+use aya::{
+    maps::{Array, HashMap},
+    Ebpf,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{ebpf::MapKind, Error};
+
+/// Verbosity of the aya-log output of a program. Everything but `Off` costs
+/// a perf buffer write per log line, so tracing is meant for one policy at a
+/// time.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    #[default]
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// Value stored in the `CONFIG` map
+    pub fn raw(&self) -> u64 {
+        match self {
+            LogLevel::Off => LOG_OFF,
+            LogLevel::Error => LOG_ERROR,
+            LogLevel::Warn => LOG_WARN,
+            LogLevel::Info => LOG_INFO,
+            LogLevel::Debug => LOG_DEBUG,
+            LogLevel::Trace => LOG_TRACE,
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            other => Err(format!("Invalid log level: {}", other)),
+        }
+    }
+}
+
+/// Per packet framing the accounting adds on top of the IP length, for
+/// example 38 bytes and a 84 byte MPU for Ethernet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LinkOverhead {
+    /// Bytes added to every packet
+    pub overhead: u32,
+
+    /// Minimum bytes a packet is charged
+    pub mpu: u32,
+}
+
+impl LinkOverhead {
+    pub fn new(overhead: u32, mpu: u32) -> Self {
+        Self { overhead, mpu }
+    }
+}
+
+pub fn write_config(ebpf: &mut Ebpf, index: u32, value: u64) -> Result<(), Error> {
+    let name: &str = MapKind::Config.into();
+    let map = ebpf
+        .map_mut(name)
+        .ok_or_else(|| Error::General(format!("No map named: {}", name)))?;
+    let mut map: Array<_, u64> = Array::try_from(map)?;
+    map.set(index, value, 0)?;
+    Ok(())
+}
+
+/// Changes the log level of a loaded program. The logger is only set up the
+/// first time logging is turned on.
+pub fn set_log_level(ebpf: &mut Ebpf, level: LogLevel) -> Result<(), Error> {
+    if level != LogLevel::Off {
+        enable_ebpf_logging(ebpf);
+    }
+    write_config(ebpf, CONFIG_LOG_LEVEL, level.raw())?;
+    info!("eBPF log level set to {:?}", level);
+    Ok(())
+}
+
+pub fn set_overhead(ebpf: &mut Ebpf, overhead: LinkOverhead) -> Result<(), Error> {
+    write_config(ebpf, CONFIG_OVERHEAD, overhead.overhead as u64)?;
+    write_config(ebpf, CONFIG_MPU, overhead.mpu as u64)?;
+    info!("Link overhead set to {:?}", overhead);
+    Ok(())
+}
+
+/// Makes the limit let packets a limit further down the cgroup tree dropped
+/// through uncharged. Only the global limit on the root cgroup sets it.
+pub fn set_skip_dropped(ebpf: &mut Ebpf, skip: bool) -> Result<(), Error> {
+    write_config(ebpf, CONFIG_SKIP_DROPPED, skip as u64)
+}
+
+/// Scopes the limit to the given interface indexes, or lifts the scope with
+/// `None`. An empty list means the limit applies nowhere, for example while a
+/// tether interface is unplugged.
+///
+/// The filter is changed in place so packets never see it half updated: new
+/// interfaces are added before stale ones go, and the scope is lifted before
+/// the filter is emptied.
+pub fn set_interfaces(ebpf: &mut Ebpf, scope: Option<&[u32]>) -> Result<(), Error> {
+    let ifindexes = scope.unwrap_or_default();
+    if ifindexes.len() > MAX_INTERFACES as usize {
+        Err(Error::General(format!(
+            "A limit can be scoped to at most {} interfaces",
+            MAX_INTERFACES
+        )))?
+    }
+
+    if scope.is_none() {
+        write_config(ebpf, CONFIG_IFACE_FILTER, 0)?;
+    }
+
+    let mut map: HashMap<_, u32, u8> = MapKind::Interfaces
+        .get_mut(ebpf)
+        .map_err(|err| Error::General(err.to_string()))?;
+    let known: Vec<u32> = map.keys().filter_map(Result::ok).collect();
+    for ifindex in ifindexes.iter().filter(|ifindex| !known.contains(ifindex)) {
+        map.insert(ifindex, 1, 0)?;
+    }
+    for ifindex in known.iter().filter(|ifindex| !ifindexes.contains(ifindex)) {
+        map.remove(ifindex)?;
+    }
+
+    if scope.is_some() {
+        write_config(ebpf, CONFIG_IFACE_FILTER, 1)?;
+    }
+    info!("Limit scoped to interfaces {:?}", scope);
+    Ok(())
+}
+
+pub(crate) fn enable_ebpf_logging(ebpf: &mut Ebpf) {
+    // The logger takes the map, so a missing map means it is already running
+    if ebpf.map(AYA_LOGS).is_none() {
+        return;
+    }
+    if let Err(e) = aya_log::EbpfLogger::init(ebpf) {
+        // This can happen if you remove all log statements from your eBPF program.
+        warn!("failed to initialize eBPF logger: {}", e);
+        return;
+    }
+    info!("Ebpf logger enabled");
+}
+
+const AYA_LOGS: &str = "AYA_LOGS";
@@ -1,7 +1,10 @@
-use std::path::{Path, PathBuf};
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
 
-use algos_common::token_bucket::TokenLimit;
-use anyhow::{anyhow, Error};
+use algos_common::{edt::EdtLimit, gcra::GcraLimit, token_bucket::TokenLimit, tr_tcm::TrTcmLimit};
+use anyhow::anyhow;
 use aya::{maps::MapData, Ebpf, Pod};
 use cgroups_rs::{cgroup_builder::CgroupBuilder, Cgroup, CgroupPid};
 use serde::{Deserialize, Serialize};
@@ -27,11 +30,25 @@ pub enum AttachmentKind<T> {
     Ingress(T),
     Egress(T),
 }
+
+impl<T> AttachmentKind<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> AttachmentKind<U> {
+        match self {
+            Self::Ingress(value) => AttachmentKind::Ingress(f(value)),
+            Self::Egress(value) => AttachmentKind::Egress(f(value)),
+        }
+    }
+}
 //used to hard limit available programs to run
 #[derive(Debug, Clone, Default, Copy, Serialize, Deserialize)]
 pub enum ProgramKind {
     CgroupIngressTknb,
     CgroupEgressTknb,
+    CgroupIngressTrTcm,
+    CgroupEgressTrTcm,
+    TcEgressEdt,
+    CgroupIngressGcra,
+    CgroupEgressGcra,
     #[default]
     Unknown,
 }
@@ -46,6 +63,11 @@ impl From<&ProgramKind> for &'static str {
         match kind {
             ProgramKind::CgroupIngressTknb => "cgroup_ingress_tknb",
             ProgramKind::CgroupEgressTknb => "cgroup_egress_tknb",
+            ProgramKind::CgroupIngressTrTcm => "cgroup_ingress_trtcm",
+            ProgramKind::CgroupEgressTrTcm => "cgroup_egress_trtcm",
+            ProgramKind::TcEgressEdt => "tc_egress_edt",
+            ProgramKind::CgroupIngressGcra => "cgroup_ingress_gcra",
+            ProgramKind::CgroupEgressGcra => "cgroup_egress_gcra",
             ProgramKind::Unknown => "unknown",
         }
     }
@@ -55,6 +77,11 @@ impl From<ProgramKind> for &'static str {
         match kind {
             ProgramKind::CgroupIngressTknb => "cgroup_ingress_tknb",
             ProgramKind::CgroupEgressTknb => "cgroup_egress_tknb",
+            ProgramKind::CgroupIngressTrTcm => "cgroup_ingress_trtcm",
+            ProgramKind::CgroupEgressTrTcm => "cgroup_egress_trtcm",
+            ProgramKind::TcEgressEdt => "tc_egress_edt",
+            ProgramKind::CgroupIngressGcra => "cgroup_ingress_gcra",
+            ProgramKind::CgroupEgressGcra => "cgroup_egress_gcra",
             ProgramKind::Unknown => "unknown",
         }
     }
@@ -64,6 +91,11 @@ impl From<&str> for ProgramKind {
         match value {
             "cgroup_ingress_tknb" => Self::CgroupIngressTknb,
             "cgroup_egress_tknb" => Self::CgroupEgressTknb,
+            "cgroup_ingress_trtcm" => Self::CgroupIngressTrTcm,
+            "cgroup_egress_trtcm" => Self::CgroupEgressTrTcm,
+            "tc_egress_edt" => Self::TcEgressEdt,
+            "cgroup_ingress_gcra" => Self::CgroupIngressGcra,
+            "cgroup_egress_gcra" => Self::CgroupEgressGcra,
             _ => Self::Unknown,
         }
     }
@@ -71,6 +103,13 @@ impl From<&str> for ProgramKind {
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum MapKind {
     TokenBucket,
+    TrTcm,
+    Edt,
+    Gcra,
+    Stats,
+    DropEvents,
+    Config,
+    Interfaces,
     Unknown,
 }
 impl MapKind {
@@ -95,6 +134,18 @@ impl MapKind {
                 let map: aya::maps::HashMap<_, u64, TokenLimit> = self.get_mut(ebpf)?;
                 Ok(map.pin(location.location())?)
             }
+            Self::TrTcm => {
+                let map: aya::maps::HashMap<_, u64, TrTcmLimit> = self.get_mut(ebpf)?;
+                Ok(map.pin(location.location())?)
+            }
+            Self::Edt => {
+                let map: aya::maps::HashMap<_, u64, EdtLimit> = self.get_mut(ebpf)?;
+                Ok(map.pin(location.location())?)
+            }
+            Self::Gcra => {
+                let map: aya::maps::HashMap<_, u64, GcraLimit> = self.get_mut(ebpf)?;
+                Ok(map.pin(location.location())?)
+            }
 
             _ => Err(anyhow!("Unknown Map to pin")),
         }
@@ -105,6 +156,13 @@ impl From<MapKind> for &'static str {
     fn from(kind: MapKind) -> Self {
         match kind {
             MapKind::TokenBucket => "TOKEN_BUCKET",
+            MapKind::TrTcm => "TRTCM_BUCKET",
+            MapKind::Edt => "EDT_STATE",
+            MapKind::Gcra => "GCRA_BUCKET",
+            MapKind::Stats => "STATS",
+            MapKind::DropEvents => "DROP_EVENTS",
+            MapKind::Config => "CONFIG",
+            MapKind::Interfaces => "IFACE_FILTER",
             _ => "unknown",
         }
     }
@@ -113,6 +171,13 @@ impl From<&MapKind> for &'static str {
     fn from(kind: &MapKind) -> Self {
         match kind {
             MapKind::TokenBucket => "TOKEN_BUCKET",
+            MapKind::TrTcm => "TRTCM_BUCKET",
+            MapKind::Edt => "EDT_STATE",
+            MapKind::Gcra => "GCRA_BUCKET",
+            MapKind::Stats => "STATS",
+            MapKind::DropEvents => "DROP_EVENTS",
+            MapKind::Config => "CONFIG",
+            MapKind::Interfaces => "IFACE_FILTER",
             _ => "unknown",
         }
     }
@@ -127,6 +192,13 @@ bitflags::bitflags! {
         const PINNED = 0b0100;
     }
 }
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Whether the unified cgroup2 hierarchy is mounted at `CGROUP_ROOT`
+pub fn cgroup2_mounted() -> bool {
+    Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct CgroupName {
     path: PathBuf,
@@ -138,14 +210,28 @@ impl CgroupName {
             true => "rateforgenamegroup",
             false => name,
         };
+        if !cgroup2_mounted() {
+            Err(crate::Error::CgroupV1Only(CGROUP_ROOT.into()))?
+        }
         let hier = cgroups_rs::hierarchies::auto();
         let c = CgroupBuilder::new(name).pid().done().build(hier)?;
 
         Ok(Self {
-            path: PathBuf::from(format!("/sys/fs/cgroup/{}", c.path())),
+            path: PathBuf::from(format!("{}/{}", CGROUP_ROOT, c.path())),
         })
     }
 
+    /// The root of the cgroup2 hierarchy, every process on the machine
+    pub fn root() -> Self {
+        Self {
+            path: PathBuf::from(CGROUP_ROOT),
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.path == Path::new(CGROUP_ROOT)
+    }
+
     pub fn name(&self) -> &str {
         self.path
             .file_name()
@@ -154,13 +240,27 @@ impl CgroupName {
             .unwrap_or_default()
     }
 
+    /// Kernel id of the cgroup, which is the inode of its directory on cgroup2
+    pub fn id(&self) -> Result<u64, crate::Error> {
+        Ok(std::fs::metadata(&self.path)?.ino())
+    }
+
+    /// Removes the cgroup. The root cgroup is never removed.
     pub fn delete(&self) -> Result<(), crate::Error> {
+        if self.is_root() {
+            return Ok(());
+        }
         let cgroup = self.load_cgroup();
         cgroup.delete()?;
         Ok(())
     }
 
     pub fn add_task(&mut self, pid: u64) -> Result<(), crate::Error> {
+        if self.is_root() {
+            Err(crate::Error::General(
+                "Processes can't be moved into the root cgroup".into(),
+            ))?
+        }
         let pid = CgroupPid::from(pid);
         let cgroup = self.load_cgroup();
         cgroup.add_task_by_tgid(pid)?;
@@ -0,0 +1,322 @@
+use std::process::Command;
+
+pub use algos_common::edt::{EdtLimit, DEFAULT_HORIZON_NS};
+use aya::{
+    maps::HashMap,
+    programs::{tc, TcAttachType},
+    Ebpf,
+};
+use log::info;
+
+use crate::{
+    config::{set_log_level, set_overhead, LinkOverhead, LogLevel},
+    ebpf::{MapKind, ProgramFlags, ProgramKind},
+    edt::errors::EdtError,
+    factory::{LimitAlgorithm, LimitSpec, EDT},
+    pins::{PinError, PinNamespace, PinnedObject, PinnedObjectBuilder},
+    stats::{pin_stats, read_stats, unpin_stats, ProgramStats, STATS_PNAME},
+    util::get_ebpf_classifier,
+};
+pub use crate::{
+    ebpf::{AttachmentKind, CgroupName, ProgramId},
+    Error,
+};
+
+const EGRESS_BASE_PNAME: &str = "edtegress";
+
+/// Handle the kernel gives the root qdisc it attaches on its own
+const DEFAULT_HANDLE: &str = "0:";
+
+/// Handle rateforge gives the fq it puts at the root, so whichever EDT
+/// program is removed last can tell it apart from an fq somebody configured
+const FQ_HANDLE: &str = "7266:";
+
+/// Egress pacer built on a tc clsact classifier. Packets from the cgroup get
+/// an earliest departure time and the `fq` qdisc on `iface` enforces it, so
+/// traffic is delayed rather than dropped.
+#[derive(Debug)]
+pub struct EdtProgram {
+    pub id: ProgramId,
+    ebpf: Ebpf,
+    flags: ProgramFlags,
+    cgroup: CgroupName,
+    iface: String,
+    pins: PinNamespace,
+    /// The root fq was put there by rateforge rather than configured
+    owns_root: bool,
+    /// clsact was added by rateforge rather than found on `iface`
+    owns_clsact: bool,
+}
+
+impl EdtProgram {
+    pub fn new(id: ProgramId, cgroup: CgroupName, iface: String, ebpf: Ebpf) -> EdtProgram {
+        Self {
+            id,
+            flags: ProgramFlags::BLOCKED,
+            ebpf,
+            cgroup,
+            iface,
+            pins: PinNamespace::default(),
+            owns_root: false,
+            owns_clsact: false,
+        }
+    }
+
+    /// Pins go under `<bpffs>/rateforge/<instance>/<id>/`
+    pub fn with_pins(mut self, pins: PinNamespace) -> Self {
+        self.pins = pins;
+        self
+    }
+
+    /// Packets are keyed by the cgroup they were sent from, so the rate is
+    /// stored against the cgroup id rather than a direction.
+    pub fn apply_rate(&mut self, rate: EdtLimit) -> Result<(), Error> {
+        let cgroup_id = self.cgroup.id()?;
+        let mut map: HashMap<_, u64, EdtLimit> = MapKind::Edt
+            .get_mut(&mut self.ebpf)
+            .map_err(|err| Error::General(err.to_string()))?;
+        map.insert(cgroup_id, rate, 0)?;
+
+        self.flags = self.flags.union(ProgramFlags::EGRESS);
+        info!("EDT rate applied to cgroup {}", cgroup_id);
+        Ok(())
+    }
+
+    pub fn load(&mut self) -> Result<(), Error> {
+        if !self.flags.contains(ProgramFlags::EGRESS) {
+            Err(EdtError::NoRate)?
+        }
+
+        self.setup_fq()?;
+        if let Err(err) = self.attach() {
+            // Detach first, the filter would otherwise count as another
+            // program still needing the qdiscs
+            if let Ok(program) =
+                get_ebpf_classifier(ProgramKind::TcEgressEdt.into(), &mut self.ebpf)
+            {
+                let _ = program.unload();
+            }
+            if let Err(restore) = self.restore_qdiscs() {
+                info!("Could not restore qdiscs on {}: {}", self.iface, restore);
+            }
+            return Err(err);
+        }
+        info!("Loaded EDT program on {}", self.iface);
+        Ok(())
+    }
+
+    fn attach(&mut self) -> Result<(), Error> {
+        // A clsact found next to another EDT program was added by that one
+        let shared = self.edt_filters()? > 0;
+        match tc::qdisc_add_clsact(&self.iface) {
+            Ok(()) => self.owns_clsact = true,
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                self.owns_clsact = shared
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let program = get_ebpf_classifier(ProgramKind::TcEgressEdt.into(), &mut self.ebpf)?;
+        program.load()?;
+        program.attach(&self.iface, TcAttachType::Egress)?;
+        self.pins.create(self.id)?;
+        pin_stats(&mut self.ebpf, &self.pins.location(self.id, STATS_PNAME))?;
+        Ok(())
+    }
+
+    pub fn unload(&mut self) -> Result<(), Error> {
+        if self.flags.contains(ProgramFlags::EGRESS) {
+            let program = get_ebpf_classifier(ProgramKind::TcEgressEdt.into(), &mut self.ebpf)?;
+            program.unload()?;
+        }
+        self.restore_qdiscs()?;
+
+        unpin_stats(&self.pins.location(self.id, STATS_PNAME))?;
+        self.pins.remove(self.id)?;
+        info!("Program unloaded");
+        Ok(())
+    }
+
+    pub fn pin(&mut self) -> Result<PinnedObject, Error> {
+        if !self.flags.contains(ProgramFlags::EGRESS) {
+            Err(EdtError::NoRate)?
+        }
+        self.pins.create(self.id)?;
+
+        let program = get_ebpf_classifier(ProgramKind::TcEgressEdt.into(), &mut self.ebpf)?;
+        let location = self.pins.location(self.id, EGRESS_BASE_PNAME);
+        program.pin(&location).map_err(PinError::from)?;
+        info!("Pinned at {:?}", location);
+
+        self.flags = self.flags.union(ProgramFlags::PINNED);
+        Ok(PinnedObjectBuilder::new()
+            .program(self.id, ProgramKind::TcEgressEdt, location)
+            .build())
+    }
+
+    /// EDT timestamps are only honoured by the `fq` qdisc. Only the root
+    /// qdisc the kernel attached on its own is replaced, one somebody
+    /// configured is left alone.
+    fn setup_fq(&mut self) -> Result<(), Error> {
+        let (kind, handle) = self.root_qdisc()?;
+        if kind == "fq" {
+            self.owns_root = handle == FQ_HANDLE;
+            return Ok(());
+        }
+        if handle != DEFAULT_HANDLE {
+            Err(EdtError::ForeignQdisc {
+                iface: self.iface.clone(),
+                kind,
+            })?
+        }
+
+        self.tc(&[
+            "qdisc",
+            "replace",
+            "dev",
+            &self.iface,
+            "root",
+            "handle",
+            FQ_HANDLE,
+            "fq",
+        ])?;
+        self.owns_root = true;
+        info!("Replaced {} root qdisc on {} with fq", kind, self.iface);
+        Ok(())
+    }
+
+    /// Removes what rateforge added once the last EDT program on `iface` is
+    /// gone, the others still need the qdiscs. Deleting the root qdisc makes
+    /// the kernel attach its default one again.
+    fn restore_qdiscs(&mut self) -> Result<(), Error> {
+        let others = self.edt_filters()?;
+        if others > 0 {
+            info!(
+                "Leaving the qdiscs on {} to {} other EDT programs",
+                self.iface, others
+            );
+            return Ok(());
+        }
+
+        if self.owns_clsact
+            && self.filters("ingress")?.is_empty()
+            && self.filters("egress")?.is_empty()
+        {
+            self.tc(&["qdisc", "del", "dev", &self.iface, "clsact"])?;
+        }
+        self.owns_clsact = false;
+        if self.owns_root && self.root_qdisc()? == ("fq".to_string(), FQ_HANDLE.to_string()) {
+            self.tc(&["qdisc", "del", "dev", &self.iface, "root"])?;
+            info!("Restored default root qdisc on {}", self.iface);
+        }
+        self.owns_root = false;
+        Ok(())
+    }
+
+    /// Kind and handle of the root qdisc on `iface`
+    fn root_qdisc(&self) -> Result<(String, String), Error> {
+        let output = self.tc(&["qdisc", "show", "dev", &self.iface, "root"])?;
+        // qdisc fq_codel 0: root refcnt 2 limit 10240p ...
+        let mut fields = output.split_whitespace().skip(1);
+        let kind = fields.next().unwrap_or_default().to_string();
+        let handle = fields.next().unwrap_or_default().to_string();
+        Ok((kind, handle))
+    }
+
+    /// The filters attached to clsact in `direction`, one per line
+    fn filters(&self, direction: &str) -> Result<Vec<String>, Error> {
+        let output = self.tc(&["filter", "show", "dev", &self.iface, direction])?;
+        Ok(output
+            .lines()
+            .filter(|line| line.starts_with("filter"))
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// How many EDT programs of rateforge are attached to `iface`
+    fn edt_filters(&self) -> Result<usize, Error> {
+        let name: &str = ProgramKind::TcEgressEdt.into();
+        Ok(self
+            .filters("egress")?
+            .iter()
+            // filter protocol all pref 49152 bpf chain 0 handle 0x1 tc_egress_edt direct-action ...
+            .filter(|line| line.split_whitespace().any(|field| field == name))
+            .count())
+    }
+
+    fn tc(&self, args: &[&str]) -> Result<String, Error> {
+        let output = Command::new("tc").args(args).output()?;
+        if !output.status.success() {
+            Err(EdtError::Qdisc {
+                iface: self.iface.clone(),
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            })?
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    pub fn iface(&self) -> &str {
+        &self.iface
+    }
+
+    pub fn cgroup(&self) -> &CgroupName {
+        &self.cgroup
+    }
+
+    pub fn cgroup_mut(&mut self) -> &mut CgroupName {
+        &mut self.cgroup
+    }
+
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.unload()?;
+        self.cgroup.delete()?;
+        Ok(())
+    }
+}
+
+impl LimitAlgorithm for EdtProgram {
+    fn name(&self) -> &'static str {
+        EDT
+    }
+
+    fn apply(&mut self, limit: AttachmentKind<LimitSpec>) -> Result<(), Error> {
+        match limit {
+            AttachmentKind::Egress(spec) => self.apply_rate(
+                EdtLimit::new(spec.id, spec.rate, DEFAULT_HORIZON_NS).with_mode(spec.mode.raw()),
+            ),
+            AttachmentKind::Ingress(_) => Err(EdtError::IngressUnsupported)?,
+        }
+    }
+
+    fn load(&mut self) -> Result<(), Error> {
+        EdtProgram::load(self)
+    }
+
+    fn unload(&mut self) -> Result<(), Error> {
+        EdtProgram::unload(self)
+    }
+
+    fn stats(&mut self) -> Result<ProgramStats, Error> {
+        read_stats(&mut self.ebpf)
+    }
+
+    fn set_log_level(&mut self, level: LogLevel) -> Result<(), Error> {
+        set_log_level(&mut self.ebpf, level)
+    }
+
+    fn set_overhead(&mut self, overhead: LinkOverhead) -> Result<(), Error> {
+        set_overhead(&mut self.ebpf, overhead)
+    }
+
+    fn pin(&mut self) -> Result<PinnedObject, Error> {
+        EdtProgram::pin(self)
+    }
+
+    fn cgroup(&self) -> &CgroupName {
+        &self.cgroup
+    }
+
+    fn cgroup_mut(&mut self) -> &mut CgroupName {
+        &mut self.cgroup
+    }
+}
@@ -0,0 +1,16 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum EdtError {
+    #[error("No pacing rate applied to the program")]
+    NoRate,
+
+    #[error("EDT can only pace egress traffic")]
+    IngressUnsupported,
+
+    #[error("Could not set up fq qdisc on {iface}: {message}")]
+    Qdisc { iface: String, message: String },
+
+    #[error("Refusing to replace the {kind} root qdisc configured on {iface}")]
+    ForeignQdisc { iface: String, kind: String },
+}
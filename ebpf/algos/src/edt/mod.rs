@@ -0,0 +1,5 @@
+mod edt;
+mod errors;
+
+pub use edt::*;
+pub use errors::*;
@@ -1,10 +1,110 @@
-use crate::{factory::ProgramFactoryError, pins::PinError, tokenb::TokenBucketError};
+use std::{io, path::PathBuf};
+
+use aya::{maps::MapError, programs::ProgramError, sys::SyscallError, EbpfError};
+
+use crate::{
+    attach::AttachError, ebpf::cgroup2_mounted, edt::EdtError, factory::ProgramFactoryError,
+    gcra::GcraError, pins::PinError, tokenb::TokenBucketError, trtcm::TrTcmError,
+};
+
+/// What went wrong in terms of what the user can do about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    PermissionDenied,
+    MissingCapability,
+    CgroupV1Only,
+    BpffsNotMounted,
+    VerifierRejected,
+    KernelTooOld,
+    Other,
+}
+
+impl ErrorKind {
+    /// How to fix it, for errors with a fix the user can apply
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            ErrorKind::PermissionDenied => Some("Run rateforge as root"),
+            ErrorKind::MissingCapability => Some(
+                "Run as root or grant CAP_BPF, CAP_NET_ADMIN and CAP_SYS_ADMIN, e.g. `setcap cap_bpf,cap_net_admin,cap_sys_admin+ep rateforge`",
+            ),
+            ErrorKind::CgroupV1Only => Some(
+                "Boot with `systemd.unified_cgroup_hierarchy=1` so cgroup2 is mounted at /sys/fs/cgroup",
+            ),
+            ErrorKind::BpffsNotMounted => Some(
+                "Mount it with `mount -t bpf bpf /sys/fs/bpf` or point --bpffs at an existing mount",
+            ),
+            ErrorKind::VerifierRejected => Some(
+                "The kernel refused the program, please report it together with the verifier log",
+            ),
+            ErrorKind::KernelTooOld => Some("rateforge needs Linux 5.8 or newer"),
+            ErrorKind::Other => None,
+        }
+    }
+
+    /// `EPERM` from the bpf syscall means a capability is missing, while
+    /// `EACCES` on files means plain permissions
+    pub fn from_io(err: &io::Error) -> Self {
+        match err.raw_os_error() {
+            Some(libc::EPERM) => ErrorKind::MissingCapability,
+            Some(libc::EACCES) => ErrorKind::PermissionDenied,
+            // ENOTSUPP, the kernel internal errno some bpf commands leak
+            Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) | Some(524) => ErrorKind::KernelTooOld,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    fn from_syscall(err: &SyscallError) -> Self {
+        Self::from_io(&err.io_error)
+    }
+
+    /// The verifier refuses a program with `EACCES`, or `EINVAL` for one it
+    /// can't make sense of. Any other errno is about the syscall itself.
+    fn from_load(err: &io::Error) -> Self {
+        match err.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EINVAL) => ErrorKind::VerifierRejected,
+            _ => Self::from_io(err),
+        }
+    }
+
+    fn from_program(err: &ProgramError) -> Self {
+        match err {
+            ProgramError::LoadError { io_error, .. } => Self::from_load(io_error),
+            ProgramError::SyscallError(err) => Self::from_syscall(err),
+            ProgramError::MapError(err) => Self::from_map(err),
+            _ => ErrorKind::Other,
+        }
+    }
+
+    fn from_map(err: &MapError) -> Self {
+        match err {
+            MapError::CreateError { io_error, .. } => Self::from_io(io_error),
+            MapError::PinError { error, .. } => Self::from_pin(error),
+            MapError::SyscallError(err) => Self::from_syscall(err),
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// Pins only go into bpffs, a missing directory on the way means it
+    /// isn't mounted there
+    fn from_pin(err: &aya::pin::PinError) -> Self {
+        match err {
+            aya::pin::PinError::SyscallError(err) => match err.io_error.raw_os_error() {
+                Some(libc::ENOENT) => ErrorKind::BpffsNotMounted,
+                _ => Self::from_syscall(err),
+            },
+            _ => ErrorKind::Other,
+        }
+    }
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("{0}")]
     Pin(#[from] PinError),
 
+    #[error("{0}")]
+    Attach(#[from] AttachError),
+
     #[error("{0}")]
     Io(#[from] std::io::Error),
 
@@ -12,7 +112,19 @@ pub enum Error {
     TokenBucket(#[from] TokenBucketError),
 
     #[error("{0}")]
-    EbpfProgram(#[from] aya::programs::ProgramError),
+    TrTcm(#[from] TrTcmError),
+
+    #[error("{0}")]
+    Edt(#[from] EdtError),
+
+    #[error("{0}")]
+    Gcra(#[from] GcraError),
+
+    #[error("{0}")]
+    EbpfProgram(aya::programs::ProgramError),
+
+    #[error("The verifier rejected the program: {source}\n{log}")]
+    VerifierRejected { source: io::Error, log: String },
 
     #[error("{0}")]
     Ebpf(#[from] aya::EbpfError),
@@ -28,4 +140,152 @@ pub enum Error {
 
     #[error("{0}")]
     Cgroups(#[from] cgroups_rs::error::Error),
+
+    #[error("Only cgroup v1 is mounted at {0:?}, rateforge needs the unified cgroup2 hierarchy")]
+    CgroupV1Only(PathBuf),
+
+    #[error("{source}, undoing what was attached failed too ({rollback})")]
+    RollbackFailed {
+        source: Box<Error>,
+        rollback: Box<Error>,
+    },
+
+    #[error("The kernel is too old for {feature}, it was added in Linux {since}")]
+    KernelTooOld {
+        feature: &'static str,
+        since: &'static str,
+    },
+}
+
+/// Loads the verifier rejected keep its log, which aya would otherwise only
+/// mention in the message
+impl From<ProgramError> for Error {
+    fn from(err: ProgramError) -> Self {
+        match err {
+            ProgramError::LoadError {
+                io_error,
+                verifier_log,
+            } if ErrorKind::from_load(&io_error) == ErrorKind::VerifierRejected => {
+                Error::VerifierRejected {
+                    source: io_error,
+                    log: verifier_log.to_string(),
+                }
+            }
+            err => Error::EbpfProgram(err),
+        }
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Pin(PinError::NotBpffs(_)) => ErrorKind::BpffsNotMounted,
+            Error::Pin(PinError::Io(err)) | Error::Io(err) => ErrorKind::from_io(err),
+            Error::Pin(PinError::Map(err)) | Error::EbpfMaps(err) => ErrorKind::from_map(err),
+            Error::Pin(PinError::AyaPin(err)) => ErrorKind::from_pin(err),
+            Error::Attach(AttachError::Query { source, .. }) => ErrorKind::from_io(source),
+            Error::EbpfProgram(err) => ErrorKind::from_program(err),
+            Error::VerifierRejected { source, .. } => ErrorKind::from_load(source),
+            Error::Ebpf(EbpfError::ProgramError(err)) => ErrorKind::from_program(err),
+            Error::Ebpf(EbpfError::MapError(err)) => ErrorKind::from_map(err),
+            Error::Ebpf(EbpfError::FileError { error, .. }) => ErrorKind::from_io(error),
+            Error::Cgroups(_) if !cgroup2_mounted() => ErrorKind::CgroupV1Only,
+            Error::CgroupV1Only(_) => ErrorKind::CgroupV1Only,
+            Error::KernelTooOld { .. } => ErrorKind::KernelTooOld,
+            Error::RollbackFailed { source, .. } => source.kind(),
+            _ => ErrorKind::Other,
+        }
+    }
+
+    pub fn hint(&self) -> Option<&'static str> {
+        self.kind().hint()
+    }
+
+    /// What the verifier printed when it rejected a program
+    pub fn verifier_log(&self) -> Option<&str> {
+        match self {
+            Error::VerifierRejected { log, .. } => Some(log),
+            Error::RollbackFailed { source, .. } => source.verifier_log(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aya::sys::SyscallError;
+
+    use super::*;
+
+    fn errno(errno: i32) -> io::Error {
+        io::Error::from_raw_os_error(errno)
+    }
+
+    fn rejected(errno: i32) -> Error {
+        Error::VerifierRejected {
+            source: self::errno(errno),
+            log: String::new(),
+        }
+    }
+
+    fn pin_failed(errno: i32) -> Error {
+        Error::EbpfMaps(MapError::PinError {
+            name: Some("stats".into()),
+            error: aya::pin::PinError::SyscallError(SyscallError {
+                call: "BPF_OBJ_PIN",
+                io_error: self::errno(errno),
+            }),
+        })
+    }
+
+    #[test]
+    fn load_errors_by_errno() {
+        assert_eq!(rejected(libc::EACCES).kind(), ErrorKind::VerifierRejected);
+        assert_eq!(rejected(libc::EINVAL).kind(), ErrorKind::VerifierRejected);
+        assert_eq!(rejected(libc::EPERM).kind(), ErrorKind::MissingCapability);
+        assert_eq!(rejected(libc::ENOSYS).kind(), ErrorKind::KernelTooOld);
+        assert_eq!(rejected(libc::ENOMEM).kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn pin_errors_by_errno() {
+        assert_eq!(pin_failed(libc::ENOENT).kind(), ErrorKind::BpffsNotMounted);
+        assert_eq!(pin_failed(libc::EPERM).kind(), ErrorKind::MissingCapability);
+        assert_eq!(pin_failed(libc::EEXIST).kind(), ErrorKind::Other);
+        let no_fd = Error::EbpfMaps(MapError::PinError {
+            name: None,
+            error: aya::pin::PinError::NoFd {
+                name: "stats".into(),
+            },
+        });
+        assert_eq!(no_fd.kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn io_errors_by_errno() {
+        assert_eq!(
+            ErrorKind::from_io(&errno(libc::EPERM)),
+            ErrorKind::MissingCapability
+        );
+        assert_eq!(
+            ErrorKind::from_io(&errno(libc::EACCES)),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(ErrorKind::from_io(&errno(524)), ErrorKind::KernelTooOld);
+        assert_eq!(ErrorKind::from_io(&errno(libc::EBUSY)), ErrorKind::Other);
+    }
+
+    #[test]
+    fn wrapped_errors_keep_their_kind() {
+        let err = Error::RollbackFailed {
+            source: Box::new(rejected(libc::EPERM)),
+            rollback: Box::new(Error::General("detach".into())),
+        };
+        assert_eq!(err.kind(), ErrorKind::MissingCapability);
+        assert_eq!(
+            Error::from(PinError::NotBpffs("/tmp".into())).kind(),
+            ErrorKind::BpffsNotMounted
+        );
+        assert_eq!(Error::General("other".into()).kind(), ErrorKind::Other);
+    }
 }
@@ -0,0 +1,177 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub use algos_common::events::{DropEvent, MAX_DROP_EVENTS_PER_SEC};
+use algos_common::stats::INGRESS_STATS;
+use aya::{
+    maps::{MapData, RingBuf},
+    Ebpf,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::unix::AsyncFd,
+    sync::broadcast::{self, error::RecvError},
+    task::JoinHandle,
+};
+
+use crate::{ebpf::MapKind, Error};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+/// Userspace view of a `DropEvent`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DropRecord {
+    /// Nanoseconds since boot
+    pub timestamp_ns: u64,
+    pub id: u64,
+    pub direction: Direction,
+    pub packet_len: u64,
+    pub bucket: u64,
+    pub capacity: u64,
+    pub protocol: u8,
+    pub src: Option<SocketAddr>,
+    pub dst: Option<SocketAddr>,
+    /// The packet was let through because the program is only observing
+    pub observed: bool,
+}
+
+impl From<&DropEvent> for DropRecord {
+    fn from(event: &DropEvent) -> Self {
+        let addr = |bytes: &[u8; 16], port: u16| -> Option<SocketAddr> {
+            let ip = match event.ip_version {
+                4 => IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+                6 => IpAddr::V6(Ipv6Addr::from(*bytes)),
+                _ => return None,
+            };
+            Some(SocketAddr::new(ip, port))
+        };
+
+        Self {
+            timestamp_ns: event.tns,
+            id: event.id,
+            direction: match event.direction {
+                INGRESS_STATS => Direction::Ingress,
+                _ => Direction::Egress,
+            },
+            packet_len: event.packet_len,
+            bucket: event.bucket,
+            capacity: event.capacity,
+            protocol: event.protocol,
+            src: addr(&event.src_addr, event.src_port),
+            dst: addr(&event.dst_addr, event.dst_port),
+            observed: event.observed != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for DropRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let endpoint = |addr: &Option<SocketAddr>| match addr {
+            Some(addr) => addr.to_string(),
+            None => "?".to_string(),
+        };
+        let action = match self.observed {
+            true => "would drop",
+            false => "drop",
+        };
+        write!(
+            f,
+            "{:?} {} len:{} proto:{} {} -> {} bucket:{}/{}",
+            self.direction,
+            action,
+            self.packet_len,
+            self.protocol,
+            endpoint(&self.src),
+            endpoint(&self.dst),
+            self.bucket,
+            self.capacity
+        )
+    }
+}
+
+/// Records a stream can fall behind by before it misses the oldest ones
+const SUBSCRIBER_BACKLOG: usize = 1024;
+
+/// Reads the ring buffer a program writes its drop events into and fans each
+/// event out to every stream subscribed to it. The ring buffer has a single
+/// consumer position, two readers would each only see part of the events.
+/// The kernel side samples at most `MAX_DROP_EVENTS_PER_SEC` per cpu.
+#[derive(Debug)]
+pub struct DropEventHub {
+    sender: broadcast::Sender<DropRecord>,
+    reader: JoinHandle<()>,
+}
+
+impl DropEventHub {
+    /// Takes the ring buffer out of `ebpf` and starts reading it on the
+    /// tokio runtime
+    pub fn new(ebpf: &mut Ebpf) -> Result<Self, Error> {
+        let name = MapKind::DropEvents.to_str();
+        let map = ebpf
+            .take_map(name)
+            .ok_or(Error::General(format!("No map named: {}", name)))?;
+        let ring = AsyncFd::new(RingBuf::try_from(map)?)?;
+        let (sender, _) = broadcast::channel(SUBSCRIBER_BACKLOG);
+        let reader = tokio::spawn(read_ring(ring, sender.clone()));
+        Ok(Self { sender, reader })
+    }
+
+    /// Stream of the events read from now on
+    pub fn subscribe(&self) -> DropEventStream {
+        DropEventStream {
+            events: self.sender.subscribe(),
+        }
+    }
+}
+
+impl Drop for DropEventHub {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn read_ring(mut ring: AsyncFd<RingBuf<MapData>>, sender: broadcast::Sender<DropRecord>) {
+    loop {
+        let mut guard = match ring.readable_mut().await {
+            Ok(guard) => guard,
+            Err(err) => {
+                warn!("Drop event ring buffer failed: {}", err);
+                return;
+            }
+        };
+
+        while let Some(item) = guard.get_inner_mut().next() {
+            if item.len() < std::mem::size_of::<DropEvent>() {
+                continue;
+            }
+            let event = unsafe { std::ptr::read_unaligned(item.as_ptr() as *const DropEvent) };
+            // Nobody subscribed right now is fine, the event is just not shown
+            let _ = sender.send(DropRecord::from(&event));
+        }
+        guard.clear_ready();
+    }
+}
+
+/// One subscriber's view of a program's drop events
+#[derive(Debug)]
+pub struct DropEventStream {
+    events: broadcast::Receiver<DropRecord>,
+}
+
+impl DropEventStream {
+    /// Next event, `None` once the program is gone. A stream that falls more
+    /// than `SUBSCRIBER_BACKLOG` events behind skips the oldest.
+    pub async fn next(&mut self) -> Option<DropRecord> {
+        loop {
+            match self.events.recv().await {
+                Ok(record) => return Some(record),
+                Err(RecvError::Lagged(missed)) => warn!("Missed {} drop events", missed),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use algos_common::mode::{MODE_ENFORCE, MODE_OBSERVE};
+pub use aya::programs::CgroupAttachMode;
+
+pub use crate::attach::AttachOrder;
+use crate::{
+    config::{LinkOverhead, LogLevel},
+    ebpf::{AttachmentKind, CgroupName, ProgramId},
+    events::DropEventStream,
+    pins::{PinNamespace, PinnedObject},
+    stats::ProgramStats,
+    Error,
+};
+
+/// Algorithm agnostic description of the limit for one traffic direction.
+/// Zero burst and peak values let the algorithm pick its own defaults.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LimitSpec {
+    pub id: u64,
+
+    /// Bytes per second
+    pub rate: u64,
+
+    /// Bytes allowed above `rate`
+    pub burst: u64,
+
+    /// Bytes per second allowed for short periods
+    pub peak_rate: u64,
+
+    /// Bytes allowed above `peak_rate`
+    pub peak_burst: u64,
+
+    pub mode: EnforcementMode,
+}
+
+impl LimitSpec {
+    pub fn new(id: u64, rate: u64) -> Self {
+        Self {
+            id,
+            rate,
+            ..Default::default()
+        }
+    }
+}
+
+/// Whether a program drops traffic over the limit or only counts it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EnforcementMode {
+    #[default]
+    Enforce,
+    /// Dry run, packets over the limit are passed and counted as would drop
+    Observe,
+}
+
+impl EnforcementMode {
+    /// Value stored in the limit maps
+    pub fn raw(&self) -> u64 {
+        match self {
+            EnforcementMode::Enforce => MODE_ENFORCE,
+            EnforcementMode::Observe => MODE_OBSERVE,
+        }
+    }
+}
+
+/// Everything an algorithm needs to be constructed
+#[derive(Debug, Clone)]
+pub struct ProgramConfig {
+    pub id: ProgramId,
+    pub cgroup: CgroupName,
+    /// Interface for algorithms that attach to a device instead of a cgroup
+    pub iface: Option<String>,
+    /// How cgroup programs share the cgroup with other programs, multi-attach
+    /// unless set otherwise
+    pub attach_mode: CgroupAttachMode,
+    /// Where cgroup programs go among the other programs of the cgroup
+    pub attach_order: AttachOrder,
+    /// Where the program and its links are pinned
+    pub pins: PinNamespace,
+}
+
+impl ProgramConfig {
+    pub fn new(id: ProgramId, cgroup: CgroupName) -> Self {
+        Self {
+            id,
+            cgroup,
+            iface: None,
+            attach_mode: CgroupAttachMode::AllowMultiple,
+            attach_order: AttachOrder::Last,
+            pins: PinNamespace::default(),
+        }
+    }
+
+    pub fn iface(mut self, iface: String) -> Self {
+        self.iface = Some(iface);
+        self
+    }
+
+    pub fn attach_mode(mut self, mode: CgroupAttachMode) -> Self {
+        self.attach_mode = mode;
+        self
+    }
+
+    pub fn attach_order(mut self, order: AttachOrder) -> Self {
+        self.attach_order = order;
+        self
+    }
+
+    pub fn pins(mut self, pins: PinNamespace) -> Self {
+        self.pins = pins;
+        self
+    }
+}
+
+/// How many bytes each direction of a program could still send, used to hand
+/// the bucket state over to the program replacing it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BucketLevels {
+    pub ingress: Option<u64>,
+    pub egress: Option<u64>,
+}
+
+/// A rate limiting program that can be selected by name from the
+/// `LimitProgramFactory`.
+pub trait LimitAlgorithm: std::fmt::Debug + Send {
+    fn name(&self) -> &'static str;
+
+    fn apply(&mut self, limit: AttachmentKind<LimitSpec>) -> Result<(), Error>;
+
+    /// Attaches the program. A failed load takes back what it attached, so
+    /// the limit in place before is left as it was.
+    fn load(&mut self) -> Result<(), Error>;
+
+    fn unload(&mut self) -> Result<(), Error>;
+
+    fn stats(&mut self) -> Result<ProgramStats, Error>;
+
+    fn pin(&mut self) -> Result<PinnedObject, Error>;
+
+    /// Sampled stream of dropped packets. Every call subscribes another
+    /// stream to the same events.
+    fn drop_events(&mut self) -> Result<DropEventStream, Error> {
+        Err(Error::General(format!(
+            "{} does not report drop events",
+            self.name()
+        )))
+    }
+
+    /// Verbosity of the kernel side logging, off by default
+    fn set_log_level(&mut self, level: LogLevel) -> Result<(), Error> {
+        Err(Error::General(format!(
+            "{} can't change its log level to {:?}",
+            self.name(),
+            level
+        )))
+    }
+
+    /// Framing added to the length every packet is charged
+    fn set_overhead(&mut self, overhead: LinkOverhead) -> Result<(), Error> {
+        Err(Error::General(format!(
+            "{} can't account for {:?}",
+            self.name(),
+            overhead
+        )))
+    }
+
+    /// Limits only traffic through the given interface indexes, `None`
+    /// applies the limit on every interface
+    fn set_interfaces(&mut self, ifindexes: Option<&[u32]>) -> Result<(), Error> {
+        Err(Error::General(format!(
+            "{} can't be scoped to interfaces {:?}",
+            self.name(),
+            ifindexes
+        )))
+    }
+
+    /// Current bucket levels, read before the program is replaced
+    fn levels(&mut self) -> Result<BucketLevels, Error> {
+        Err(Error::General(format!(
+            "{} can't report its bucket levels",
+            self.name()
+        )))
+    }
+
+    /// Starts the buckets at `levels` instead of full. Called after `apply`
+    /// and before `load`, so a replacement doesn't hand out a fresh burst.
+    fn seed(&mut self, levels: BucketLevels) -> Result<(), Error> {
+        Err(Error::General(format!(
+            "{} can't be seeded with {:?}",
+            self.name(),
+            levels
+        )))
+    }
+
+    /// Leaves the limit running behind its pinned links for the next
+    /// rateforge process to take over, instead of lifting it like `close`
+    fn release(&mut self) -> Result<(), Error> {
+        Err(Error::General(format!(
+            "{} can't be left running on its own",
+            self.name()
+        )))
+    }
+
+    fn cgroup(&self) -> &CgroupName;
+
+    fn cgroup_mut(&mut self) -> &mut CgroupName;
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.unload()?;
+        self.cgroup().delete()?;
+        Ok(())
+    }
+}
@@ -1,38 +1,124 @@
+use std::collections::BTreeMap;
+
+mod algorithm;
+pub use algorithm::*;
+
 use crate::{
-    ebpf::{CgroupName, ProgramId},
-    tokenb::TokenBucketProgram,
-    util::get_ebpf,
-    Error,
+    block::BlockProgram, edt::EdtProgram, gcra::GcraProgram, skb::CgroupSkbAlgorithm,
+    tokenb::TokenBucketProgram, trtcm::TrTcmProgram, util::get_ebpf, Error,
 };
 
-pub enum LimitProgram {
-    TokenBucket(TokenBucketProgram),
-    UnknownProgram,
+pub type AlgorithmConstructor = fn(ProgramConfig) -> Result<Box<dyn LimitAlgorithm>, Error>;
+
+pub const TOKEN_BUCKET: &str = "token_bucket";
+pub const TR_TCM: &str = "trtcm";
+pub const EDT: &str = "edt";
+pub const BLOCK: &str = "block";
+pub const GCRA: &str = "gcra";
+pub const LEAKY_BUCKET: &str = "leaky_bucket";
+
+/// Registry of the available limit algorithms keyed by name
+#[derive(Debug, Clone)]
+pub struct LimitProgramFactory {
+    algorithms: BTreeMap<&'static str, AlgorithmConstructor>,
 }
 
-pub enum LimitProgramFactory {
-    TokenBucket(ProgramId, CgroupName),
+impl Default for LimitProgramFactory {
+    fn default() -> Self {
+        let mut factory = Self::empty();
+        factory.register(TOKEN_BUCKET, |config| {
+            Ok(Box::new(
+                TokenBucketProgram::new(config.id, config.cgroup, get_ebpf()?)
+                    .with_attach_mode(config.attach_mode)
+                    .with_attach_order(config.attach_order)
+                    .with_pins(config.pins),
+            ))
+        });
+        factory.register(TR_TCM, |config| {
+            Ok(Box::new(
+                TrTcmProgram::new(config.id, config.cgroup, get_ebpf()?)
+                    .with_attach_mode(config.attach_mode)
+                    .with_attach_order(config.attach_order)
+                    .with_pins(config.pins),
+            ))
+        });
+        factory.register(EDT, |config| {
+            let iface = config
+                .iface
+                .ok_or(ProgramFactoryError::MissingInterface(EDT))?;
+            Ok(Box::new(
+                EdtProgram::new(config.id, config.cgroup, iface, get_ebpf()?)
+                    .with_pins(config.pins),
+            ))
+        });
+        factory.register(GCRA, |config| {
+            Ok(Box::new(
+                GcraProgram::new(config.id, config.cgroup, get_ebpf()?)
+                    .with_attach_mode(config.attach_mode)
+                    .with_attach_order(config.attach_order)
+                    .with_pins(config.pins),
+            ))
+        });
+        factory.register(LEAKY_BUCKET, |config| {
+            Ok(Box::new(
+                GcraProgram::new(config.id, config.cgroup, get_ebpf()?)
+                    .with_name(LEAKY_BUCKET)
+                    .with_attach_mode(config.attach_mode)
+                    .with_attach_order(config.attach_order)
+                    .with_pins(config.pins),
+            ))
+        });
+        factory.register(BLOCK, |config| {
+            Ok(Box::new(
+                BlockProgram::new(config.id, config.cgroup, get_ebpf()?)
+                    .with_attach_mode(config.attach_mode)
+                    .with_attach_order(config.attach_order)
+                    .with_pins(config.pins),
+            ))
+        });
+        factory
+    }
 }
 
 impl LimitProgramFactory {
-    pub fn get_program(self) -> Result<LimitProgram, Error> {
-        match self {
-            Self::TokenBucket(id, name) => {
-                let program = TokenBucketProgram::new(id, name, get_ebpf()?);
-                Ok(LimitProgram::TokenBucket(program))
-            }
-
-            _ => Err(ProgramFactoryError::NotImplemented)?,
+    /// Factory without any algorithms registered
+    pub fn empty() -> Self {
+        Self {
+            algorithms: BTreeMap::new(),
         }
     }
 
-    pub fn token_bucket(id: ProgramId, name: CgroupName) -> Result<TokenBucketProgram, Error> {
-        Ok(TokenBucketProgram::new(id, name, get_ebpf()?))
+    /// Adds or replaces the algorithm available under `name`
+    pub fn register(&mut self, name: &'static str, constructor: AlgorithmConstructor) {
+        self.algorithms.insert(name, constructor);
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.algorithms.keys().copied()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.algorithms.contains_key(name)
+    }
+
+    pub fn create(
+        &self,
+        name: &str,
+        config: ProgramConfig,
+    ) -> Result<Box<dyn LimitAlgorithm>, Error> {
+        let constructor = self
+            .algorithms
+            .get(name)
+            .ok_or(ProgramFactoryError::NotImplemented(name.to_string()))?;
+        constructor(config)
     }
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum ProgramFactoryError {
-    #[error("Unknown program selected")]
-    NotImplemented,
+    #[error("Unknown program selected: {0}")]
+    NotImplemented(String),
+
+    #[error("Algorithm {0} needs an interface to attach to")]
+    MissingInterface(&'static str),
 }
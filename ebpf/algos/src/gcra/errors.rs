@@ -0,0 +1,7 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum GcraError {
+    #[error("{0}")]
+    NoTrafficDirection(String),
+}
@@ -0,0 +1,136 @@
+pub use algos_common::gcra::GcraLimit;
+use aya::Ebpf;
+
+use crate::{
+    config::{LinkOverhead, LogLevel},
+    ebpf::{MapKind, ProgramKind},
+    events::DropEventStream,
+    factory::{BucketLevels, LimitAlgorithm, LimitSpec, GCRA},
+    gcra::errors::GcraError,
+    pins::PinnedObject,
+    skb::{CgroupSkbAlgorithm, CgroupSkbProgram, SkbPrograms},
+    stats::ProgramStats,
+};
+pub use crate::{
+    ebpf::{AttachmentKind, CgroupName, ProgramId},
+    Error,
+};
+
+const PROGRAMS: SkbPrograms = SkbPrograms {
+    ingress: ProgramKind::CgroupIngressGcra,
+    egress: ProgramKind::CgroupEgressGcra,
+    ingress_pin: "gcraingress",
+    egress_pin: "gcraegress",
+    map: MapKind::Gcra,
+    no_traffic: |reason| GcraError::NoTrafficDirection(reason).into(),
+};
+
+/// Arrival jitter tolerated when a policy sets no burst
+pub const DEFAULT_TOLERANCE_NS: u64 = 10_000_000;
+
+/// Leaky bucket limiter using GCRA. Unlike the token bucket it does not let a
+/// full bucket through at once after an idle period, only `tolerance` worth.
+#[derive(Debug)]
+pub struct GcraProgram {
+    skb: CgroupSkbProgram,
+    /// Name it was selected by, GCRA is also offered as the leaky bucket
+    name: &'static str,
+}
+
+impl GcraProgram {
+    pub fn new(id: ProgramId, cgroup: CgroupName, ebpf: Ebpf) -> GcraProgram {
+        Self {
+            skb: CgroupSkbProgram::new(id, cgroup, ebpf, PROGRAMS),
+            name: GCRA,
+        }
+    }
+
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub fn apply_rate(&mut self, limit: AttachmentKind<GcraLimit>) -> Result<(), Error> {
+        self.skb.apply_rate(limit)
+    }
+
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.skb.close()
+    }
+}
+
+impl CgroupSkbAlgorithm for GcraProgram {
+    fn skb_mut(&mut self) -> &mut CgroupSkbProgram {
+        &mut self.skb
+    }
+}
+
+impl LimitAlgorithm for GcraProgram {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The burst is turned into the time it takes to send at the limited
+    /// rate. Peak rates have no meaning for a single rate leaky bucket.
+    fn apply(&mut self, limit: AttachmentKind<LimitSpec>) -> Result<(), Error> {
+        self.apply_rate(limit.map(|spec| {
+            let tolerance = match (spec.burst, spec.rate) {
+                (_, 0) | (0, _) => DEFAULT_TOLERANCE_NS,
+                (burst, rate) => burst.saturating_mul(1_000_000_000) / rate,
+            };
+            GcraLimit::new(spec.id, spec.rate, tolerance).with_mode(spec.mode.raw())
+        }))
+    }
+
+    fn load(&mut self) -> Result<(), Error> {
+        self.skb.load()
+    }
+
+    fn unload(&mut self) -> Result<(), Error> {
+        self.skb.unload()
+    }
+
+    fn stats(&mut self) -> Result<ProgramStats, Error> {
+        self.skb.stats()
+    }
+
+    fn set_log_level(&mut self, level: LogLevel) -> Result<(), Error> {
+        self.skb.set_log_level(level)
+    }
+
+    fn set_overhead(&mut self, overhead: LinkOverhead) -> Result<(), Error> {
+        self.skb.set_overhead(overhead)
+    }
+
+    fn set_interfaces(&mut self, ifindexes: Option<&[u32]>) -> Result<(), Error> {
+        self.skb.set_interfaces(ifindexes)
+    }
+
+    fn pin(&mut self) -> Result<PinnedObject, Error> {
+        Ok(self.skb.pin()?.build())
+    }
+
+    fn drop_events(&mut self) -> Result<DropEventStream, Error> {
+        self.skb.drop_events()
+    }
+
+    fn levels(&mut self) -> Result<BucketLevels, Error> {
+        self.skb.levels::<GcraLimit>()
+    }
+
+    fn seed(&mut self, levels: BucketLevels) -> Result<(), Error> {
+        self.skb.seed::<GcraLimit>(levels)
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        self.skb.release()
+    }
+
+    fn cgroup(&self) -> &CgroupName {
+        self.skb.cgroup()
+    }
+
+    fn cgroup_mut(&mut self) -> &mut CgroupName {
+        self.skb.cgroup_mut()
+    }
+}
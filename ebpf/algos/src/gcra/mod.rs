@@ -0,0 +1,5 @@
+mod errors;
+mod gcra;
+
+pub use errors::*;
+pub use gcra::*;
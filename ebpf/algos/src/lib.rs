@@ -1,10 +1,20 @@
+pub mod attach;
+pub mod block;
+pub mod config;
 pub mod ebpf;
+pub mod edt;
 pub mod errors;
+pub mod events;
 pub mod factory;
+pub mod gcra;
 pub mod pins;
+pub mod probe;
+pub mod skb;
+pub mod stats;
 pub mod tokenb;
+pub mod trtcm;
 mod util;
-pub use errors::Error;
+pub use errors::{Error, ErrorKind};
 
 // use algos_common::token_bucket::TokenLimit;
 // use aya::Ebpf;
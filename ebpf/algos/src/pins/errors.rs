@@ -1,25 +1,48 @@
+use std::path::PathBuf;
+
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum PinError {
-    #[error("File system: {}", 0)]
+    #[error("File system: {0}")]
     Io(#[from] std::io::Error),
 
-    #[error("{}", 0)]
+    #[error("{0}")]
     PathLocation(String),
 
-    #[error("{}", 0)]
+    #[error("{0}")]
     AyaPin(#[from] aya::pin::PinError),
 
-    #[error("{}", 0)]
+    #[error("{0}")]
+    Map(#[from] aya::maps::MapError),
+
+    #[error("{0:?} is not on a bpffs mount")]
+    NotBpffs(PathBuf),
+
+    #[error("{location:?} holds {value_size} byte values, which no known layout has")]
+    UnknownLayout {
+        location: PathBuf,
+        value_size: usize,
+    },
+
+    #[error(
+        "{location:?} was pinned with layout {version}, this build only knows up to {supported}"
+    )]
+    NewerLayout {
+        location: PathBuf,
+        version: u64,
+        supported: u64,
+    },
+
+    #[error("{0}")]
     InvalidPinObject(String),
 
-    #[error("No pinned objects({})", 0)]
+    #[error("No pinned objects({0})")]
     EmptyPinObject(String),
 
-    #[error("Unpinning a non pinned program {}", 0)]
+    #[error("Unpinning a non pinned program {0}")]
     NotPinned(String),
 
-    #[error("Unpinning a non pinned program {}", 0)]
+    #[error("Pinning an already pinned program {0}")]
     AlreadyPinned(String),
 }
@@ -0,0 +1,217 @@
+use std::mem::size_of;
+
+use algos_common::{
+    bucket::BucketLevel,
+    gcra::GcraLimit,
+    layout::{LayoutHeader, LAYOUT_KEY},
+    token_bucket::{
+        legacy::{TokenLimitV1, TokenLimitV2},
+        TokenLimit, TOKEN_LIMIT_VERSION,
+    },
+    tr_tcm::TrTcmLimit,
+};
+use aya::{
+    maps::{HashMap, Map, MapData},
+    Pod,
+};
+use log::info;
+
+use super::{PinError, PinLocation};
+use crate::{
+    ebpf::MapKind,
+    factory::BucketLevels,
+    skb::{EGRESS_KEY, INGRESS_KEY},
+    util::monotonic_ns,
+};
+
+/// Buckets read out of a pinned map, upgraded to the current layout
+#[derive(Debug)]
+pub struct Migrated<V> {
+    /// Layout the map was pinned with
+    pub from_version: u64,
+    pub entries: Vec<(u64, V)>,
+}
+
+/// Reads a token bucket map pinned by this or an older build and upgrades
+/// its buckets to the current `TokenLimit`. Maps pinned before the layout
+/// header existed are told apart by their value size.
+pub fn migrate_token_bucket(location: &PinLocation) -> Result<Migrated<TokenLimit>, PinError> {
+    let data = MapData::from_pin(location)?;
+    let value_size = data.info()?.value_size() as usize;
+    let map = Map::HashMap(data);
+
+    let migrated = if value_size == size_of::<TokenLimitV1>() {
+        Migrated {
+            from_version: 1,
+            entries: upgrade(read_entries::<TokenLimitV1>(map)?, |limit| {
+                TokenLimit::from(TokenLimitV2::from(limit))
+            }),
+        }
+    } else if value_size == size_of::<TokenLimitV2>() {
+        Migrated {
+            from_version: 2,
+            entries: upgrade(read_entries::<TokenLimitV2>(map)?, TokenLimit::from),
+        }
+    } else if value_size == size_of::<TokenLimit>() {
+        let map: HashMap<MapData, u64, TokenLimit> = HashMap::try_from(map)?;
+        let version = match map.get(&LAYOUT_KEY, 0) {
+            Ok(value) => header_of(&value)
+                .filter(LayoutHeader::is_valid)
+                .map(|header| header.version)
+                .unwrap_or(TOKEN_LIMIT_VERSION),
+            Err(_) => TOKEN_LIMIT_VERSION,
+        };
+        if version > TOKEN_LIMIT_VERSION {
+            Err(PinError::NewerLayout {
+                location: location.location().to_path_buf(),
+                version,
+                supported: TOKEN_LIMIT_VERSION,
+            })?
+        }
+        Migrated {
+            from_version: version,
+            entries: entries(&map)?,
+        }
+    } else {
+        Err(PinError::UnknownLayout {
+            location: location.location().to_path_buf(),
+            value_size,
+        })?
+    };
+
+    info!(
+        "Read {} buckets of layout {} from {:?}",
+        migrated.entries.len(),
+        migrated.from_version,
+        location
+    );
+    Ok(migrated)
+}
+
+/// Bucket levels in a limit map of `kind` a released program left pinned.
+/// Token buckets pinned by an older build are upgraded first.
+pub fn pinned_levels(kind: MapKind, location: &PinLocation) -> Result<BucketLevels, PinError> {
+    let entries = match kind {
+        MapKind::TokenBucket => levels(migrate_token_bucket(location)?.entries),
+        MapKind::TrTcm => levels(read_entries::<TrTcmLimit>(pinned_map(location)?)?),
+        MapKind::Gcra => levels(read_entries::<GcraLimit>(pinned_map(location)?)?),
+        kind => Err(PinError::InvalidPinObject(format!(
+            "{:?} holds no limits",
+            kind
+        )))?,
+    };
+    let level = |key| {
+        entries
+            .iter()
+            .find_map(|(found, level)| (*found == key).then_some(*level))
+    };
+    Ok(BucketLevels {
+        ingress: level(INGRESS_KEY),
+        egress: level(EGRESS_KEY),
+    })
+}
+
+fn pinned_map(location: &PinLocation) -> Result<Map, PinError> {
+    Ok(Map::HashMap(MapData::from_pin(location)?))
+}
+
+fn levels<V: BucketLevel>(entries: Vec<(u64, V)>) -> Vec<(u64, u64)> {
+    let now = monotonic_ns();
+    upgrade(entries, |limit| limit.level(now))
+}
+
+/// Value to store under `LAYOUT_KEY` in a map of `V`
+pub fn layout_value<V: Pod>(version: u64) -> V {
+    assert!(size_of::<V>() >= size_of::<LayoutHeader>());
+    let header = LayoutHeader::new(version);
+    let mut value: V = unsafe { std::mem::zeroed() };
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            &header as *const LayoutHeader as *const u8,
+            &mut value as *mut V as *mut u8,
+            size_of::<LayoutHeader>(),
+        )
+    };
+    value
+}
+
+fn header_of<V: Pod>(value: &V) -> Option<LayoutHeader> {
+    if size_of::<V>() < size_of::<LayoutHeader>() {
+        return None;
+    }
+    Some(unsafe { std::ptr::read_unaligned(value as *const V as *const LayoutHeader) })
+}
+
+fn read_entries<V: Pod>(map: Map) -> Result<Vec<(u64, V)>, PinError> {
+    let map: HashMap<MapData, u64, V> = HashMap::try_from(map)?;
+    entries(&map)
+}
+
+fn entries<V: Pod>(map: &HashMap<MapData, u64, V>) -> Result<Vec<(u64, V)>, PinError> {
+    let mut entries = Vec::new();
+    for entry in map.iter() {
+        let (key, value) = entry?;
+        if key != LAYOUT_KEY {
+            entries.push((key, value));
+        }
+    }
+    Ok(entries)
+}
+
+fn upgrade<V, T>(entries: Vec<(u64, V)>, f: impl Fn(V) -> T) -> Vec<(u64, T)> {
+    entries
+        .into_iter()
+        .map(|(key, value)| (key, f(value)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_value_carries_its_header() {
+        let value: TokenLimit = layout_value(TOKEN_LIMIT_VERSION);
+        let header = header_of(&value).unwrap();
+        assert!(header.is_valid());
+        assert_eq!(header.version, TOKEN_LIMIT_VERSION);
+        // The rest of the value is zeroed
+        assert_eq!(
+            (value.last_tns, value.id, value.mode, value.debt),
+            (0, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn buckets_are_not_headers() {
+        let bucket = TokenLimit::new(1, 1000, 0);
+        assert!(!header_of(&bucket).unwrap().is_valid());
+        assert!(header_of(&0u64).is_none());
+    }
+
+    #[test]
+    fn legacy_layouts_are_told_apart_by_size() {
+        let sizes = [
+            size_of::<TokenLimitV1>(),
+            size_of::<TokenLimitV2>(),
+            size_of::<TokenLimit>(),
+        ];
+        assert!(sizes.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn upgrade_keeps_keys() {
+        let v1 = TokenLimitV1 {
+            token_capacity: 1000,
+            token_bucket: 300,
+            ..Default::default()
+        };
+        let upgraded = upgrade(vec![(EGRESS_KEY, v1), (INGRESS_KEY, v1)], |limit| {
+            TokenLimit::from(TokenLimitV2::from(limit))
+        });
+        assert_eq!(upgraded.len(), 2);
+        assert_eq!(upgraded[0].0, EGRESS_KEY);
+        assert_eq!(upgraded[1].0, INGRESS_KEY);
+        assert_eq!(levels(upgraded)[0], (EGRESS_KEY, 300));
+    }
+}
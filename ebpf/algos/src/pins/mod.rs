@@ -1,7 +1,11 @@
 use std::path::{Path, PathBuf};
 
 mod errors;
+mod migrate;
+mod namespace;
 pub use errors::PinError;
+pub use migrate::{layout_value, migrate_token_bucket, pinned_levels, Migrated};
+pub use namespace::{verify_bpffs, PinNamespace, BPF_FS_MAGIC, DEFAULT_BPFFS};
 use serde::{Deserialize, Serialize};
 
 use crate::ebpf::{MapKind, ProgramId, ProgramKind};
@@ -10,9 +14,11 @@ use crate::ebpf::{MapKind, ProgramId, ProgramKind};
 pub struct PinLocation(PathBuf);
 
 impl PinLocation {
+    /// `name` directly under the default bpffs mount. Programs pin through
+    /// their `PinNamespace` instead.
     #[cfg(target_os = "linux")]
     pub fn new<P: AsRef<Path>>(name: P) -> Self {
-        Self(PathBuf::from("/sys/fs/bpf/").join(name))
+        Self(PathBuf::from(DEFAULT_BPFFS).join(name))
     }
 
     pub fn location(&self) -> &Path {
@@ -34,17 +40,14 @@ impl AsRef<Path> for PinLocation {
 impl TryFrom<PathBuf> for PinLocation {
     type Error = std::io::Error;
 
+    /// Accepts any path on a bpffs mount, wherever it is mounted
     #[cfg(target_os = "linux")]
     fn try_from(value: PathBuf) -> Result<Self, Self::Error> {
-        let base = Path::new("/sys/fs/bpf/");
-        match value.starts_with(base) {
-            true => Ok(Self(value)),
-            false => Err(std::io::Error::new(
+        match verify_bpffs(&value) {
+            Ok(()) => Ok(Self(value)),
+            Err(err) => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
-                format!(
-                    "Path must start with /sys/fs/bpf/. Received {}",
-                    value.display()
-                ),
+                format!("{}. Received {}", err, value.display()),
             )),
         }
     }
@@ -0,0 +1,116 @@
+use std::{
+    ffi::CString,
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{PinError, PinLocation};
+use crate::ebpf::ProgramId;
+
+/// Where bpffs is mounted unless configured otherwise
+pub const DEFAULT_BPFFS: &str = "/sys/fs/bpf";
+
+/// `f_type` statfs reports for a bpffs mount
+pub const BPF_FS_MAGIC: libc::__fsword_t = 0xcafe4a11;
+
+const DEFAULT_INSTANCE: &str = "default";
+
+/// Directory the pins of one rateforge instance live in,
+/// `<bpffs>/rateforge/<instance>/<policy-id>/`. Separate instances and
+/// policies never share a pin name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinNamespace {
+    bpffs: PathBuf,
+    instance: String,
+}
+
+impl Default for PinNamespace {
+    fn default() -> Self {
+        Self {
+            bpffs: PathBuf::from(DEFAULT_BPFFS),
+            instance: DEFAULT_INSTANCE.into(),
+        }
+    }
+}
+
+impl PinNamespace {
+    /// Checks that `bpffs` is a bpffs mount, for containers that mount it
+    /// somewhere other than `/sys/fs/bpf`
+    pub fn new<P: Into<PathBuf>>(bpffs: P, instance: &str) -> Result<Self, PinError> {
+        let bpffs = bpffs.into();
+        verify_bpffs(&bpffs)?;
+        if instance.is_empty() || instance.contains('/') || instance == "." || instance == ".." {
+            Err(PinError::PathLocation(format!(
+                "Invalid instance name {:?}",
+                instance
+            )))?
+        }
+
+        Ok(Self {
+            bpffs,
+            instance: instance.into(),
+        })
+    }
+
+    pub fn bpffs(&self) -> &Path {
+        &self.bpffs
+    }
+
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    /// `<bpffs>/rateforge/<instance>`
+    pub fn instance_dir(&self) -> PathBuf {
+        self.bpffs.join("rateforge").join(&self.instance)
+    }
+
+    /// `<bpffs>/rateforge/<instance>/<policy-id>`
+    pub fn policy_dir(&self, id: ProgramId) -> PathBuf {
+        self.instance_dir().join(id.to_string())
+    }
+
+    pub fn location(&self, id: ProgramId, name: &str) -> PinLocation {
+        PinLocation(self.policy_dir(id).join(name))
+    }
+
+    /// Creates the policy directory, pinning into a missing directory fails
+    pub fn create(&self, id: ProgramId) -> Result<(), PinError> {
+        std::fs::create_dir_all(self.policy_dir(id))?;
+        Ok(())
+    }
+
+    /// Removes the policy directory once nothing is pinned in it anymore
+    pub fn remove(&self, id: ProgramId) -> Result<(), PinError> {
+        let dir = self.policy_dir(id);
+        if dir.exists() && std::fs::read_dir(&dir)?.next().is_none() {
+            std::fs::remove_dir(dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Fails unless `path` lies on a bpffs mount. Paths that don't exist yet are
+/// checked through their closest existing ancestor.
+pub fn verify_bpffs(path: &Path) -> Result<(), PinError> {
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("/"));
+    let c_path = CString::new(existing.as_os_str().as_bytes())
+        .map_err(|err| PinError::PathLocation(err.to_string()))?;
+
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        Err(std::io::Error::last_os_error())?
+    }
+    let stat = unsafe { stat.assume_init() };
+
+    if stat.f_type != BPF_FS_MAGIC {
+        Err(PinError::NotBpffs(path.to_path_buf()))?
+    }
+    Ok(())
+}
@@ -0,0 +1,355 @@
+use std::{
+    ffi::CStr,
+    fmt, io,
+    os::fd::{FromRawFd, OwnedFd},
+    path::Path,
+    sync::OnceLock,
+};
+
+use serde::Serialize;
+
+use crate::{
+    ebpf::{cgroup2_mounted, CGROUP_ROOT},
+    util::bpf,
+    Error,
+};
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_PROG_LOAD: libc::c_long = 5;
+
+const BPF_PROG_TYPE_SCHED_CLS: u32 = 3;
+const BPF_PROG_TYPE_CGROUP_SKB: u32 = 8;
+const BPF_MAP_TYPE_RINGBUF: u32 = 27;
+const BPF_CGROUP_INET_INGRESS: u32 = 0;
+const BPF_CGROUP_INET_EGRESS: u32 = 1;
+
+const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+const BPF_FUNC_KTIME_GET_NS: i32 = 5;
+const BPF_FUNC_GET_CURRENT_PID_TGID: i32 = 14;
+const BPF_FUNC_PERF_EVENT_OUTPUT: i32 = 25;
+const BPF_FUNC_SKB_LOAD_BYTES: i32 = 26;
+const BPF_FUNC_SKB_CGROUP_ID: i32 = 79;
+const BPF_FUNC_RINGBUF_OUTPUT: i32 = 130;
+
+const CGROUP2: &str = "cgroup v2";
+const VMLINUX_BTF: &str = "/sys/kernel/btf/vmlinux";
+const LOG_SIZE: usize = 4096;
+
+/// One `struct bpf_insn`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+const fn call(helper: i32) -> Insn {
+    Insn {
+        code: 0x85,
+        regs: 0,
+        off: 0,
+        imm: helper,
+    }
+}
+
+/// `r0 = imm`
+const fn mov_r0(imm: i32) -> Insn {
+    Insn {
+        code: 0xb7,
+        regs: 0,
+        off: 0,
+        imm,
+    }
+}
+
+const EXIT: Insn = Insn {
+    code: 0x95,
+    regs: 0,
+    off: 0,
+    imm: 0,
+};
+
+/// The `BPF_PROG_LOAD` member of `union bpf_attr`, up to the fields the
+/// probes need
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+/// The `BPF_MAP_CREATE` member of `union bpf_attr`
+#[repr(C)]
+#[derive(Default)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+/// Probing needs the same privileges as loading the programs, so without
+/// them the answer is unknown rather than no.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Support {
+    Yes,
+    No,
+    Unknown,
+}
+
+impl From<bool> for Support {
+    fn from(supported: bool) -> Self {
+        match supported {
+            true => Support::Yes,
+            false => Support::No,
+        }
+    }
+}
+
+impl fmt::Display for Support {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Support::Yes => f.pad("yes"),
+            Support::No => f.pad("no"),
+            Support::Unknown => f.pad("unknown"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Feature {
+    pub name: &'static str,
+    /// Release that added it, where it matters for which kernels we run on
+    pub since: Option<&'static str>,
+    /// The programs can't be loaded without it, the others only disable
+    /// logging or a single algorithm
+    pub required: bool,
+    pub support: Support,
+}
+
+/// What the running kernel offers the eBPF programs
+#[derive(Debug, Clone, Serialize)]
+pub struct KernelFeatures {
+    pub release: String,
+    pub features: Vec<Feature>,
+}
+
+impl KernelFeatures {
+    pub fn probe() -> Self {
+        let feature = |name, since, required, support| Feature {
+            name,
+            since,
+            required,
+            support,
+        };
+        let skb_helper = |name, helper, required| {
+            feature(
+                name,
+                None,
+                required,
+                probe_helper(BPF_PROG_TYPE_CGROUP_SKB, helper),
+            )
+        };
+
+        let features = vec![
+            feature(CGROUP2, Some("4.5"), true, cgroup2_mounted().into()),
+            feature(
+                "cgroup_skb ingress programs",
+                Some("4.10"),
+                true,
+                probe_program(BPF_PROG_TYPE_CGROUP_SKB, BPF_CGROUP_INET_INGRESS),
+            ),
+            feature(
+                "cgroup_skb egress programs",
+                Some("4.10"),
+                true,
+                probe_program(BPF_PROG_TYPE_CGROUP_SKB, BPF_CGROUP_INET_EGRESS),
+            ),
+            feature(
+                "sched_cls programs (edt)",
+                Some("4.1"),
+                false,
+                probe_program(BPF_PROG_TYPE_SCHED_CLS, 0),
+            ),
+            skb_helper("bpf_map_lookup_elem", BPF_FUNC_MAP_LOOKUP_ELEM, true),
+            skb_helper("bpf_ktime_get_ns", BPF_FUNC_KTIME_GET_NS, true),
+            skb_helper("bpf_skb_load_bytes", BPF_FUNC_SKB_LOAD_BYTES, true),
+            skb_helper("bpf_ringbuf_output", BPF_FUNC_RINGBUF_OUTPUT, true),
+            skb_helper(
+                "bpf_perf_event_output (logging)",
+                BPF_FUNC_PERF_EVENT_OUTPUT,
+                false,
+            ),
+            skb_helper(
+                "bpf_get_current_pid_tgid (logging)",
+                BPF_FUNC_GET_CURRENT_PID_TGID,
+                false,
+            ),
+            feature(
+                "bpf_skb_cgroup_id (edt)",
+                Some("4.18"),
+                false,
+                probe_helper(BPF_PROG_TYPE_SCHED_CLS, BPF_FUNC_SKB_CGROUP_ID),
+            ),
+            feature("ring buffer maps", Some("5.8"), true, probe_ringbuf()),
+            feature(
+                "kernel BTF",
+                Some("5.4"),
+                false,
+                Path::new(VMLINUX_BTF).exists().into(),
+            ),
+        ];
+
+        Self {
+            release: kernel_release(),
+            features,
+        }
+    }
+
+    /// Probes once per process, the kernel doesn't change underneath us
+    pub fn get() -> &'static Self {
+        static FEATURES: OnceLock<KernelFeatures> = OnceLock::new();
+        FEATURES.get_or_init(Self::probe)
+    }
+
+    /// Required features the kernel is known to lack
+    pub fn missing(&self) -> impl Iterator<Item = &Feature> {
+        self.features
+            .iter()
+            .filter(|feature| feature.required && feature.support == Support::No)
+    }
+
+    /// Fails on the first required feature that is missing. Unknown ones
+    /// are left to the load, which fails with a better error.
+    pub fn check(&self) -> Result<(), Error> {
+        let Some(feature) = self.missing().next() else {
+            return Ok(());
+        };
+        match feature.name {
+            CGROUP2 => Err(Error::CgroupV1Only(CGROUP_ROOT.into())),
+            name => Err(Error::KernelTooOld {
+                feature: name,
+                since: feature.since.unwrap_or("5.8"),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for KernelFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "kernel {}", self.release)?;
+        for feature in &self.features {
+            write!(f, "  {:<40} {:<8}", feature.name, feature.support)?;
+            if let Some(since) = feature.since {
+                write!(f, " since {}", since)?;
+            }
+            if !feature.required {
+                write!(f, " (optional)")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Loads a program that only returns, which every kernel supporting the
+/// program type accepts
+fn probe_program(prog_type: u32, expected_attach_type: u32) -> Support {
+    let insns = [mov_r0(1), EXIT];
+    match load(prog_type, expected_attach_type, &insns, None) {
+        Ok(_) => Support::Yes,
+        Err(err) => support_from(&err),
+    }
+}
+
+/// Calls the helper without setting up its arguments. The verifier rejects
+/// that either way, but only complains about the function itself when the
+/// program type can't use it, which is how bpftool probes helpers too.
+fn probe_helper(prog_type: u32, helper: i32) -> Support {
+    let insns = [call(helper), EXIT];
+    let mut log = vec![0u8; LOG_SIZE];
+    match load(prog_type, 0, &insns, Some(&mut log)) {
+        Ok(_) => Support::Yes,
+        Err(err) if err.raw_os_error() == Some(libc::EPERM) => Support::Unknown,
+        Err(_) => {
+            let log = CStr::from_bytes_until_nul(&log)
+                .map(|log| log.to_string_lossy().to_string())
+                .unwrap_or_default();
+            match log.contains("invalid func ") || log.contains("unknown func ") {
+                true => Support::No,
+                false => Support::Yes,
+            }
+        }
+    }
+}
+
+fn probe_ringbuf() -> Support {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+    let mut attr = MapCreateAttr {
+        map_type: BPF_MAP_TYPE_RINGBUF,
+        max_entries: page_size,
+        ..Default::default()
+    };
+    match bpf(BPF_MAP_CREATE, &mut attr) {
+        Ok(fd) => {
+            drop(unsafe { OwnedFd::from_raw_fd(fd as i32) });
+            Support::Yes
+        }
+        Err(err) => support_from(&err),
+    }
+}
+
+fn support_from(err: &io::Error) -> Support {
+    match err.raw_os_error() {
+        Some(libc::EPERM) => Support::Unknown,
+        _ => Support::No,
+    }
+}
+
+fn load(
+    prog_type: u32,
+    expected_attach_type: u32,
+    insns: &[Insn],
+    log: Option<&mut [u8]>,
+) -> io::Result<OwnedFd> {
+    let license = c"GPL";
+    let mut attr = ProgLoadAttr {
+        prog_type,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        expected_attach_type,
+        ..Default::default()
+    };
+    if let Some(log) = log {
+        attr.log_level = 1;
+        attr.log_size = log.len() as u32;
+        attr.log_buf = log.as_mut_ptr() as u64;
+    }
+    let fd = bpf(BPF_PROG_LOAD, &mut attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+fn kernel_release() -> String {
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } != 0 {
+        return "unknown".into();
+    }
+    unsafe { CStr::from_ptr(name.release.as_ptr()) }
+        .to_string_lossy()
+        .to_string()
+}
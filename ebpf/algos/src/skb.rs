@@ -0,0 +1,416 @@
+use algos_common::{bucket::BucketLevel, layout::LAYOUT_KEY, token_bucket::TOKEN_LIMIT_VERSION};
+use aya::{
+    maps::HashMap,
+    programs::{CgroupAttachMode, CgroupSkbAttachType},
+    Ebpf, Pod,
+};
+use log::{info, warn};
+
+use crate::{
+    attach::{link_name, undo_attach, AttachOrder, Attached},
+    config::{
+        set_interfaces, set_log_level, set_overhead, set_skip_dropped, LinkOverhead, LogLevel,
+    },
+    ebpf::{AttachmentKind, CgroupName, MapKind, ProgramFlags, ProgramId, ProgramKind},
+    events::{DropEventHub, DropEventStream},
+    factory::BucketLevels,
+    gcra::GcraLimit,
+    pins::{layout_value, pinned_levels, PinError, PinLocation, PinNamespace, PinnedObjectBuilder},
+    stats::{pin_stats, read_stats, unpin_stats, ProgramStats, STATS_PNAME},
+    tokenb::TokenLimit,
+    trtcm::TrTcmLimit,
+    util::*,
+    Error,
+};
+
+/// Key of the ingress limit in an algorithm's map
+pub const INGRESS_KEY: u64 = 0;
+/// Key of the egress limit in an algorithm's map
+pub const EGRESS_KEY: u64 = 1;
+
+/// Limit maps of every algorithm, one of them may be left pinned by a
+/// released program
+const LIMIT_MAPS: [MapKind; 3] = [MapKind::TokenBucket, MapKind::TrTcm, MapKind::Gcra];
+
+/// What sets the cgroup skb programs of one algorithm apart
+#[derive(Debug, Clone, Copy)]
+pub struct SkbPrograms {
+    pub ingress: ProgramKind,
+    pub egress: ProgramKind,
+    /// Names the programs are pinned under
+    pub ingress_pin: &'static str,
+    pub egress_pin: &'static str,
+    /// Map holding the limit of each direction
+    pub map: MapKind,
+    /// Error the algorithm reports when it is loaded without any rate
+    pub no_traffic: fn(String) -> Error,
+}
+
+/// An ingress and an egress cgroup skb program sharing a limit map. The
+/// token bucket, trTCM and GCRA programs are all attached this way and only
+/// differ in the limits they store.
+#[derive(Debug)]
+pub struct CgroupSkbProgram {
+    pub id: ProgramId,
+    ebpf: Ebpf,
+    flags: ProgramFlags,
+    cgroup: CgroupName,
+    attach_mode: CgroupAttachMode,
+    attach_order: AttachOrder,
+    pins: PinNamespace,
+    programs: SkbPrograms,
+    /// Reads the drop events once the first stream asks for them
+    events: Option<DropEventHub>,
+}
+
+impl CgroupSkbProgram {
+    pub fn new(id: ProgramId, cgroup: CgroupName, ebpf: Ebpf, programs: SkbPrograms) -> Self {
+        Self {
+            id,
+            flags: ProgramFlags::BLOCKED,
+            ebpf,
+            cgroup,
+            attach_mode: CgroupAttachMode::AllowMultiple,
+            attach_order: AttachOrder::Last,
+            pins: PinNamespace::default(),
+            programs,
+            events: None,
+        }
+    }
+
+    pub fn flags(&self) -> ProgramFlags {
+        self.flags
+    }
+
+    /// Where `name` is pinned for this program
+    pub fn location(&self, name: &str) -> PinLocation {
+        self.pins.location(self.id, name)
+    }
+
+    /// Stores `value` under `key` in the limit map
+    pub fn insert<V: Pod>(&mut self, key: u64, value: V) -> Result<(), Error> {
+        let mut map: HashMap<_, u64, V> = self
+            .programs
+            .map
+            .get_mut(&mut self.ebpf)
+            .map_err(|err| Error::General(err.to_string()))?;
+        map.insert(key, value, 0)?;
+        Ok(())
+    }
+
+    /// Stores the limit of a direction and marks it to be attached
+    pub fn apply_rate<V: Pod>(&mut self, limit: AttachmentKind<V>) -> Result<(), Error> {
+        let (key, flag, limit) = match limit {
+            AttachmentKind::Ingress(limit) => (INGRESS_KEY, ProgramFlags::INGRESS, limit),
+            AttachmentKind::Egress(limit) => (EGRESS_KEY, ProgramFlags::EGRESS, limit),
+        };
+
+        self.insert(key, limit)?;
+        self.flags = self.flags.union(flag);
+        info!("{:?} Program Flags: {:?}", self.programs.map, self.flags);
+        Ok(())
+    }
+
+    /// Current levels of the limits of type `V` in the limit map
+    pub fn levels<V: Pod + BucketLevel>(&mut self) -> Result<BucketLevels, Error> {
+        let map: HashMap<_, u64, V> = self
+            .programs
+            .map
+            .get_mut(&mut self.ebpf)
+            .map_err(|err| Error::General(err.to_string()))?;
+        let now = monotonic_ns();
+        let level = |key| map.get(&key, 0).ok().map(|limit| limit.level(now));
+        Ok(BucketLevels {
+            ingress: level(INGRESS_KEY),
+            egress: level(EGRESS_KEY),
+        })
+    }
+
+    /// Starts the limits of type `V` at `levels`. Directions without a limit
+    /// yet are left alone.
+    pub fn seed<V: Pod + BucketLevel>(&mut self, levels: BucketLevels) -> Result<(), Error> {
+        let mut map: HashMap<_, u64, V> = self
+            .programs
+            .map
+            .get_mut(&mut self.ebpf)
+            .map_err(|err| Error::General(err.to_string()))?;
+        let now = monotonic_ns();
+        for (key, level) in [(INGRESS_KEY, levels.ingress), (EGRESS_KEY, levels.egress)] {
+            let Some(level) = level else { continue };
+            let Ok(mut limit) = map.get(&key, 0) else {
+                continue;
+            };
+            limit.seed(level, now);
+            map.insert(key, limit, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Seeds the limits with the map's own limit type
+    fn seed_limits(&mut self, levels: BucketLevels) -> Result<(), Error> {
+        match self.programs.map {
+            MapKind::TokenBucket => self.seed::<TokenLimit>(levels),
+            MapKind::TrTcm => self.seed::<TrTcmLimit>(levels),
+            MapKind::Gcra => self.seed::<GcraLimit>(levels),
+            kind => Err(Error::General(format!("{:?} holds no limits", kind))),
+        }
+    }
+
+    /// Bucket levels a released program of the policy left pinned, whichever
+    /// algorithm it ran. The pin is gone once read, the released program only
+    /// runs until the links are taken over.
+    fn take_released_levels(&self) -> Option<BucketLevels> {
+        for kind in LIMIT_MAPS {
+            let location = self.location(kind.to_str());
+            if !location.location().exists() {
+                continue;
+            }
+            let levels = pinned_levels(kind, &location);
+            if let Err(err) = location.delete() {
+                warn!("Could not remove {:?}: {}", location, err);
+            }
+            match levels {
+                Ok(levels) => return Some(levels),
+                Err(err) => warn!("Starting with full buckets, {:?}: {}", location, err),
+            }
+        }
+        None
+    }
+
+    fn check_directions(&self) -> Result<(), Error> {
+        if self.flags == ProgramFlags::BLOCKED {
+            no_traffic_error(self.programs.no_traffic)?
+        }
+        Ok(())
+    }
+
+    /// The programs of every direction with a limit, `(kind, attach type,
+    /// pin name)`
+    fn directions(&self) -> Vec<(ProgramKind, CgroupSkbAttachType, &'static str)> {
+        let mut directions = Vec::with_capacity(2);
+        if self.flags.contains(ProgramFlags::EGRESS) {
+            directions.push((
+                self.programs.egress,
+                CgroupSkbAttachType::Egress,
+                self.programs.egress_pin,
+            ));
+        }
+        if self.flags.contains(ProgramFlags::INGRESS) {
+            directions.push((
+                self.programs.ingress,
+                CgroupSkbAttachType::Ingress,
+                self.programs.ingress_pin,
+            ));
+        }
+        directions
+    }
+
+    fn link(&self, attach_type: CgroupSkbAttachType) -> PinLocation {
+        self.location(&link_name(self.cgroup.as_ref(), attach_type))
+    }
+
+    /// Pins the programs of every direction with a limit. Algorithms pinning
+    /// more than their programs add it to the returned builder.
+    pub fn pin(&mut self) -> Result<PinnedObjectBuilder, Error> {
+        self.check_directions()?;
+        self.pins.create(self.id)?;
+
+        let mut pin_builer = PinnedObjectBuilder::new();
+        for (kind, _, name) in self.directions() {
+            let location = self.location(name);
+            let skb = get_ebpf_cgroup(kind.into(), &mut self.ebpf)?;
+            skb.pin(&location).map_err(PinError::from)?;
+
+            info!("Pinned at {:?}", location);
+            pin_builer = pin_builer.program(self.id, kind, location);
+        }
+
+        self.flags = self.flags.union(ProgramFlags::PINNED);
+        Ok(pin_builer)
+    }
+
+    /// Attaches every direction with a limit. A failed load takes back what
+    /// it attached, so whatever limited the cgroup before still does.
+    pub fn load(&mut self) -> Result<(), Error> {
+        self.check_directions()?;
+        self.pins.create(self.id)?;
+        // Set before attaching, the root cgroup sees every packet at once
+        set_skip_dropped(&mut self.ebpf, self.cgroup.is_root())?;
+        self.stamp_layout()?;
+        if let Some(levels) = self.take_released_levels() {
+            self.seed_limits(levels)?;
+            info!("Taking over from a released program at {:?}", levels);
+        }
+
+        let mut attached = Vec::with_capacity(2);
+        let stats = self.location(STATS_PNAME);
+        let loaded = self
+            .attach_directions(&mut attached)
+            .and_then(|()| pin_stats(&mut self.ebpf, &stats));
+        let Err(err) = loaded else {
+            return Ok(());
+        };
+        match self.undo_attached(attached) {
+            Ok(()) => Err(err),
+            Err(rollback) => Err(Error::RollbackFailed {
+                source: Box::new(err),
+                rollback: Box::new(rollback),
+            }),
+        }
+    }
+
+    /// Records each direction in `attached` as soon as it is
+    fn attach_directions(
+        &mut self,
+        attached: &mut Vec<(PinLocation, Attached)>,
+    ) -> Result<(), Error> {
+        for (kind, attach_type, _) in self.directions() {
+            let link = self.link(attach_type);
+            let skb = get_ebpf_cgroup(kind.into(), &mut self.ebpf)?;
+            let done = match attach_type {
+                CgroupSkbAttachType::Egress => load_attach_egress(
+                    &self.cgroup,
+                    skb,
+                    self.attach_mode,
+                    self.attach_order,
+                    &link,
+                )?,
+                CgroupSkbAttachType::Ingress => load_attach_ingress(
+                    &self.cgroup,
+                    skb,
+                    self.attach_mode,
+                    self.attach_order,
+                    &link,
+                )?,
+            };
+            attached.push((link, done));
+        }
+        Ok(())
+    }
+
+    /// Undoes the attached directions, the last one first. Keeps going past
+    /// failures and returns the first one.
+    fn undo_attached(&self, attached: Vec<(PinLocation, Attached)>) -> Result<(), Error> {
+        let mut result = Ok(());
+        for (link, done) in attached.into_iter().rev() {
+            if let Err(err) = undo_attach(&link, done) {
+                warn!("Could not undo {:?}: {}", link, err);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        // Only goes once nothing is pinned in it, a replaced link stays
+        if let Err(err) = self.pins.remove(self.id) {
+            warn!("Could not remove the pins of policy {}: {}", self.id, err);
+        }
+        result
+    }
+
+    pub fn unload(&mut self) -> Result<(), Error> {
+        for (kind, attach_type, _) in self.directions() {
+            let link = self.link(attach_type);
+            let skb = get_ebpf_cgroup(kind.into(), &mut self.ebpf)?;
+            detach_unload(skb, &link)?;
+        }
+
+        unpin_stats(&self.location(STATS_PNAME))?;
+        self.pins.remove(self.id)?;
+        info!("Program unloaded");
+        Ok(())
+    }
+
+    /// Leaves the programs running behind their pinned links and pins the
+    /// limit map next to them, so the next rateforge process loading a
+    /// program for the policy starts from its bucket levels
+    pub fn release(&mut self) -> Result<(), Error> {
+        let location = self.pin_map()?;
+        info!("Released, limits pinned at {:?}", location);
+        Ok(())
+    }
+
+    /// Pins the limit map under its own name, replacing a map left there
+    pub fn pin_map(&mut self) -> Result<PinLocation, Error> {
+        let mut map = self.programs.map;
+        let location = self.location(map.to_str());
+        if location.location().exists() {
+            location.delete()?;
+        }
+        map.pin(&location, &mut self.ebpf)
+            .map_err(|err| Error::General(err.to_string()))?;
+        Ok(location)
+    }
+
+    /// Records the limit layout in maps that have one, so a newer build
+    /// knows how to read the map once it is pinned
+    fn stamp_layout(&mut self) -> Result<(), Error> {
+        match self.programs.map {
+            MapKind::TokenBucket => {
+                self.insert(LAYOUT_KEY, layout_value::<TokenLimit>(TOKEN_LIMIT_VERSION))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn stats(&mut self) -> Result<ProgramStats, Error> {
+        read_stats(&mut self.ebpf)
+    }
+
+    pub fn set_log_level(&mut self, level: LogLevel) -> Result<(), Error> {
+        set_log_level(&mut self.ebpf, level)
+    }
+
+    pub fn set_overhead(&mut self, overhead: LinkOverhead) -> Result<(), Error> {
+        set_overhead(&mut self.ebpf, overhead)
+    }
+
+    pub fn set_interfaces(&mut self, ifindexes: Option<&[u32]>) -> Result<(), Error> {
+        set_interfaces(&mut self.ebpf, ifindexes)
+    }
+
+    pub fn drop_events(&mut self) -> Result<DropEventStream, Error> {
+        if self.events.is_none() {
+            self.events = Some(DropEventHub::new(&mut self.ebpf)?);
+        }
+        Ok(self.events.as_ref().unwrap().subscribe())
+    }
+
+    pub fn cgroup(&self) -> &CgroupName {
+        &self.cgroup
+    }
+
+    pub fn cgroup_mut(&mut self) -> &mut CgroupName {
+        &mut self.cgroup
+    }
+
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.unload()?;
+        self.cgroup.delete()?;
+        Ok(())
+    }
+}
+
+/// Algorithms running on a `CgroupSkbProgram`
+pub trait CgroupSkbAlgorithm: Sized {
+    fn skb_mut(&mut self) -> &mut CgroupSkbProgram;
+
+    /// Defaults to `AllowMultiple`, so the program runs alongside the ones
+    /// systemd or other tools attached to the cgroup and its parents. The
+    /// kernel attaches through a bpf_link where it supports them.
+    fn with_attach_mode(mut self, mode: CgroupAttachMode) -> Self {
+        self.skb_mut().attach_mode = mode;
+        self
+    }
+
+    /// Defaults to `Last`, behind the programs already attached to the cgroup
+    fn with_attach_order(mut self, order: AttachOrder) -> Self {
+        self.skb_mut().attach_order = order;
+        self
+    }
+
+    /// Pins go under `<bpffs>/rateforge/<instance>/<id>/`
+    fn with_pins(mut self, pins: PinNamespace) -> Self {
+        self.skb_mut().pins = pins;
+        self
+    }
+}
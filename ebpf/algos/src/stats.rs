@@ -0,0 +1,71 @@
+use std::borrow::Borrow;
+
+pub use algos_common::stats::{BucketStats, EGRESS_STATS, INGRESS_STATS};
+use aya::{
+    maps::{Map, MapData, PerCpuArray},
+    Ebpf,
+};
+
+use crate::{
+    ebpf::MapKind,
+    pins::{PinError, PinLocation},
+    Error,
+};
+
+/// Name of the counters' pin in a policy's directory
+pub const STATS_PNAME: &str = "stats";
+
+/// Counters of a limiter program summed over all cpus
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProgramStats {
+    pub ingress: BucketStats,
+    pub egress: BucketStats,
+}
+
+pub fn read_stats(ebpf: &mut Ebpf) -> Result<ProgramStats, Error> {
+    let name = MapKind::Stats.to_str();
+    let map: PerCpuArray<_, BucketStats> = PerCpuArray::try_from(
+        ebpf.map(name)
+            .ok_or(Error::General(format!("No map named: {}", name)))?,
+    )?;
+    sum_stats(&map)
+}
+
+/// Pins the counters at `location`, so they can be read without the process
+/// that loaded the program. A pin left by the program it replaced is
+/// replaced as well.
+pub fn pin_stats(ebpf: &mut Ebpf, location: &PinLocation) -> Result<(), Error> {
+    let name = MapKind::Stats.to_str();
+    let map = ebpf
+        .map(name)
+        .ok_or(Error::General(format!("No map named: {}", name)))?;
+    unpin_stats(location)?;
+    map.pin(location).map_err(PinError::from)?;
+    Ok(())
+}
+
+pub fn unpin_stats(location: &PinLocation) -> Result<(), Error> {
+    if location.location().exists() {
+        location.delete()?;
+    }
+    Ok(())
+}
+
+/// Counters of a program that is only kept alive by its pins
+pub fn read_pinned_stats(location: &PinLocation) -> Result<ProgramStats, Error> {
+    let data = MapData::from_pin(location)
+        .map_err(|err| Error::General(format!("No stats pinned at {:?}: {}", location, err)))?;
+    let map: PerCpuArray<_, BucketStats> = PerCpuArray::try_from(Map::PerCpuArray(data))?;
+    sum_stats(&map)
+}
+
+fn sum_stats<T: Borrow<MapData>>(map: &PerCpuArray<T, BucketStats>) -> Result<ProgramStats, Error> {
+    let mut stats = ProgramStats::default();
+    for value in map.get(&INGRESS_STATS, 0)?.iter() {
+        stats.ingress.merge(value);
+    }
+    for value in map.get(&EGRESS_STATS, 0)?.iter() {
+        stats.egress.merge(value);
+    }
+    Ok(stats)
+}
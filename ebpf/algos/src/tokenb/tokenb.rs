@@ -1,238 +1,182 @@
 pub use algos_common::token_bucket::TokenLimit;
-use aya::{maps::HashMap, programs::cgroup_skb::CgroupSkbAttachType, Ebpf};
+use aya::{programs::cgroup_skb::CgroupSkbAttachType, Ebpf};
 use log::info;
 
-pub use crate::{
-    ebpf::{AttachmentKind, CgroupName, ProgramId},
-    Error,
-};
 use crate::{
+    config::{LinkOverhead, LogLevel},
     ebpf::{MapKind, ProgramFlags, ProgramKind},
-    pins::{PinError, PinLocation, PinnedObject, PinnedObjectBuilder},
+    events::DropEventStream,
+    factory::{BucketLevels, LimitAlgorithm, LimitSpec, TOKEN_BUCKET},
+    pins::{PinError, PinnedObject},
+    skb::{CgroupSkbAlgorithm, CgroupSkbProgram, SkbPrograms},
+    stats::ProgramStats,
     tokenb::errors::TokenBucketError,
     util::*,
 };
+pub use crate::{
+    ebpf::{AttachmentKind, CgroupName, ProgramId},
+    Error,
+};
+
+const PROGRAMS: SkbPrograms = SkbPrograms {
+    ingress: ProgramKind::CgroupIngressTknb,
+    egress: ProgramKind::CgroupEgressTknb,
+    ingress_pin: "tokenbingress",
+    egress_pin: "tokenbegress",
+    map: MapKind::TokenBucket,
+    no_traffic: |reason| TokenBucketError::NoTrafficDirection(reason).into(),
+};
 
-const EGRESS_BASE_PNAME: &str = "tokenbegress";
-const INGRESS_BASE_PNAME: &str = "tokenbingress";
+/// Time in nanoseconds the bucket waits before refilling
+pub const DEFAULT_BURST_NS: u64 = 10_000;
 
 #[derive(Debug)]
 pub struct TokenBucketProgram {
-    pub id: ProgramId,
-    ebpf: Ebpf,
-    flags: ProgramFlags,
-    cgroup: CgroupName,
+    skb: CgroupSkbProgram,
 }
 
 impl TokenBucketProgram {
     pub fn new(id: ProgramId, cgroup: CgroupName, ebpf: Ebpf) -> TokenBucketProgram {
-        let s = Self {
-            id,
-            flags: ProgramFlags::BLOCKED,
-            ebpf,
-            cgroup,
-        };
-        s
+        Self {
+            skb: CgroupSkbProgram::new(id, cgroup, ebpf, PROGRAMS),
+        }
     }
 
+    /// Pins the programs and the bucket map
     pub fn pin(&mut self) -> Result<PinnedObject, Error> {
-        if self.flags == ProgramFlags::BLOCKED {
-            no_traffic_error()?
-        }
-
-        let mut pin_builer = PinnedObjectBuilder::new();
-
-        if self.flags.contains(ProgramFlags::EGRESS) {
-            let skb = get_ebpf_cgroup(ProgramKind::CgroupEgressTknb.into(), &mut self.ebpf)
-                .map_err(|err| Error::EbpfProgram(err))?;
-
-            let location = PinLocation::new(&format!("{}{}", EGRESS_BASE_PNAME, self.id));
-            info!("Pinning at {:?}", location);
-            skb.pin(&location).map_err(|err| PinError::from(err))?;
-
-            info!("Pinned at {:?}", location);
-            pin_builer =
-                pin_builer.program(self.id.into(), ProgramKind::CgroupEgressTknb, location);
-        }
-        if self.flags.contains(ProgramFlags::INGRESS) {
-            let skb = get_ebpf_cgroup(ProgramKind::CgroupEgressTknb.into(), &mut self.ebpf)
-                .map_err(|err| Error::EbpfProgram(err))?;
-
-            let location = PinLocation::new(&format!("{}{}", INGRESS_BASE_PNAME, self.id));
+        let mut pin_builer = self.skb.pin()?;
 
-            info!("Pinning at {:?}", location);
-            skb.pin(&location).map_err(|err| PinError::from(err))?;
+        let location = self.skb.pin_map()?;
+        info!("Pinned map at {:?}", location);
+        pin_builer = pin_builer.map(MapKind::TokenBucket, location);
 
-            info!("Pinned at {:?}", location);
-            pin_builer =
-                pin_builer.program(self.id.into(), ProgramKind::CgroupIngressTknb, location);
-        }
-
-        // let map: HashMap<_, u64, TokenLimit> = MapKind::TokenBucket
-        //     .get_mut(&mut self.ebpf)
-        //     .map_err(|err| Error::General(err.to_string()))?;
-        // let location = PinLocation::new(&format!("tokenbmap{}", self.id));
-        // map.pin(&location).map_err(|err| PinError::from(err))?;
-        // pin_builer = pin_builer.map(MapKind::TokenBucket, location);
-
-        self.flags = self.flags.union(ProgramFlags::PINNED);
         Ok(pin_builer.build())
     }
 
     pub fn unpin(&mut self) -> Result<(), Error> {
-        if !self.flags.contains(ProgramFlags::PINNED) {
+        let flags = self.skb.flags();
+        if !flags.contains(ProgramFlags::PINNED) {
             Err(PinError::NotPinned(
                 "Cant unpin a program not pinned".into(),
             ))?
         }
 
-        if self.flags.contains(ProgramFlags::EGRESS) {
-            let location = PinLocation::new(format!("{}{}", EGRESS_BASE_PNAME, self.id));
+        if flags.contains(ProgramFlags::EGRESS) {
+            let location = self.skb.location(PROGRAMS.egress_pin);
             let skb = get_pinned_ebpf_cgroup(&location, CgroupSkbAttachType::Egress)?;
             skb.unpin()?;
         }
 
-        if self.flags.contains(ProgramFlags::INGRESS) {
-            let location = PinLocation::new(format!("{}{}", INGRESS_BASE_PNAME, self.id));
+        if flags.contains(ProgramFlags::INGRESS) {
+            let location = self.skb.location(PROGRAMS.ingress_pin);
             let skb = get_pinned_ebpf_cgroup(&location, CgroupSkbAttachType::Ingress)?;
             skb.unpin()?;
         }
 
-        // let map: HashMap<_, u64, TokenLimit> = MapKind::TokenBucket
-        //     .get_mut(&mut self.ebpf)
-        //     .map_err(|err| Error::General(err.to_string()))?;
-        // let location = PinLocation::new(&format!("tokenbmap{}", self.id));
-        // map.pin(&location).map_err(|err| PinError::from(err))?;
-
-        Ok(())
-    }
+        let location = self.skb.location(PROGRAMS.map.to_str());
+        if location.location().exists() {
+            location.delete()?;
+        }
 
-    fn submit_rate_to_map(
-        &mut self,
-        key: u64,
-        rate: TokenLimit,
-        map: MapKind,
-    ) -> Result<(), Error> {
-        let mut map: HashMap<_, u64, TokenLimit> = map
-            .get_mut(&mut self.ebpf)
-            .map_err(|err| Error::General(err.to_string()))?;
-        map.insert(key, rate, 0)?;
         Ok(())
     }
 
     pub fn apply_rate(&mut self, token: AttachmentKind<TokenLimit>) -> Result<(), Error> {
-        match token {
-            AttachmentKind::Ingress(tk) => {
-                self.flags = self.flags.union(ProgramFlags::INGRESS);
-                self.submit_rate_to_map(0, tk, MapKind::TokenBucket)?;
-            }
-            AttachmentKind::Egress(tk) => {
-                self.flags = self.flags.union(ProgramFlags::EGRESS);
-                self.submit_rate_to_map(1, tk, MapKind::TokenBucket)?;
-            }
-        }
-        info!("TB Program Flags: {:?}", self.flags);
-        Ok(())
+        self.skb.apply_rate(token)
     }
 
     pub fn load(&mut self) -> Result<(), Error> {
-        info!("TB Program Flags BEFORE LOAD: {:?}", self.flags);
-        self.unpin_load()
+        info!("TB Program Flags BEFORE LOAD: {:?}", self.skb.flags());
+        self.skb.load()
     }
 
-    fn pin_load(&mut self) -> Result<(), Error> {
-        //should never be the case if program is pinned
-        if self.flags == ProgramFlags::BLOCKED {
-            no_traffic_error()?
-        }
+    pub fn unload(&mut self) -> Result<(), Error> {
+        self.skb.unload()
+    }
 
-        info!("PIN LOAD TB Program Flags BEFORE LOAD: {:?}", self.flags);
-        if self.flags.contains(ProgramFlags::EGRESS) {
-            let location = PinLocation::new(format!("{}{}", EGRESS_BASE_PNAME, self.id));
-            let mut skb = get_pinned_ebpf_cgroup(&location, CgroupSkbAttachType::Egress)?;
-            load_attach_egress(&location, &mut skb)?;
-        }
+    pub fn cgroup(&self) -> &CgroupName {
+        self.skb.cgroup()
+    }
 
-        if self.flags.contains(ProgramFlags::INGRESS) {
-            let location = PinLocation::new(format!("{}{}", INGRESS_BASE_PNAME, self.id));
-            let mut skb = get_pinned_ebpf_cgroup(&location, CgroupSkbAttachType::Ingress)?;
-            load_attach_ingress(&location, &mut skb)?;
-        }
+    pub fn cgroup_mut(&mut self) -> &mut CgroupName {
+        self.skb.cgroup_mut()
+    }
 
-        Ok(())
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.skb.close()
     }
+}
 
-    pub fn unload(&mut self) -> Result<(), Error> {
-        self.unpin_unload()
+impl CgroupSkbAlgorithm for TokenBucketProgram {
+    fn skb_mut(&mut self) -> &mut CgroupSkbProgram {
+        &mut self.skb
     }
+}
 
-    fn unpin_load(&mut self) -> Result<(), Error> {
-        if self.flags == ProgramFlags::BLOCKED {
-            no_traffic_error()?
-        }
+impl LimitAlgorithm for TokenBucketProgram {
+    fn name(&self) -> &'static str {
+        TOKEN_BUCKET
+    }
 
-        if self.flags.contains(ProgramFlags::EGRESS) {
-            let skb = get_ebpf_cgroup(ProgramKind::CgroupEgressTknb.into(), &mut self.ebpf)?;
+    fn apply(&mut self, limit: AttachmentKind<LimitSpec>) -> Result<(), Error> {
+        self.apply_rate(limit.map(|spec| {
+            TokenLimit::new(spec.id, spec.rate, DEFAULT_BURST_NS).with_mode(spec.mode.raw())
+        }))
+    }
 
-            load_attach_egress(&self.cgroup, skb)?;
-        }
-        if self.flags.contains(ProgramFlags::INGRESS) {
-            let skb = get_ebpf_cgroup(ProgramKind::CgroupIngressTknb.into(), &mut self.ebpf)?;
+    fn load(&mut self) -> Result<(), Error> {
+        TokenBucketProgram::load(self)
+    }
 
-            load_attach_ingress(&self.cgroup, skb)?;
-        }
+    fn unload(&mut self) -> Result<(), Error> {
+        TokenBucketProgram::unload(self)
+    }
 
-        Ok(())
+    fn stats(&mut self) -> Result<ProgramStats, Error> {
+        self.skb.stats()
     }
 
-    fn unpin_unload(&mut self) -> Result<(), Error> {
-        if self.flags.contains(ProgramFlags::EGRESS) {
-            let skb = get_ebpf_cgroup(ProgramKind::CgroupEgressTknb.into(), &mut self.ebpf)?;
-            skb.unload()?;
-        }
-        if self.flags.contains(ProgramFlags::INGRESS) {
-            let skb = get_ebpf_cgroup(ProgramKind::CgroupIngressTknb.into(), &mut self.ebpf)?;
-            skb.unload()?;
-        }
+    fn set_log_level(&mut self, level: LogLevel) -> Result<(), Error> {
+        self.skb.set_log_level(level)
+    }
 
-        info!("Program unloaded");
-        Ok(())
+    fn set_overhead(&mut self, overhead: LinkOverhead) -> Result<(), Error> {
+        self.skb.set_overhead(overhead)
     }
 
-    // fn pin_unload(&mut self) -> Result<(), Error> {
-    //     if self.flags.contains(ProgramFlags::EGRESS) {
-    //         let location = PinLocation::new(format!("{}{}", EGRESS_BASE_PNAME, self.id));
-    //         let mut skb = get_pinned_ebpf_cgroup(&location, CgroupSkbAttachType::Egress)?;
-    //         skb.unload()?;
-    //     }
-    //
-    //     if self.flags.contains(ProgramFlags::INGRESS) {
-    //         let location = PinLocation::new(format!("{}{}", INGRESS_BASE_PNAME, self.id));
-    //         let mut skb = get_pinned_ebpf_cgroup(&location, CgroupSkbAttachType::Ingress)?;
-    //         skb.unload()?;
-    //     }
-    //     Ok(())
-    // }
-    //
-    pub fn cgroup(&self) -> &CgroupName {
-        &self.cgroup
+    fn set_interfaces(&mut self, ifindexes: Option<&[u32]>) -> Result<(), Error> {
+        self.skb.set_interfaces(ifindexes)
     }
 
-    pub fn cgroup_mut(&mut self) -> &mut CgroupName {
-        &mut self.cgroup
+    fn pin(&mut self) -> Result<PinnedObject, Error> {
+        TokenBucketProgram::pin(self)
     }
-    pub fn close(&mut self) -> Result<(), Error> {
-        self.unload()?;
-        self.cgroup.delete()?;
-        Ok(())
+
+    fn drop_events(&mut self) -> Result<DropEventStream, Error> {
+        self.skb.drop_events()
+    }
+
+    fn levels(&mut self) -> Result<BucketLevels, Error> {
+        self.skb.levels::<TokenLimit>()
     }
-}
 
-fn no_traffic_error() -> Result<(), Error> {
-    return Err(TokenBucketError::NoTrafficDirection(
-        "Program doesnt know who to attach to. Consider adding a applying a rate".into(),
-    ))?;
+    fn seed(&mut self, levels: BucketLevels) -> Result<(), Error> {
+        self.skb.seed::<TokenLimit>(levels)
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        self.skb.release()
+    }
+
+    fn cgroup(&self) -> &CgroupName {
+        self.skb.cgroup()
+    }
+
+    fn cgroup_mut(&mut self) -> &mut CgroupName {
+        self.skb.cgroup_mut()
+    }
 }
+
 // impl TokenBucketProgram {
 // fn try_from_pinned(
 //     value: PinnedObject,
@@ -0,0 +1,10 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TrTcmError {
+    #[error("{0}")]
+    NoTrafficDirection(String),
+
+    #[error("Peak rate {peak} is lower than committed rate {committed}")]
+    PeakBelowCommitted { committed: u64, peak: u64 },
+}
@@ -0,0 +1,5 @@
+mod errors;
+mod trtcm;
+
+pub use errors::*;
+pub use trtcm::*;
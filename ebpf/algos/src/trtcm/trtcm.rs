@@ -0,0 +1,141 @@
+pub use algos_common::tr_tcm::TrTcmLimit;
+use aya::Ebpf;
+
+use crate::{
+    config::{LinkOverhead, LogLevel},
+    ebpf::{MapKind, ProgramKind},
+    events::DropEventStream,
+    factory::{BucketLevels, LimitAlgorithm, LimitSpec, TR_TCM},
+    pins::PinnedObject,
+    skb::{CgroupSkbAlgorithm, CgroupSkbProgram, SkbPrograms},
+    stats::ProgramStats,
+    trtcm::errors::TrTcmError,
+};
+pub use crate::{
+    ebpf::{AttachmentKind, CgroupName, ProgramId},
+    Error,
+};
+
+const PROGRAMS: SkbPrograms = SkbPrograms {
+    ingress: ProgramKind::CgroupIngressTrTcm,
+    egress: ProgramKind::CgroupEgressTrTcm,
+    ingress_pin: "trtcmingress",
+    egress_pin: "trtcmegress",
+    map: MapKind::TrTcm,
+    no_traffic: |reason| TrTcmError::NoTrafficDirection(reason).into(),
+};
+
+/// Two rate three color limiter. Traffic above the committed rate is allowed
+/// through until the peak bucket runs dry.
+#[derive(Debug)]
+pub struct TrTcmProgram {
+    skb: CgroupSkbProgram,
+}
+
+impl TrTcmProgram {
+    pub fn new(id: ProgramId, cgroup: CgroupName, ebpf: Ebpf) -> TrTcmProgram {
+        Self {
+            skb: CgroupSkbProgram::new(id, cgroup, ebpf, PROGRAMS),
+        }
+    }
+
+    pub fn apply_rate(&mut self, limit: AttachmentKind<TrTcmLimit>) -> Result<(), Error> {
+        let (AttachmentKind::Ingress(rate) | AttachmentKind::Egress(rate)) = &limit;
+        if rate.peak_rate < rate.committed_rate {
+            Err(TrTcmError::PeakBelowCommitted {
+                committed: rate.committed_rate,
+                peak: rate.peak_rate,
+            })?
+        }
+        self.skb.apply_rate(limit)
+    }
+
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.skb.close()
+    }
+}
+
+impl CgroupSkbAlgorithm for TrTcmProgram {
+    fn skb_mut(&mut self) -> &mut CgroupSkbProgram {
+        &mut self.skb
+    }
+}
+
+impl LimitAlgorithm for TrTcmProgram {
+    fn name(&self) -> &'static str {
+        TR_TCM
+    }
+
+    /// Bursts default to one second worth of their rate and a missing peak
+    /// rate means the direction has no excess allowance.
+    fn apply(&mut self, limit: AttachmentKind<LimitSpec>) -> Result<(), Error> {
+        self.apply_rate(limit.map(|spec| {
+            let peak_rate = match spec.peak_rate {
+                0 => spec.rate,
+                rate => rate,
+            };
+            let burst = match spec.burst {
+                0 => spec.rate,
+                burst => burst,
+            };
+            let peak_burst = match spec.peak_burst {
+                0 => peak_rate,
+                burst => burst,
+            };
+            TrTcmLimit::new(spec.id, spec.rate, burst, peak_rate, peak_burst)
+                .with_mode(spec.mode.raw())
+        }))
+    }
+
+    fn load(&mut self) -> Result<(), Error> {
+        self.skb.load()
+    }
+
+    fn unload(&mut self) -> Result<(), Error> {
+        self.skb.unload()
+    }
+
+    fn stats(&mut self) -> Result<ProgramStats, Error> {
+        self.skb.stats()
+    }
+
+    fn set_log_level(&mut self, level: LogLevel) -> Result<(), Error> {
+        self.skb.set_log_level(level)
+    }
+
+    fn set_overhead(&mut self, overhead: LinkOverhead) -> Result<(), Error> {
+        self.skb.set_overhead(overhead)
+    }
+
+    fn set_interfaces(&mut self, ifindexes: Option<&[u32]>) -> Result<(), Error> {
+        self.skb.set_interfaces(ifindexes)
+    }
+
+    fn pin(&mut self) -> Result<PinnedObject, Error> {
+        Ok(self.skb.pin()?.build())
+    }
+
+    fn drop_events(&mut self) -> Result<DropEventStream, Error> {
+        self.skb.drop_events()
+    }
+
+    fn levels(&mut self) -> Result<BucketLevels, Error> {
+        self.skb.levels::<TrTcmLimit>()
+    }
+
+    fn seed(&mut self, levels: BucketLevels) -> Result<(), Error> {
+        self.skb.seed::<TrTcmLimit>(levels)
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        self.skb.release()
+    }
+
+    fn cgroup(&self) -> &CgroupName {
+        self.skb.cgroup()
+    }
+
+    fn cgroup_mut(&mut self) -> &mut CgroupName {
+        self.skb.cgroup_mut()
+    }
+}
@@ -1,23 +1,27 @@
-use std::{fmt::Debug, path::Path};
+use std::{fmt::Debug, io, path::Path};
 
 use aya::{
-    programs::{CgroupAttachMode, CgroupSkb, CgroupSkbAttachType, ProgramError},
-    Ebpf, EbpfError,
+    programs::{CgroupAttachMode, CgroupSkb, CgroupSkbAttachType, ProgramError, SchedClassifier},
+    Ebpf,
 };
 use log::{debug, info, warn};
 
-use crate::ebpf::ProgramKind;
+use crate::{
+    attach::{attach_or_replace, detach, AttachOrder, Attached},
+    config::{set_log_level, LogLevel},
+    ebpf::ProgramKind,
+    pins::PinLocation,
+    probe::KernelFeatures,
+    Error,
+};
 
-#[cfg(feature = "ebpf_logging_enabled")]
-fn enable_ebpf_logging(ebpf: &mut Ebpf) {
-    if let Err(e) = aya_log::EbpfLogger::init(&mut ebpf) {
-        // This can happen if you remove all log statements from your eBPF program.
-        warn!("failed to initialize eBPF logger: {}", e);
-    }
-    info!("Ebpf logger enabled");
-}
+/// Loads the program object after checking the kernel offers everything it
+/// needs, so an old kernel is reported as such instead of as a failed load
+pub fn get_ebpf() -> Result<Ebpf, Error> {
+    let features = KernelFeatures::get();
+    info!("Kernel features:\n{}", features);
+    features.check()?;
 
-pub fn get_ebpf() -> Result<Ebpf, EbpfError> {
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
     let rlim = libc::rlimit {
@@ -37,13 +41,13 @@ pub fn get_ebpf() -> Result<Ebpf, EbpfError> {
         "/algos"
     )))?;
 
-    #[cfg(feature = "ebpf_loggin_enabled")]
-    enable_ebpf_logging(&mut ebpf);
-    if let Err(e) = aya_log::EbpfLogger::init(&mut ebpf) {
-        // This can happen if you remove all log statements from your eBPF program.
-        warn!("failed to initialize eBPF logger: {}", e);
+    // Programs stay quiet until a log level is set at runtime, the feature
+    // only changes the starting level
+    if cfg!(feature = "ebpf_logging_enabled") {
+        if let Err(e) = set_log_level(&mut ebpf, LogLevel::Info) {
+            warn!("failed to enable eBPF logging: {}", e);
+        }
     }
-    info!("Ebpf logger enabled");
     Ok(ebpf)
 }
 
@@ -58,6 +62,17 @@ pub fn get_ebpf_cgroup<'a>(
     info!("got cgroup {}", name);
     Ok(program)
 }
+pub fn get_ebpf_classifier<'a>(
+    name: &str,
+    ebpf: &'a mut Ebpf,
+) -> Result<&'a mut SchedClassifier, aya::programs::ProgramError> {
+    let program: &mut SchedClassifier = ebpf
+        .program_mut(name)
+        .ok_or(ProgramError::InvalidName { name: name.into() })?
+        .try_into()?;
+    info!("got classifier {}", name);
+    Ok(program)
+}
 pub fn get_pinned_ebpf_cgroup<'a, P: AsRef<Path>>(
     path: P,
     attatch_type: CgroupSkbAttachType,
@@ -83,30 +98,82 @@ pub async fn get_token_bucket_egress<'a>(
 pub fn load_attach_egress<'a, P: AsRef<Path> + Debug>(
     cgroup_path: P,
     program: &'a mut CgroupSkb,
-) -> Result<(), aya::programs::ProgramError> {
-    let cgroup = std::fs::File::open(&cgroup_path)?;
-    program.load()?;
-    program.attach(
-        &cgroup,
+    mode: CgroupAttachMode,
+    order: AttachOrder,
+    link: &PinLocation,
+) -> Result<Attached, Error> {
+    let attached = attach_or_replace(
+        cgroup_path.as_ref(),
+        program,
         CgroupSkbAttachType::Egress,
-        CgroupAttachMode::Single,
+        mode,
+        order,
+        link,
     )?;
 
-    info!("Loaded Ingress Program  at {:?}", cgroup_path);
-    Ok(())
+    info!("Loaded Egress Program at {:?}", cgroup_path);
+    Ok(attached)
 }
 pub fn load_attach_ingress<'a, P: AsRef<Path> + Debug>(
     cgroup_path: P,
     program: &'a mut CgroupSkb,
-) -> Result<(), aya::programs::ProgramError> {
-    let cgroup = std::fs::File::open(&cgroup_path)?;
-    program.load()?;
-    program.attach(
-        &cgroup,
+    mode: CgroupAttachMode,
+    order: AttachOrder,
+    link: &PinLocation,
+) -> Result<Attached, Error> {
+    let attached = attach_or_replace(
+        cgroup_path.as_ref(),
+        program,
         CgroupSkbAttachType::Ingress,
-        CgroupAttachMode::Single,
+        mode,
+        order,
+        link,
     )?;
     info!("Loaded Ingress Program at {:?}", cgroup_path);
 
+    Ok(attached)
+}
+/// The pinned link keeps a program attached after it is unloaded, so it has
+/// to be detached first
+pub fn detach_unload(program: &mut CgroupSkb, link: &PinLocation) -> Result<(), Error> {
+    detach(link)?;
+    program.unload()?;
     Ok(())
 }
+/// `CLOCK_MONOTONIC`, the clock `bpf_ktime_get_ns` reads
+pub fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Runs the bpf command `cmd` on `attr` for the calls aya doesn't offer.
+/// Returns the fd the command created, if it creates one.
+pub fn bpf<T>(cmd: libc::c_long, attr: &mut T) -> io::Result<libc::c_long> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T,
+            std::mem::size_of::<T>() as u32,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+/// A program without any rate has no direction to attach to. Each algorithm
+/// reports it through its own error variant.
+pub fn no_traffic_error<E>(variant: fn(String) -> E) -> Result<(), Error>
+where
+    Error: From<E>,
+{
+    Err(variant(
+        "Program doesnt know who to attach to. Consider applying a rate".into(),
+    ))?
+}
@@ -1,72 +1,755 @@
-use std::process::exit;
+mod rules;
+mod run;
 
-use clap::Parser;
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    process::exit,
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand};
 use rtfg_core::{
-    control::{CgroupName, Policy, Rate, RateController, TokenBucketController},
-    platform::get_pids_by_name,
+    Error,
+    control::{
+        Burst, ControllerManager, DEFAULT_BPFFS, DropEventStream, DropRecord, LIFT_SIGNAL,
+        LogLevel, Pid, PinNamespace, PinnedRule, Policy, PolicyBuilder, ProcessEvent, Rate,
+        RateController, RuleId, RuleRegistry, Target, apply_transaction,
+    },
+    platform::{
+        Diagnosis, InterfaceEvent, InterfaceWatcher, ProcessTree, default_interface,
+        get_pids_by_name, pid_running,
+    },
+};
+use rules::{open_registry, report};
+use tokio::{
+    io::unix::AsyncFd,
+    signal::unix::{Signal, SignalKind, signal},
 };
-use tokio::signal;
+
+/// How often the processes of a limited tree or name are looked up
+const TREE_POLL: Duration = Duration::from_secs(2);
 
 #[derive(Debug, Parser)]
 #[command(name = "rateforge")]
 #[command(version = "1.0")]
 pub struct Commands {
-    #[arg(short, long, value_name = "Process Name")]
-    name: String,
+    #[command(subcommand)]
+    command: Tool,
+
+    ///Where bpffs is mounted, for containers that mount it elsewhere
+    #[arg(long, global = true, value_name = "PATH", default_value = DEFAULT_BPFFS)]
+    bpffs: PathBuf,
+
+    ///Name that keeps the pins of separate rateforge instances apart
+    #[arg(long, global = true, value_name = "NAME", default_value = "default")]
+    instance: String,
+}
+
+#[derive(Debug, Subcommand)]
+enum Tool {
+    /// Limit a process or the whole machine
+    Limit(LimitArgs),
+    /// Run a command under a limit that is lifted when it exits
+    Run(RunArgs),
+    /// List the rules of this instance and the processes they limit
+    List,
+    /// Lift a rule, given by id, process name or `global`
+    Remove { rule: String },
+    /// Show one rule, given by id, process name or `global`
+    Status { rule: String },
+    /// Show the counters of one rule, or of every rule
+    Stats { rule: Option<String> },
+    /// Check that this machine can run rateforge and print how to fix what it can't
+    Doctor(DoctorArgs),
+}
+
+#[derive(Debug, Args)]
+struct LimitArgs {
+    ///Process to limit, can be given more than once to limit each under a
+    ///rule of its own
+    #[arg(
+        short,
+        long,
+        value_name = "Process Name",
+        required_unless_present_any = ["global", "pids", "tree"]
+    )]
+    name: Vec<String>,
+
+    ///Limit the whole machine instead of a process. Process limits still apply
+    #[arg(long, conflicts_with = "name")]
+    global: bool,
 
-    ///Kilobytes per second
+    ///Limit only this process, can be given more than once
+    #[arg(long = "pid", value_name = "PID", conflicts_with_all = ["name", "global"])]
+    pids: Vec<u32>,
+
+    ///Limit this process and every process it starts
+    #[arg(
+        long,
+        value_name = "PID",
+        conflicts_with_all = ["name", "global", "pids"]
+    )]
+    tree: Option<u32>,
+
+    ///Leave the limit to its pinned links and exit instead of waiting for Ctrl-c
+    #[arg(long, conflicts_with_all = ["watch_drops", "dry_run"])]
+    detach: bool,
+
+    ///Print a sample of the packets dropped by the limit
+    #[arg(long)]
+    watch_drops: bool,
+
+    #[command(flatten)]
+    policy: PolicyArgs,
+}
+
+impl LimitArgs {
+    /// Rules on pids are named after them, so they can be told apart from
+    /// rules on a process name. The cgroup of the rule takes its name, which
+    /// can't be longer than 255 bytes, so several pids are named after the
+    /// first and how many follow it.
+    fn target(&self) -> Target {
+        if let Some(root) = self.tree {
+            return Target::Process(format!("tree-{}", root));
+        }
+        if let [first, rest @ ..] = self.pids.as_slice() {
+            return Target::Process(match rest.len() {
+                0 => format!("pid-{}", first),
+                more => format!("pid-{}+{}", first, more),
+            });
+        }
+        match self.name.first() {
+            Some(name) => Target::Process(name.clone()),
+            None => Target::Global,
+        }
+    }
+
+    /// Processes to move into the cgroup of a process target
+    fn processes(&self, tree: Option<&ProcessTree>) -> Result<Vec<Pid>, Error> {
+        if let Some(tree) = tree {
+            return Ok(tree.pids());
+        }
+        if !self.pids.is_empty() {
+            let pids: Vec<Pid> = self.pids.iter().map(|pid| Pid::from(*pid)).collect();
+            if let Some(pid) = pids.iter().find(|pid| !pid_running(**pid)) {
+                Err(Error::PidNotFound { pid: pid.0 })?
+            }
+            return Ok(pids);
+        }
+        let name = self.name.first().map(String::as_str).unwrap_or_default();
+        get_pids_by_name(name).ok_or_else(|| Error::ProcessNotFound { name: name.into() })
+    }
+}
+
+#[derive(Debug, Args)]
+struct RunArgs {
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    ///Command to run under the limit, after `--`
+    #[arg(last = true, required = true, value_name = "COMMAND")]
+    command: Vec<String>,
+}
+
+/// Flags `limit` and `run` share
+#[derive(Debug, Args)]
+struct PolicyArgs {
+    ///Kilobytes per second, or with a unit like 1MB
     #[arg(short, long)]
     download: Option<Rate>,
 
-    ///Kilobytes per second
+    ///Kilobytes per second, or with a unit like 200KB
     #[arg(short, long)]
     upload: Option<Rate>,
+
+    ///Kilobytes per second download may peak at. Enables two rate limiting
+    #[arg(long, requires = "download")]
+    peak_download: Option<Rate>,
+
+    ///Kilobytes per second upload may peak at. Enables two rate limiting
+    #[arg(long, requires = "upload")]
+    peak_upload: Option<Rate>,
+
+    ///Kilobytes allowed over the committed rate
+    #[arg(long)]
+    burst: Option<Burst>,
+
+    ///Kilobytes allowed over the peak rate
+    #[arg(long)]
+    peak_burst: Option<Burst>,
+
+    ///Limit algorithm: token_bucket, trtcm, gcra (leaky_bucket), edt or block
+    #[arg(short, long)]
+    algorithm: Option<String>,
+
+    ///Count what the limit would drop without dropping anything
+    #[arg(long)]
+    dry_run: bool,
+
+    ///Bytes of framing charged per packet, e.g. 38 for Ethernet or 8 more for PPPoE
+    #[arg(long, value_name = "BYTES")]
+    overhead: Option<u32>,
+
+    ///Minimum bytes charged per packet, e.g. 84 for Ethernet
+    #[arg(long, value_name = "BYTES")]
+    mpu: Option<u32>,
+
+    ///Only limit traffic through this interface, can be given more than once
+    #[arg(long = "iface", value_name = "NAME")]
+    interfaces: Vec<String>,
+
+    ///Kernel log level for this limit: off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL")]
+    ebpf_log: Option<LogLevel>,
 }
 
-async fn handle_controller(control: &mut dyn RateController, policy: Policy) {
-    match control.apply_policy(policy) {
-        Ok(_) => (),
+impl PolicyArgs {
+    fn policy(&self) -> Policy {
+        let mut builder = PolicyBuilder::new()
+            .down(self.download.unwrap_or_default().kbs())
+            .up(self.upload.unwrap_or_default().kbs())
+            .peak_down(self.peak_download.unwrap_or_default().kbs())
+            .peak_up(self.peak_upload.unwrap_or_default().kbs())
+            .committed_burst(self.burst.unwrap_or_default().kbs())
+            .peak_burst(self.peak_burst.unwrap_or_default().kbs())
+            .dry_run(self.dry_run)
+            .overhead(self.overhead.unwrap_or_default())
+            .mpu(self.mpu.unwrap_or_default());
+        if let Some(algorithm) = &self.algorithm {
+            builder = builder.algorithm(algorithm.clone());
+        }
+        for iface in &self.interfaces {
+            builder = builder.interface(iface.clone());
+        }
+        builder.build()
+    }
+}
+
+#[derive(Debug, Args)]
+struct DoctorArgs {
+    ///Print the checks as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// Exits with 1 when a check failed, so scripts can gate on it
+fn doctor(args: DoctorArgs, bpffs: &Path, instance: &str) -> ! {
+    let diagnosis = Diagnosis::run(bpffs, instance);
+    match args.json {
+        true => match serde_json::to_string_pretty(&diagnosis) {
+            Ok(json) => println!("{}", json),
+            Err(err) => {
+                eprintln!("Can't encode the diagnosis: {}", err);
+                exit(1)
+            }
+        },
+        false => print!("{}", diagnosis),
+    }
+    exit(match diagnosis.passed() {
+        true => 0,
+        false => 1,
+    })
+}
+
+async fn next_drop(events: &mut Option<DropEventStream>) -> Option<DropRecord> {
+    match events {
+        Some(events) => events.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Handler for `kind`, a limit that can't listen just misses the signal
+fn listen(kind: SignalKind, name: &str) -> Option<Signal> {
+    match signal(kind) {
+        Ok(signal) => Some(signal),
         Err(err) => {
-            eprintln!("Could not add policy: {}", err);
-            exit(1)
+            report(&format!("Can't listen for {}", name), &err.into());
+            None
+        }
+    }
+}
+
+async fn next_signal(signal: &mut Option<Signal>) {
+    match signal {
+        Some(signal) => {
+            signal.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Subscribes to interface changes when the policy names interfaces. A
+/// limit that can't follow them keeps running on the indexes it resolved.
+fn watch_interfaces(interfaces: &[String]) -> Option<AsyncFd<InterfaceWatcher>> {
+    if interfaces.is_empty() {
+        return None;
+    }
+    match InterfaceWatcher::new().and_then(AsyncFd::new) {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            report("Can't follow interface changes", &err.into());
+            None
+        }
+    }
+}
+
+/// Waits for the kernel to report interface changes
+async fn next_interfaces(
+    watcher: &mut Option<AsyncFd<InterfaceWatcher>>,
+) -> io::Result<Vec<InterfaceEvent>> {
+    let Some(watcher) = watcher else {
+        return std::future::pending().await;
+    };
+    let mut guard = watcher.readable_mut().await?;
+    let events = guard.get_inner_mut().poll();
+    guard.clear_ready();
+    events
+}
+
+/// Re-resolves the policy's interfaces when one of them was renamed, plugged
+/// in or removed
+fn follow_interfaces(
+    control: &mut dyn RateController,
+    events: io::Result<Vec<InterfaceEvent>>,
+    interfaces: &[String],
+) {
+    let events = match events {
+        Ok(events) => events,
+        Err(err) => {
+            report("Can't follow interface changes", &err.into());
+            return;
+        }
+    };
+    if !events.iter().any(|event| event.concerns(interfaces)) {
+        return;
+    }
+    if let Err(err) = control.refresh_interfaces() {
+        report("Could not update interfaces", &err);
+    }
+}
+
+/// Limits the processes the tree gained since the last tick
+fn follow_tree(control: &mut dyn RateController, tree: &mut ProcessTree) {
+    for pid in tree.poll() {
+        match control.add_task(pid) {
+            Ok(()) => (),
+            // Exited before it could be moved
+            Err(_) if !pid_running(pid) => (),
+            Err(err) => report(&format!("Can't limit process {}", pid.0), &err),
+        }
+    }
+}
+
+/// How a foreground limit ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shutdown {
+    /// Ctrl-c or `rateforge remove`, the limit is lifted
+    Lift,
+    /// SIGTERM, the limit stays behind its pinned links for the next
+    /// rateforge process to take over
+    Release,
+}
+
+async fn wait_for_exit(
+    control: &mut dyn RateController,
+    watch: bool,
+    interfaces: &[String],
+    mut tree: Option<ProcessTree>,
+) -> Shutdown {
+    let mut events = match watch {
+        true => match control.drop_events() {
+            Ok(events) => Some(events),
+            Err(err) => {
+                report("Can't watch drops", &err);
+                None
+            }
+        },
+        false => None,
+    };
+    let mut watcher = watch_interfaces(interfaces);
+    let mut tick = tokio::time::interval(TREE_POLL);
+
+    let mut lift = listen(SignalKind::from_raw(LIFT_SIGNAL), "SIGUSR1");
+    let mut terminate = listen(SignalKind::terminate(), "SIGTERM");
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Shutdown::Lift,
+            _ = next_signal(&mut lift) => return Shutdown::Lift,
+            _ = next_signal(&mut terminate) => return Shutdown::Release,
+            event = next_drop(&mut events) => match event {
+                Some(event) => println!("{}", event),
+                None => events = None,
+            },
+            events = next_interfaces(&mut watcher) => {
+                follow_interfaces(control, events, interfaces)
+            }
+            _ = tick.tick(), if tree.is_some() => {
+                if let Some(tree) = &mut tree {
+                    follow_tree(control, tree);
+                }
+            }
+        }
+    }
+}
+
+fn report_dry_run(control: &mut dyn RateController) {
+    match control.stats() {
+        Ok(stats) => {
+            println!(
+                "download would drop {} packets ({} bytes)",
+                stats.ingress.would_drop_packets, stats.ingress.would_drop_bytes
+            );
+            println!(
+                "upload would drop {} packets ({} bytes)",
+                stats.egress.would_drop_packets, stats.egress.would_drop_bytes
+            );
+        }
+        Err(err) => report("Can't read dry run stats", &err),
+    }
+}
+
+async fn handle_controller(
+    control: &mut dyn RateController,
+    policy: &Policy,
+    watch: bool,
+    log_level: Option<LogLevel>,
+    tree: Option<ProcessTree>,
+) -> Shutdown {
+    let dry_run = policy.dry_run();
+    let interfaces = policy.interfaces().to_vec();
+    if let Some(Err(err)) = log_level.map(|level| control.set_log_level(level)) {
+        report("Could not set log level", &err);
+    }
+    match dry_run {
+        true => println!("observing. Ctrl-c to quit."),
+        false => println!("limiting. Ctrl-c to quit."),
+    }
+    let shutdown = wait_for_exit(control, watch, &interfaces, tree).await;
+    if dry_run {
+        report_dry_run(control);
+    }
+    if shutdown == Shutdown::Release {
+        match control.release() {
+            Ok(()) => return Shutdown::Release,
+            Err(err) => report("Can't leave the limit running, lifting it", &err),
         }
     }
-    println!("limiting. Ctrl-c to quit.");
-    signal::ctrl_c().await;
     match control.close() {
-        Ok(_) => (),
+        Ok(_) => Shutdown::Lift,
         Err(err) => {
-            eprintln!("Failed cleaning resources: {}", err);
+            report("Failed cleaning resources", &err);
             exit(1)
         }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let args = Commands::parse();
+/// Applies the policy to `pids` in the cgroup `name`, undoing everything if
+/// a step fails
+fn process_controller(
+    name: &str,
+    pids: &[Pid],
+    policy: &Policy,
+    pins: &PinNamespace,
+) -> Result<Box<dyn RateController>, Error> {
+    let iface = default_interface().ok().flatten();
+    let target = Target::Process(name.into());
+    apply_transaction(&target, policy, pids, iface.as_deref(), pins)
+}
 
-    let policy = Policy::new(args.download, args.upload);
-    let mut cgname = CgroupName::new(args.name.as_str()).unwrap();
-    let procs = get_pids_by_name(args.name.as_str());
+/// Records what the rule enforces and on which cgroup, for the commands
+/// that show it
+fn record_rule(
+    registry: &mut RuleRegistry,
+    id: RuleId,
+    policy: &Policy,
+    control: &dyn RateController,
+) {
+    let recorded = registry
+        .set_policy(id, policy)
+        .and_then(|()| match control.cgroup() {
+            Some(cgroup) => registry.set_cgroup(id, cgroup),
+            None => Ok(()),
+        });
+    if let Err(err) = recorded {
+        report("Can't record the rule", &err);
+    }
+}
 
-    if let Some(procs) = procs {
-        for proc in procs {
-            println!("{:?}", proc);
-            cgname.add_task(proc.into()).unwrap();
+/// Leaves the rule to its pinned links. Only eBPF limits on a cgroup are
+/// attached through links, HTB and EDT limits are lifted again.
+fn detach(
+    mut control: Box<dyn RateController>,
+    registry: &mut RuleRegistry,
+    pins: &PinNamespace,
+    id: RuleId,
+    target: &Target,
+) {
+    let pinned = registry
+        .get(id)
+        .map(|entry| PinnedRule::new(entry.clone(), pins));
+    let released = match pinned {
+        Ok(rule) if !rule.links().is_empty() => control.release(),
+        _ => Err(Error::General(
+            "Only eBPF limits on a cgroup can be detached".into(),
+        )),
+    };
+    if let Err(err) = released {
+        report("Can't detach", &err);
+        if let Err(err) = control.close() {
+            report("Failed cleaning resources", &err);
         }
-    } else {
-        eprintln!("No process by the name {}", args.name);
+        let _ = registry.remove(id);
         exit(1)
     }
+    leave_to_links(registry, id, target);
+}
+
+/// Records that only the pinned links hold the rule from now on
+fn leave_to_links(registry: &mut RuleRegistry, id: RuleId, target: &Target) {
+    if let Err(err) = registry.set_owner(id, None) {
+        report("Can't record the rule", &err);
+    }
+    println!(
+        "Rule {} limits {} until `rateforge remove {}`",
+        id, target, id
+    );
+}
+
+fn pin_namespace(bpffs: &Path, instance: &str) -> PinNamespace {
+    match PinNamespace::new(bpffs, instance) {
+        Ok(pins) => pins,
+        Err(err) => {
+            report(
+                &format!("Can't pin under {}", bpffs.display()),
+                &Error::from(err),
+            );
+            exit(1)
+        }
+    }
+}
+
+async fn limit(args: LimitArgs, bpffs: &Path, instance: &str) {
+    if args.name.len() > 1 {
+        return limit_each(args, bpffs, instance).await;
+    }
+    let policy = args.policy.policy();
+    let target = args.target();
+    let tree = match args.tree.map(|root| ProcessTree::new(Pid::from(root))) {
+        Some(Ok(tree)) => Some(tree),
+        Some(Err(err)) => {
+            report("Can't find the process tree", &err);
+            exit(1)
+        }
+        None => None,
+    };
 
-    let mut controller = TokenBucketController::new(cgname);
+    let pins = pin_namespace(bpffs, instance);
+    let mut registry = open_registry(instance);
+    let claim = match registry.claim(&target, policy.algorithm(), std::process::id()) {
+        Ok(claim) => claim,
+        Err(err) => {
+            report("Can't register the rule", &err);
+            exit(1)
+        }
+    };
+    let id = claim.id;
+    let policy = policy.with_id(id);
 
-    match controller.as_mut() {
-        Ok(control) => handle_controller(control, policy).await,
+    let controller = match &target {
+        Target::Process(name) => args
+            .processes(tree.as_ref())
+            .and_then(|pids| process_controller(name, &pids, &policy, &pins)),
+        Target::Global => apply_transaction(&target, &policy, &[], None, &pins),
+    };
+    let mut control = match controller {
+        Ok(control) => control,
         Err(err) => {
-            eprintln!("Program failed initializing: {}", err);
+            report("Could not add policy", &err);
+            let _ = registry.unclaim(claim);
             exit(1)
         }
+    };
+    record_rule(&mut registry, id, &policy, control.as_ref());
+
+    if args.detach {
+        return detach(control, &mut registry, &pins, id, &target);
+    }
+    println!("Rule {} limits {}", id, target);
+    let shutdown = handle_controller(
+        control.as_mut(),
+        &policy,
+        args.watch_drops,
+        args.policy.ebpf_log,
+        tree,
+    )
+    .await;
+
+    match shutdown {
+        Shutdown::Release => leave_to_links(&mut registry, id, &target),
+        Shutdown::Lift => {
+            if let Err(err) = registry.remove(id) {
+                report(&format!("Can't unregister rule {}", id), &err);
+            }
+        }
+    }
+}
+
+/// Limits every named process under a rule of its own. One manager owns
+/// the rules, `rateforge remove` on any of them lifts them all.
+async fn limit_each(args: LimitArgs, bpffs: &Path, instance: &str) {
+    if args.detach || args.watch_drops {
+        eprintln!("--detach and --watch-drops take a single --name");
+        exit(1)
+    }
+    let policy = args.policy.policy();
+    let manager = ControllerManager::new(open_registry(instance), pin_namespace(bpffs, instance));
+    for name in &args.name {
+        let applied = manager.apply(Target::Process(name.clone()), policy.clone());
+        match applied {
+            Ok(id) => println!("Rule {} limits {}", id, name),
+            Err(err) => {
+                report(&format!("Could not limit {}", name), &err);
+                if let Err(err) = manager.shutdown_all() {
+                    report("Failed cleaning resources", &err);
+                }
+                exit(1)
+            }
+        }
+    }
+    for rule in manager.list() {
+        let level = args.policy.ebpf_log;
+        let set = level
+            .map(|level| manager.with_controller(rule.id, |control| control.set_log_level(level)));
+        if let Some(Err(err)) = set {
+            report("Could not set log level", &err);
+        }
+    }
+
+    let mut lift = listen(SignalKind::from_raw(LIFT_SIGNAL), "SIGUSR1");
+    let mut terminate = listen(SignalKind::terminate(), "SIGTERM");
+    let mut tick = tokio::time::interval(TREE_POLL);
+    let mut seen = HashSet::new();
+    println!("limiting. Ctrl-c to quit.");
+    let shutdown = loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break Shutdown::Lift,
+            _ = next_signal(&mut lift) => break Shutdown::Lift,
+            _ = next_signal(&mut terminate) => break Shutdown::Release,
+            _ = tick.tick() => route_started(&manager, &args.name, &mut seen),
+        }
+    };
+
+    if policy.dry_run() {
+        for rule in manager.list() {
+            println!("{}:", rule.target);
+            let _ = manager.with_controller(rule.id, |control| {
+                report_dry_run(control);
+                Ok(())
+            });
+        }
+    }
+    let closed = match shutdown {
+        Shutdown::Lift => manager.shutdown_all(),
+        Shutdown::Release => manager.release_all(),
+    };
+    if let Err(err) = closed {
+        report("Failed cleaning resources", &err);
+        exit(1)
+    }
+}
+
+/// Hands the processes of `names` started since the last poll to the rule
+/// of their name
+fn route_started(manager: &ControllerManager, names: &[String], seen: &mut HashSet<Pid>) {
+    let mut running = HashSet::new();
+    for name in names {
+        for pid in get_pids_by_name(name).unwrap_or_default() {
+            running.insert(pid);
+            if seen.contains(&pid) {
+                continue;
+            }
+            let started = ProcessEvent::Started {
+                pid,
+                name: name.clone(),
+            };
+            if let Err(err) = manager.route(&started) {
+                report(&format!("Can't limit process {}", pid.0), &err);
+            }
+        }
+    }
+    *seen = running;
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Commands::parse();
+    // Kernel log lines are forwarded to the `algos` target
+    let ebpf_log = match &args.command {
+        Tool::Limit(limit) => limit.policy.ebpf_log,
+        Tool::Run(run) => run.policy.ebpf_log,
+        _ => None,
+    };
+    let filter = match ebpf_log {
+        Some(_) => "warn,algos=trace",
+        None => "warn",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter)).init();
+
+    match args.command {
+        Tool::Limit(limit_args) => limit(limit_args, &args.bpffs, &args.instance).await,
+        Tool::Run(run_args) => run::run(run_args, &args.bpffs, &args.instance).await,
+        Tool::List => rules::list(&args.bpffs, &args.instance),
+        Tool::Remove { rule } => rules::remove(&rule, &args.bpffs, &args.instance),
+        Tool::Status { rule } => rules::status(&rule, &args.bpffs, &args.instance),
+        Tool::Stats { rule } => rules::stats(rule.as_deref(), &args.bpffs, &args.instance),
+        Tool::Doctor(doctor_args) => doctor(doctor_args, &args.bpffs, &args.instance),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit_target(args: &[&str]) -> Target {
+        let args = ["rateforge", "limit", "-d", "1MB"].iter().chain(args);
+        match Commands::parse_from(args).command {
+            Tool::Limit(limit) => limit.target(),
+            other => panic!("parsed {:?}", other),
+        }
+    }
+
+    #[test]
+    fn targets_are_named_after_what_they_limit() {
+        assert_eq!(
+            limit_target(&["-n", "curl"]),
+            Target::Process("curl".into())
+        );
+        assert_eq!(limit_target(&["--global"]), Target::Global);
+        assert_eq!(
+            limit_target(&["--tree", "42"]),
+            Target::Process("tree-42".into())
+        );
+        assert_eq!(
+            limit_target(&["--pid", "42"]),
+            Target::Process("pid-42".into())
+        );
+    }
+
+    #[test]
+    fn many_pids_fit_a_cgroup_name() {
+        let pids: Vec<String> = (4_000_000_000u32..4_000_000_100)
+            .map(|pid| pid.to_string())
+            .collect();
+        let mut args = Vec::new();
+        for pid in &pids {
+            args.extend(["--pid", pid.as_str()]);
+        }
+        let Target::Process(name) = limit_target(&args) else {
+            panic!("pids make a process target");
+        };
+        assert_eq!(name, "pid-4000000000+99");
     }
 }
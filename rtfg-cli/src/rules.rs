@@ -0,0 +1,224 @@
+use std::{
+    path::Path,
+    process::exit,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use rtfg_core::{
+    Error,
+    control::{
+        BucketStats, Pid, PinNamespace, PinnedRule, Policy, Rate, RuleId, RuleRegistry, RuleState,
+    },
+};
+
+/// How long `remove` waits for a foreground limit to lift itself
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// Prints `err` after `context`, followed by how to fix it when we know
+pub fn report(context: &str, err: &Error) {
+    eprintln!("{}: {}", context, err);
+    if let Some(hint) = err.hint() {
+        eprintln!("hint: {}", hint);
+    }
+}
+
+pub fn open_registry(instance: &str) -> RuleRegistry {
+    match RuleRegistry::open(RuleRegistry::path_for(instance)) {
+        Ok(registry) => registry,
+        Err(err) => {
+            report("Can't open the rule registry", &err);
+            exit(1)
+        }
+    }
+}
+
+/// The namespace `limit` pinned the rules of `instance` in
+fn namespace(bpffs: &Path, instance: &str) -> PinNamespace {
+    match PinNamespace::new(bpffs, instance) {
+        Ok(pins) => pins,
+        Err(err) => {
+            report(
+                &format!("Can't read pins under {}", bpffs.display()),
+                &Error::from(err),
+            );
+            exit(1)
+        }
+    }
+}
+
+fn resolve(registry: &RuleRegistry, key: &str, pins: &PinNamespace) -> PinnedRule {
+    match registry.resolve(key) {
+        Ok(entry) => PinnedRule::new(entry.clone(), pins),
+        Err(err) => {
+            report("Unknown rule", &err);
+            exit(1)
+        }
+    }
+}
+
+fn rate(rate: Option<&Rate>) -> String {
+    match rate {
+        Some(rate) => format!("{} KB/s", rate.kbs()),
+        None => "-".into(),
+    }
+}
+
+fn pids(pids: &[Pid]) -> String {
+    match pids.is_empty() {
+        true => "-".into(),
+        false => pids
+            .iter()
+            .map(|pid| pid.0.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+pub fn list(bpffs: &Path, instance: &str) {
+    let pins = namespace(bpffs, instance);
+    let registry = open_registry(instance);
+    if registry.is_empty() {
+        println!("No rules");
+        return;
+    }
+
+    println!(
+        "{:<4} {:<16} {:<14} {:<12} {:<12} {:<22} PROCESSES",
+        "ID", "TARGET", "ALGORITHM", "DOWN", "UP", "STATE"
+    );
+    for entry in registry.iter() {
+        let rule = PinnedRule::new(entry.clone(), &pins);
+        let policy = entry.policy.as_ref();
+        println!(
+            "{:<4} {:<16} {:<14} {:<12} {:<12} {:<22} {}",
+            entry.id,
+            entry.target,
+            entry.algorithm,
+            rate(policy.and_then(Policy::down)),
+            rate(policy.and_then(Policy::up)),
+            rule.state().to_string(),
+            pids(&rule.processes())
+        );
+    }
+}
+
+pub fn status(key: &str, bpffs: &Path, instance: &str) {
+    let pins = namespace(bpffs, instance);
+    let registry = open_registry(instance);
+    let rule = resolve(&registry, key, &pins);
+    let entry = &rule.entry;
+
+    println!("rule {} on {}", entry.id, entry.target);
+    println!("  state: {}", rule.state());
+    println!("  algorithm: {}", entry.algorithm);
+    if let Some(policy) = &entry.policy {
+        println!(
+            "  download: {} (peak {})",
+            rate(policy.down()),
+            rate(policy.peak_down())
+        );
+        println!(
+            "  upload: {} (peak {})",
+            rate(policy.up()),
+            rate(policy.peak_up())
+        );
+        if policy.dry_run() {
+            println!("  dry run: counting only");
+        }
+        match policy.interfaces().is_empty() {
+            true => println!("  interfaces: all"),
+            false => println!("  interfaces: {}", policy.interfaces().join(", ")),
+        }
+    }
+    println!("  cgroup: {}", rule.cgroup().display());
+    if !rule.is_global() {
+        println!("  processes: {}", pids(&rule.processes()));
+    }
+    println!("  pins: {}", rule.pin_dir().display());
+    for link in rule.links() {
+        println!("    {}", link);
+    }
+}
+
+fn print_direction(direction: &str, stats: &BucketStats) {
+    println!(
+        "  {}: passed {} packets ({} bytes), dropped {} packets ({} bytes)",
+        direction,
+        stats.passed_packets,
+        stats.passed_bytes,
+        stats.dropped_packets,
+        stats.dropped_bytes
+    );
+    if stats.would_drop_packets != 0 {
+        println!(
+            "  {}: would drop {} packets ({} bytes)",
+            direction, stats.would_drop_packets, stats.would_drop_bytes
+        );
+    }
+}
+
+/// Counters of the rule given by `key`, or of every rule
+pub fn stats(key: Option<&str>, bpffs: &Path, instance: &str) {
+    let pins = namespace(bpffs, instance);
+    let registry = open_registry(instance);
+    let rules: Vec<PinnedRule> = match key {
+        Some(key) => vec![resolve(&registry, key, &pins)],
+        None => registry
+            .iter()
+            .map(|entry| PinnedRule::new(entry.clone(), &pins))
+            .collect(),
+    };
+
+    for rule in rules {
+        println!("rule {} on {}", rule.entry.id, rule.entry.target);
+        match rule.stats() {
+            Ok(stats) => {
+                print_direction("download", &stats.ingress);
+                print_direction("upload", &stats.egress);
+            }
+            Err(err) => report("  Can't read the counters", &err),
+        }
+    }
+}
+
+/// A foreground limit is asked to lift itself, a detached one is lifted
+/// here from its pins
+pub fn remove(key: &str, bpffs: &Path, instance: &str) {
+    let pins = namespace(bpffs, instance);
+    let mut registry = open_registry(instance);
+    let rule = resolve(&registry, key, &pins);
+    let id = rule.entry.id;
+
+    let removed = match rule.state() {
+        RuleState::Foreground(pid) => rule
+            .stop_owner()
+            .and_then(|()| wait_removed(id, instance, pid)),
+        RuleState::Detached | RuleState::Inactive => {
+            rule.lift().and_then(|()| registry.remove(id).map(|_| ()))
+        }
+    };
+    match removed {
+        Ok(()) => println!("Rule {} on {} removed", id, rule.entry.target),
+        Err(err) => {
+            report(&format!("Can't remove rule {}", id), &err);
+            exit(1)
+        }
+    }
+}
+
+fn wait_removed(id: RuleId, instance: &str, pid: u32) -> Result<(), Error> {
+    let started = Instant::now();
+    while started.elapsed() < STOP_TIMEOUT {
+        let registry = RuleRegistry::open(RuleRegistry::path_for(instance))?;
+        if registry.get(id).is_err() {
+            return Ok(());
+        }
+        sleep(STOP_POLL);
+    }
+    Err(Error::General(format!(
+        "rateforge process {} did not lift rule {} in time",
+        pid, id
+    )))
+}
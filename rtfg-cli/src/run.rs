@@ -0,0 +1,191 @@
+use std::{
+    fs::OpenOptions,
+    io,
+    os::{fd::AsRawFd, unix::process::ExitStatusExt},
+    path::Path,
+    process::{ExitStatus, exit},
+};
+
+use rtfg_core::{
+    Error,
+    control::{LIFT_SIGNAL, RateController, RuleId, RuleRegistry, Target, apply_transaction},
+    platform::default_interface,
+};
+use tokio::{
+    process::{Child, Command},
+    signal::unix::SignalKind,
+};
+
+use crate::{
+    RunArgs, follow_interfaces, listen, next_interfaces, next_signal, pin_namespace, record_rule,
+    report_dry_run,
+    rules::{open_registry, report},
+    watch_interfaces,
+};
+
+/// Exit code of a command that could not be started, as shells use it
+const SPAWN_FAILED: i32 = 127;
+
+/// Rules of `run` are named after the command and this process, so several
+/// runs of the same command get a cgroup each
+fn rule_name(program: &str) -> String {
+    let program = Path::new(program)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| program.to_string());
+    format!("{}-{}", program, std::process::id())
+}
+
+/// Starts the command inside `cgroup`. The child writes itself into
+/// `cgroup.procs` between fork and exec, so it never sends a packet outside
+/// the limit.
+fn spawn(command: &[String], cgroup: &Path) -> io::Result<Child> {
+    let procs = OpenOptions::new()
+        .write(true)
+        .open(cgroup.join("cgroup.procs"))?;
+    let fd = procs.as_raw_fd();
+
+    let mut child = Command::new(&command[0]);
+    child.args(&command[1..]);
+    // Only async signal safe calls between fork and exec, the file is
+    // opened above and closed on exec
+    unsafe {
+        child.pre_exec(move || {
+            let pid = b"0";
+            match libc::write(fd, pid.as_ptr().cast(), pid.len()) {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        });
+    }
+    child.spawn()
+}
+
+/// Waits for the command while the limit follows its interfaces. Ctrl-c
+/// reaches the command too, so only SIGTERM is passed on. The limit of a
+/// command ends with it, so `rateforge remove` stops the command the same way.
+async fn wait_child(
+    child: &mut Child,
+    control: &mut dyn RateController,
+    interfaces: &[String],
+) -> io::Result<ExitStatus> {
+    // Stays valid until the wait below reaps the command
+    let pid = child.id();
+    let mut watcher = watch_interfaces(interfaces);
+    let mut lift = listen(SignalKind::from_raw(LIFT_SIGNAL), "SIGUSR1");
+    let mut terminate = listen(SignalKind::terminate(), "SIGTERM");
+    let stop = || {
+        if let Some(pid) = pid {
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        }
+    };
+
+    loop {
+        tokio::select! {
+            status = child.wait() => return status,
+            _ = tokio::signal::ctrl_c() => (),
+            _ = next_signal(&mut lift) => stop(),
+            _ = next_signal(&mut terminate) => stop(),
+            events = next_interfaces(&mut watcher) => {
+                follow_interfaces(control, events, interfaces)
+            }
+        }
+    }
+}
+
+/// A command killed by a signal exits like it would from a shell
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+/// Lifts the limit once the command is gone and passes its exit code on
+fn finish(
+    mut control: Box<dyn RateController>,
+    registry: &mut RuleRegistry,
+    id: RuleId,
+    dry_run: bool,
+    code: i32,
+) -> ! {
+    if dry_run {
+        report_dry_run(control.as_mut());
+    }
+    // A daemon the command left in the cgroup keeps it from being removed,
+    // that is reported but doesn't change how the command exited
+    if let Err(err) = control.close() {
+        report("Failed cleaning resources", &err);
+    }
+    if let Err(err) = registry.remove(id) {
+        report(&format!("Can't unregister rule {}", id), &err);
+    }
+    exit(code)
+}
+
+/// Attaches the limit to a fresh cgroup first and starts the command in it,
+/// so the command is limited from its first packet on
+pub async fn run(args: RunArgs, bpffs: &Path, instance: &str) -> ! {
+    let policy = args.policy.policy();
+    let target = Target::Process(rule_name(&args.command[0]));
+
+    let pins = pin_namespace(bpffs, instance);
+    let mut registry = open_registry(instance);
+    let claim = match registry.claim(&target, policy.algorithm(), std::process::id()) {
+        Ok(claim) => claim,
+        Err(err) => {
+            report("Can't register the rule", &err);
+            exit(1)
+        }
+    };
+    let id = claim.id;
+    let policy = policy.with_id(id);
+
+    // Nothing to move yet, the command starts inside the cgroup
+    let iface = default_interface().ok().flatten();
+    let mut control = match apply_transaction(&target, &policy, &[], iface.as_deref(), &pins) {
+        Ok(control) => control,
+        Err(err) => {
+            report("Could not add policy", &err);
+            let _ = registry.unclaim(claim);
+            exit(1)
+        }
+    };
+    record_rule(&mut registry, id, &policy, control.as_ref());
+    if let Some(Err(err)) = args
+        .policy
+        .ebpf_log
+        .map(|level| control.set_log_level(level))
+    {
+        report("Could not set log level", &err);
+    }
+
+    let Some(cgroup) = control.cgroup().map(Path::to_path_buf) else {
+        report(
+            "Can't start the command",
+            &Error::General("The limit is on no cgroup".into()),
+        );
+        finish(control, &mut registry, id, false, SPAWN_FAILED)
+    };
+    let mut child = match spawn(&args.command, &cgroup) {
+        Ok(child) => child,
+        Err(err) => {
+            report(
+                &format!("Can't start {}", args.command[0]),
+                &Error::from(err),
+            );
+            finish(control, &mut registry, id, false, SPAWN_FAILED)
+        }
+    };
+
+    let status = wait_child(&mut child, control.as_mut(), policy.interfaces()).await;
+    let code = match status {
+        Ok(status) => exit_code(status),
+        Err(err) => {
+            report("Lost track of the command", &Error::from(err));
+            1
+        }
+    };
+    finish(control, &mut registry, id, policy.dry_run(), code)
+}
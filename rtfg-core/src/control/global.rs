@@ -0,0 +1,89 @@
+use std::path::Path;
+
+use ebpf::factory::{CgroupAttachMode, LimitProgramFactory, ProgramConfig};
+use log::warn;
+
+use crate::Error;
+
+use super::{
+    CgroupName, DropEventStream, EbpfController, LogLevel, PinNamespace, Policy, ProgramStats,
+    RateController, TOKEN_BUCKET,
+};
+
+/// Caps the traffic of the whole machine with a token bucket on the root
+/// cgroup.
+///
+/// The program is attached with `AllowMultiple`, so the programs of per
+/// process limits keep running underneath it and a packet needs tokens in
+/// both buckets to pass. The kernel runs every program of the hierarchy, so
+/// a process limit marks the packets it drops and the global bucket lets
+/// them through without charging them.
+#[derive(Debug)]
+pub struct GlobalController {
+    inner: EbpfController,
+}
+
+impl GlobalController {
+    pub fn new() -> Result<Self, Error> {
+        Self::with_pins(PinNamespace::default())
+    }
+
+    pub fn with_pins(pins: PinNamespace) -> Result<Self, Error> {
+        Self::with_factory(&LimitProgramFactory::default(), pins)
+    }
+
+    /// Pinned under policy id 0 in `pins`
+    pub fn with_factory(factory: &LimitProgramFactory, pins: PinNamespace) -> Result<Self, Error> {
+        let config = ProgramConfig::new(0.into(), CgroupName::root())
+            .attach_mode(CgroupAttachMode::AllowMultiple)
+            .pins(pins);
+        Ok(Self {
+            inner: EbpfController::with_config(factory, TOKEN_BUCKET, config)?,
+        })
+    }
+}
+
+impl RateController for GlobalController {
+    fn apply_policy(&mut self, policy: Policy) -> Result<(), Error> {
+        if policy.algorithm() != TOKEN_BUCKET {
+            warn!(
+                "Global limits always use {}, ignoring {}",
+                TOKEN_BUCKET,
+                policy.algorithm()
+            );
+        }
+        self.inner.apply_policy(policy)
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.inner.close()
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        self.inner.release()
+    }
+
+    fn cgroup(&self) -> Option<&Path> {
+        self.inner.cgroup()
+    }
+
+    fn drop_events(&mut self) -> Result<DropEventStream, Error> {
+        self.inner.drop_events()
+    }
+
+    fn stats(&mut self) -> Result<ProgramStats, Error> {
+        self.inner.stats()
+    }
+
+    fn refresh_interfaces(&mut self) -> Result<(), Error> {
+        self.inner.refresh_interfaces()
+    }
+
+    fn set_log_level(&mut self, level: LogLevel) -> Result<(), Error> {
+        self.inner.set_log_level(level)
+    }
+
+    fn switch_algorithm(&mut self, algorithm: &str) -> Result<(), Error> {
+        self.inner.switch_algorithm(algorithm)
+    }
+}
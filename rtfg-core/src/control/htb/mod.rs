@@ -0,0 +1,330 @@
+mod nft;
+mod tc;
+
+use std::{collections::HashSet, io, path::Path};
+
+use ebpf::ebpf::CGROUP_ROOT;
+use log::{info, warn};
+use netlink_packet_core::{NLM_F_ACK, NLM_F_DUMP};
+use netlink_packet_route::{RtnlMessage, link::nlas::InfoKind};
+
+use crate::{
+    Error,
+    platform::{NETLINK_NETFILTER, NETLINK_ROUTE, NetlinkSocket, message},
+};
+
+use super::{ApplyStep, CgroupName, LinkOverhead, Pid, Policy, RateController};
+
+/// Shared by every controller so ingress traffic is only redirected once
+const IFB_DEVICE: &str = "rtfg-ifb";
+const MARK_BASE: u32 = 0x5254_0000;
+/// Class 1:0 is the qdisc itself and 0xffff is left free for a default
+const CLASS_MINORS: std::ops::RangeInclusive<u16> = 1..=0xfffe;
+/// Requests that create something and fail if it exists
+const EXCLUSIVE: u16 = NLM_F_ACK | libc::NLM_F_CREATE as u16 | libc::NLM_F_EXCL as u16;
+
+/// Rate controller that uses the kernel's traffic control instead of eBPF.
+///
+/// Sockets in the cgroup get a firewall mark from nftables, which a `fw` filter
+/// steers into an HTB class on the interface. Download traffic is redirected
+/// through an IFB device so it can be shaped the same way. Everything is set
+/// up over netlink in the current network namespace, or in `netns` when set,
+/// so the controller can be exercised on a veth pair inside a test namespace.
+#[derive(Debug)]
+pub struct HtbController {
+    cgroup: CgroupName,
+    iface: String,
+    netns: Option<String>,
+    /// Minor of the HTB classes, claimed through the nft table of the same
+    /// number once the policy is applied
+    classid: Option<u16>,
+    overhead: LinkOverhead,
+    ingress: bool,
+    egress: bool,
+}
+
+impl HtbController {
+    pub fn new(cgroup: CgroupName, iface: &str) -> Self {
+        Self {
+            cgroup,
+            iface: iface.to_string(),
+            netns: None,
+            classid: None,
+            overhead: LinkOverhead::default(),
+            ingress: false,
+            egress: false,
+        }
+    }
+
+    pub fn in_netns(mut self, netns: &str) -> Self {
+        self.netns = Some(netns.to_string());
+        self
+    }
+
+    pub fn iface(&self) -> &str {
+        &self.iface
+    }
+
+    fn socket(&self, protocol: isize) -> Result<NetlinkSocket, Error> {
+        Ok(NetlinkSocket::in_netns(protocol, self.netns.as_deref())?)
+    }
+
+    fn mark(classid: u16) -> u32 {
+        MARK_BASE | classid as u32
+    }
+
+    fn table(classid: u16) -> String {
+        format!("rateforge{}", classid)
+    }
+
+    /// Minors of the classes on the htb of `index`
+    fn used_classids(route: &mut NetlinkSocket, index: u32) -> Result<HashSet<u16>, Error> {
+        let answers = route.request(vec![message(tc::get_classes(index), NLM_F_DUMP)])?;
+        Ok(answers
+            .into_iter()
+            .filter_map(|answer| match answer {
+                RtnlMessage::NewTrafficClass(class) => Some(class.header.handle as u16),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Picks the first minor no class on `indexes` uses and claims it by
+    /// creating its nft table, which only one controller can do. A table
+    /// that exists already means another controller got there first.
+    fn claim_classid(&self, route: &mut NetlinkSocket, indexes: &[u32]) -> Result<u16, Error> {
+        let mut used = HashSet::new();
+        for index in indexes {
+            used.extend(Self::used_classids(route, *index)?);
+        }
+
+        let relative = self
+            .cgroup
+            .as_ref()
+            .strip_prefix(CGROUP_ROOT)
+            .unwrap_or(Path::new(self.cgroup.name()));
+        let level = relative.components().count() as u32;
+        let cgroup_id = self.cgroup.id()?;
+
+        let mut netfilter = self.socket(NETLINK_NETFILTER)?;
+        for classid in CLASS_MINORS.filter(|classid| !used.contains(classid)) {
+            let table = Self::table(classid);
+            match nft::add_marking(
+                &mut netfilter,
+                &table,
+                cgroup_id,
+                level,
+                Self::mark(classid),
+            ) {
+                Ok(()) => return Ok(classid),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => Err(err)?,
+            }
+        }
+        Err(Error::General(format!(
+            "Every HTB class on {} is taken",
+            self.iface
+        )))
+    }
+
+    /// Adds an htb root on `index` unless there is one, then the class and
+    /// the filter steering the mark into it
+    fn add_class(
+        &self,
+        route: &mut NetlinkSocket,
+        index: u32,
+        classid: u16,
+        bytes: u64,
+    ) -> Result<(), Error> {
+        create(route, tc::add_root(index))?;
+        route.request(vec![
+            message(
+                tc::add_class(index, classid, bytes, &self.overhead),
+                EXCLUSIVE,
+            ),
+            message(
+                tc::add_mark_filter(index, Self::mark(classid), classid),
+                EXCLUSIVE,
+            ),
+        ])?;
+        Ok(())
+    }
+
+    /// Removes the class and its filter. The htb root goes too once no
+    /// controller has a class on it anymore. Returns whether it did.
+    fn remove_class(
+        &self,
+        route: &mut NetlinkSocket,
+        index: u32,
+        classid: u16,
+    ) -> Result<bool, Error> {
+        ignore_missing(route.request(vec![message(
+            tc::delete_mark_filter(index, Self::mark(classid)),
+            NLM_F_ACK,
+        )]))?;
+        ignore_missing(route.request(vec![message(tc::delete_class(index, classid), NLM_F_ACK)]))?;
+
+        if !Self::used_classids(route, index)?.is_empty() {
+            return Ok(false);
+        }
+        ignore_missing(route.request(vec![message(tc::delete_root(index), NLM_F_ACK)]))?;
+        Ok(true)
+    }
+
+    /// Creates the IFB device and redirects everything arriving on `index`
+    /// to it. Returns the IFB device's index.
+    fn setup_ifb(&self, route: &mut NetlinkSocket, index: u32) -> Result<u32, Error> {
+        route.add_link(IFB_DEVICE, InfoKind::Ifb)?;
+        let ifb = route.link_index(IFB_DEVICE)?;
+        // Another controller redirecting already created the ingress qdisc
+        if create(route, tc::add_ingress(index))? {
+            route.request(vec![message(tc::add_redirect(index, ifb), EXCLUSIVE)])?;
+        }
+        Ok(ifb)
+    }
+
+    /// Undoes whatever `apply_policy` set up. Tries every step even when one
+    /// fails and returns the first error.
+    fn teardown(&mut self) -> Result<(), Error> {
+        let Some(classid) = self.classid else {
+            return Ok(());
+        };
+        let mut route = self.socket(NETLINK_ROUTE)?;
+        let index = route.link_index(&self.iface)?;
+        let mut result = Ok(());
+
+        if self.ingress {
+            let removed = route
+                .link_index(IFB_DEVICE)
+                .map_err(Error::from)
+                .and_then(|ifb| Ok((ifb, self.remove_class(&mut route, ifb, classid)?)));
+            match removed {
+                // The last class is gone, the redirect and the device with it
+                Ok((ifb, true)) => {
+                    let cleared = ignore_missing(
+                        route.request(vec![message(tc::delete_ingress(index), NLM_F_ACK)]),
+                    )
+                    .and_then(|()| ignore_missing(route.delete_link(ifb)));
+                    if let Err(err) = cleared {
+                        warn!("Could not remove {}: {}", IFB_DEVICE, err);
+                        result = result.and(Err(err));
+                    }
+                }
+                Ok((_, false)) => (),
+                Err(err) => {
+                    warn!("Could not remove the download class: {}", err);
+                    result = result.and(Err(err));
+                }
+            }
+            self.ingress = false;
+        }
+        if self.egress {
+            if let Err(err) = self.remove_class(&mut route, index, classid) {
+                warn!("Could not remove the upload class: {}", err);
+                result = result.and(Err(err));
+            }
+            self.egress = false;
+        }
+
+        let table = Self::table(classid);
+        let deleted = self
+            .socket(NETLINK_NETFILTER)
+            .and_then(|mut netfilter| Ok(nft::delete_table(&mut netfilter, &table)?));
+        if let Err(err) = deleted {
+            warn!("Could not remove nft table {}: {}", table, err);
+            result = result.and(Err(err));
+        }
+        self.classid = None;
+        result
+    }
+
+    fn setup(&mut self, policy: &Policy) -> Result<(), Error> {
+        let mut route = self.socket(NETLINK_ROUTE)?;
+        let index = route.link_index(&self.iface)?;
+        let mut indexes = vec![index];
+        if let Ok(ifb) = route.link_index(IFB_DEVICE) {
+            indexes.push(ifb);
+        }
+        let classid = self.claim_classid(&mut route, &indexes)?;
+        self.classid = Some(classid);
+
+        if let Some(rate) = policy.down() {
+            self.ingress = true;
+            let ifb = self.setup_ifb(&mut route, index)?;
+            self.add_class(&mut route, ifb, classid, rate.bytes())?;
+        }
+        if let Some(rate) = policy.up() {
+            self.egress = true;
+            self.add_class(&mut route, index, classid, rate.bytes())?;
+        }
+        info!("HTB class 1:{:x} applied on {}", classid, self.iface);
+        Ok(())
+    }
+}
+
+/// Sends a create request, returns whether it created anything rather than
+/// finding it there already
+fn create(route: &mut NetlinkSocket, request: RtnlMessage) -> Result<bool, Error> {
+    match route.request(vec![message(request, EXCLUSIVE)]) {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Removing something that is gone already is fine. A filter whose qdisc
+/// is gone is reported as an invalid request.
+fn ignore_missing<T>(result: io::Result<T>) -> Result<(), Error> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) => match err.raw_os_error() {
+            Some(libc::ENOENT | libc::ENODEV | libc::EINVAL) => Ok(()),
+            _ => Err(err.into()),
+        },
+    }
+}
+
+impl RateController for HtbController {
+    /// A failure part way removes everything set up before it
+    fn apply_policy(&mut self, policy: Policy) -> Result<(), Error> {
+        if policy.dry_run() {
+            return Err(Error::General(
+                "HTB can't observe traffic without shaping it, dry runs need eBPF".into(),
+            ));
+        }
+
+        if policy.interfaces().iter().any(|iface| iface != &self.iface) {
+            return Err(Error::General(format!(
+                "HTB only shapes {}, it can't be scoped to {:?}",
+                self.iface,
+                policy.interfaces()
+            )));
+        }
+
+        self.overhead = policy.overhead().copied().unwrap_or_default();
+        if let Err(err) = self.setup(&policy) {
+            let err = Error::apply_failed(ApplyStep::Attach, err);
+            return match self.teardown() {
+                Ok(()) => Err(err),
+                Err(rollback) => Err(err.rollback_failed(rollback)),
+            };
+        }
+        Ok(())
+    }
+
+    /// Removes the classes and the marking. The htb roots, the ingress
+    /// redirect and the IFB device go with the last controller using them.
+    fn close(&mut self) -> Result<(), Error> {
+        self.teardown()?;
+        self.cgroup.delete()?;
+        Ok(())
+    }
+
+    fn cgroup(&self) -> Option<&Path> {
+        Some(self.cgroup.as_ref())
+    }
+
+    fn add_task(&mut self, pid: Pid) -> Result<(), Error> {
+        Ok(self.cgroup.add_task(pid.into())?)
+    }
+}
@@ -0,0 +1,248 @@
+//! nf_tables rules that mark the sockets of a cgroup, sent over netfilter
+//! netlink the way `nft` sends them. Only what the HTB fallback needs is
+//! encoded, attribute numbers follow `linux/netfilter/nf_tables.h`.
+
+use std::io;
+
+use netlink_packet_core::{NLM_F_ACK, NetlinkDeserializable, NetlinkHeader, NetlinkSerializable};
+
+use crate::platform::{NetlinkSocket, message};
+
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+const NFPROTO_INET: u8 = 1;
+
+const NLA_F_NESTED: u16 = 0x8000;
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_DATA_VALUE: u16 = 1;
+
+const NF_INET_LOCAL_OUT: u32 = 3;
+const NF_IP_PRI_MANGLE: i32 = -150;
+const NFT_REG_1: u32 = 1;
+const NFT_SOCKET_CGROUPV2: u32 = 3;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_META_MARK: u32 = 3;
+const NFT_CT_MARK: u32 = 3;
+const CHAIN: &str = "output";
+
+/// A netfilter message: `struct nfgenmsg` followed by attributes
+#[derive(Debug, Clone)]
+pub struct NftMessage {
+    message_type: u16,
+    family: u8,
+    /// Big endian in the message
+    res_id: u16,
+    attributes: Vec<u8>,
+}
+
+impl NftMessage {
+    fn new(kind: u16, attributes: Attributes) -> Self {
+        Self {
+            message_type: NFNL_SUBSYS_NFTABLES << 8 | kind,
+            family: NFPROTO_INET,
+            res_id: 0,
+            attributes: attributes.0,
+        }
+    }
+
+    /// Changes to nf_tables only go through inside a batch
+    fn batch(kind: u16) -> Self {
+        Self {
+            message_type: kind,
+            family: 0,
+            res_id: NFNL_SUBSYS_NFTABLES,
+            attributes: Vec::new(),
+        }
+    }
+}
+
+impl NetlinkSerializable for NftMessage {
+    fn message_type(&self) -> u16 {
+        self.message_type
+    }
+
+    fn buffer_len(&self) -> usize {
+        4 + self.attributes.len()
+    }
+
+    fn serialize(&self, buffer: &mut [u8]) {
+        buffer[0] = self.family;
+        buffer[1] = 0;
+        buffer[2..4].copy_from_slice(&self.res_id.to_be_bytes());
+        buffer[4..].copy_from_slice(&self.attributes);
+    }
+}
+
+impl NetlinkDeserializable for NftMessage {
+    type Error = io::Error;
+
+    fn deserialize(header: &NetlinkHeader, payload: &[u8]) -> Result<Self, Self::Error> {
+        if payload.len() < 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Truncated netfilter message",
+            ));
+        }
+        Ok(Self {
+            message_type: header.message_type,
+            family: payload[0],
+            res_id: u16::from_be_bytes([payload[2], payload[3]]),
+            attributes: payload[4..].to_vec(),
+        })
+    }
+}
+
+/// Attributes in netlink's type-length-value layout, each padded to 4 bytes
+#[derive(Debug, Default)]
+struct Attributes(Vec<u8>);
+
+impl Attributes {
+    fn bytes(mut self, kind: u16, value: &[u8]) -> Self {
+        let length = 4 + value.len();
+        self.0.extend_from_slice(&(length as u16).to_ne_bytes());
+        self.0.extend_from_slice(&kind.to_ne_bytes());
+        self.0.extend_from_slice(value);
+        self.0.resize(self.0.len() + (4 - length % 4) % 4, 0);
+        self
+    }
+
+    /// nf_tables expects NUL terminated strings
+    fn string(self, kind: u16, value: &str) -> Self {
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+        self.bytes(kind, &value)
+    }
+
+    /// nf_tables numbers are big endian
+    fn number(self, kind: u16, value: u32) -> Self {
+        self.bytes(kind, &value.to_be_bytes())
+    }
+
+    fn nested(self, kind: u16, nested: Attributes) -> Self {
+        self.bytes(kind | NLA_F_NESTED, &nested.0)
+    }
+
+    /// Register contents are compared in host order
+    fn data(self, kind: u16, value: &[u8]) -> Self {
+        self.nested(kind, Attributes::default().bytes(NFTA_DATA_VALUE, value))
+    }
+
+    fn expression(self, name: &str, data: Attributes) -> Self {
+        self.nested(
+            NFTA_LIST_ELEM,
+            Attributes::default()
+                .string(NFTA_EXPR_NAME, name)
+                .nested(NFTA_EXPR_DATA, data),
+        )
+    }
+}
+
+/// Creates `table` with a rule doing `socket cgroupv2 level <level> <cgroup>
+/// meta mark set <mark> ct mark set meta mark` on output. The table is
+/// created exclusively, so it fails with `AlreadyExists` when somebody else
+/// holds the name.
+pub fn add_marking(
+    socket: &mut NetlinkSocket,
+    table: &str,
+    cgroup_id: u64,
+    level: u32,
+    mark: u32,
+) -> io::Result<()> {
+    let create = libc::NLM_F_CREATE as u16;
+    let exclusive = libc::NLM_F_EXCL as u16;
+
+    let new_table = Attributes::default().string(NFTA_TABLE_NAME, table);
+    let new_chain = Attributes::default()
+        .string(NFTA_CHAIN_TABLE, table)
+        .string(NFTA_CHAIN_NAME, CHAIN)
+        .nested(
+            NFTA_CHAIN_HOOK,
+            Attributes::default()
+                .number(NFTA_HOOK_HOOKNUM, NF_INET_LOCAL_OUT)
+                .number(NFTA_HOOK_PRIORITY, NF_IP_PRI_MANGLE as u32),
+        )
+        .string(NFTA_CHAIN_TYPE, "filter");
+    let expressions = Attributes::default()
+        .expression(
+            "socket",
+            Attributes::default()
+                .number(1, NFT_SOCKET_CGROUPV2)
+                .number(2, NFT_REG_1)
+                .number(3, level),
+        )
+        .expression(
+            "cmp",
+            Attributes::default()
+                .number(1, NFT_REG_1)
+                .number(2, NFT_CMP_EQ)
+                .data(3, &cgroup_id.to_ne_bytes()),
+        )
+        .expression(
+            "immediate",
+            Attributes::default()
+                .number(1, NFT_REG_1)
+                .data(2, &mark.to_ne_bytes()),
+        )
+        .expression(
+            "meta",
+            Attributes::default()
+                .number(2, NFT_META_MARK)
+                .number(3, NFT_REG_1),
+        )
+        .expression(
+            "ct",
+            Attributes::default()
+                .number(2, NFT_CT_MARK)
+                .number(4, NFT_REG_1),
+        );
+    let new_rule = Attributes::default()
+        .string(NFTA_RULE_TABLE, table)
+        .string(NFTA_RULE_CHAIN, CHAIN)
+        .nested(NFTA_RULE_EXPRESSIONS, expressions);
+
+    socket.request(vec![
+        message(NftMessage::batch(NFNL_MSG_BATCH_BEGIN), 0),
+        message(
+            NftMessage::new(NFT_MSG_NEWTABLE, new_table),
+            NLM_F_ACK | create | exclusive,
+        ),
+        message(
+            NftMessage::new(NFT_MSG_NEWCHAIN, new_chain),
+            NLM_F_ACK | create,
+        ),
+        message(
+            NftMessage::new(NFT_MSG_NEWRULE, new_rule),
+            NLM_F_ACK | create,
+        ),
+        message(NftMessage::batch(NFNL_MSG_BATCH_END), 0),
+    ])?;
+    Ok(())
+}
+
+/// Deletes `table` along with its chain and rule
+pub fn delete_table(socket: &mut NetlinkSocket, table: &str) -> io::Result<()> {
+    let table = Attributes::default().string(NFTA_TABLE_NAME, table);
+    socket.request(vec![
+        message(NftMessage::batch(NFNL_MSG_BATCH_BEGIN), 0),
+        message(NftMessage::new(NFT_MSG_DELTABLE, table), NLM_F_ACK),
+        message(NftMessage::batch(NFNL_MSG_BATCH_END), 0),
+    ])?;
+    Ok(())
+}
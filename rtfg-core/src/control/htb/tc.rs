@@ -0,0 +1,234 @@
+//! Traffic control messages for the HTB fallback. netlink-packet-route
+//! knows the generic parts, the htb, fw and connmark options are laid out
+//! here like `linux/pkt_sched.h` and `linux/tc_act/tc_connmark.h` do.
+
+use netlink_packet_route::{
+    RtnlMessage, TcMessage,
+    tc::{
+        self, ActNla, ActOpt, Action, TcOpt,
+        constants::{
+            TC_ACT_PIPE, TC_ACT_STOLEN, TC_H_INGRESS, TC_H_ROOT, TC_U32_TERMINAL, TCA_EGRESS_REDIR,
+        },
+    },
+};
+use netlink_packet_utils::nla::DefaultNla;
+
+use crate::control::LinkOverhead;
+
+/// Major number of the root htb, `1:`
+pub const ROOT_MAJOR: u32 = 1 << 16;
+/// Handle of the ingress qdisc, `ffff:`
+const INGRESS_MAJOR: u32 = 0xffff << 16;
+const HTB: &str = "htb";
+const FW: &str = "fw";
+const CONNMARK: &str = "connmark";
+
+const TCA_HTB_PARMS: u16 = 1;
+const TCA_HTB_INIT: u16 = 2;
+const TCA_HTB_RATE64: u16 = 6;
+const TCA_HTB_CEIL64: u16 = 7;
+const TCA_FW_CLASSID: u16 = 1;
+const TCA_CONNMARK_PARMS: u16 = 1;
+
+const TC_HTB_PROTOVER: u32 = 3;
+/// Quantum is rate / 10, clamped by the kernel
+const RATE2QUANTUM: u32 = 10;
+/// With the link layer set the kernel needs no rate table
+const TC_LINKLAYER_ETHERNET: u8 = 1;
+/// Scheduler ticks are 64ns
+const PSCHED_SHIFT: u32 = 6;
+/// What `tc` assumes for the burst when none is given
+const DEFAULT_MTU: u64 = 1600;
+const ETH_P_ALL: u16 = 0x0003;
+const FILTER_PRIO: u32 = 1;
+
+/// `1:<minor>`
+pub fn class_handle(minor: u16) -> u32 {
+    ROOT_MAJOR | minor as u32
+}
+
+/// `tc qdisc add dev <index> root handle 1: htb`. Traffic without a class
+/// goes straight through.
+pub fn add_root(index: u32) -> RtnlMessage {
+    let mut message = TcMessage::with_index(index as i32);
+    message.header.handle = ROOT_MAJOR;
+    message.header.parent = TC_H_ROOT;
+
+    // struct tc_htb_glob: version, rate2quantum, defcls, debug, direct_pkts
+    let mut glob = Vec::with_capacity(20);
+    for field in [TC_HTB_PROTOVER, RATE2QUANTUM, 0, 0, 0] {
+        glob.extend_from_slice(&field.to_ne_bytes());
+    }
+    message.nlas.push(tc::Nla::Kind(HTB.into()));
+    message
+        .nlas
+        .push(tc::Nla::Options(vec![TcOpt::Other(DefaultNla::new(
+            TCA_HTB_INIT,
+            glob,
+        ))]));
+    RtnlMessage::NewQueueDiscipline(message)
+}
+
+pub fn delete_root(index: u32) -> RtnlMessage {
+    let mut message = TcMessage::with_index(index as i32);
+    message.header.handle = ROOT_MAJOR;
+    message.header.parent = TC_H_ROOT;
+    RtnlMessage::DelQueueDiscipline(message)
+}
+
+/// Classes of the htb on `index`, answered with one `NewTrafficClass` each
+pub fn get_classes(index: u32) -> RtnlMessage {
+    let mut message = TcMessage::with_index(index as i32);
+    message.header.parent = ROOT_MAJOR;
+    RtnlMessage::GetTrafficClass(message)
+}
+
+/// `tc class add dev <index> parent 1: classid 1:<minor> htb rate <bytes>
+/// ceil <bytes> overhead .. mpu ..`
+pub fn add_class(index: u32, minor: u16, bytes: u64, overhead: &LinkOverhead) -> RtnlMessage {
+    let mut message = TcMessage::with_index(index as i32);
+    message.header.handle = class_handle(minor);
+    message.header.parent = ROOT_MAJOR;
+
+    let rate = ratespec(bytes, overhead);
+    let buffer = buffer_ticks(bytes);
+    // struct tc_htb_opt: rate, ceil, buffer, cbuffer, quantum, level, prio
+    let mut opt = Vec::with_capacity(44);
+    opt.extend_from_slice(&rate);
+    opt.extend_from_slice(&rate);
+    for field in [buffer, buffer, 0, 0, 0] {
+        opt.extend_from_slice(&field.to_ne_bytes());
+    }
+
+    let mut options = vec![TcOpt::Other(DefaultNla::new(TCA_HTB_PARMS, opt))];
+    if bytes > u32::MAX as u64 {
+        for kind in [TCA_HTB_RATE64, TCA_HTB_CEIL64] {
+            options.push(TcOpt::Other(DefaultNla::new(
+                kind,
+                bytes.to_ne_bytes().to_vec(),
+            )));
+        }
+    }
+    message.nlas.push(tc::Nla::Kind(HTB.into()));
+    message.nlas.push(tc::Nla::Options(options));
+    RtnlMessage::NewTrafficClass(message)
+}
+
+pub fn delete_class(index: u32, minor: u16) -> RtnlMessage {
+    let mut message = TcMessage::with_index(index as i32);
+    message.header.handle = class_handle(minor);
+    message.header.parent = ROOT_MAJOR;
+    RtnlMessage::DelTrafficClass(message)
+}
+
+/// `tc filter add dev <index> parent 1: protocol all prio 1 handle <mark>
+/// fw flowid 1:<minor>`
+pub fn add_mark_filter(index: u32, mark: u32, minor: u16) -> RtnlMessage {
+    let mut message = filter(index, ROOT_MAJOR, FW);
+    message.header.handle = mark;
+    message
+        .nlas
+        .push(tc::Nla::Options(vec![TcOpt::Other(DefaultNla::new(
+            TCA_FW_CLASSID,
+            class_handle(minor).to_ne_bytes().to_vec(),
+        ))]));
+    RtnlMessage::NewTrafficFilter(message)
+}
+
+pub fn delete_mark_filter(index: u32, mark: u32) -> RtnlMessage {
+    let mut message = filter(index, ROOT_MAJOR, FW);
+    message.header.handle = mark;
+    RtnlMessage::DelTrafficFilter(message)
+}
+
+/// `tc qdisc add dev <index> handle ffff: ingress`
+pub fn add_ingress(index: u32) -> RtnlMessage {
+    let mut message = TcMessage::with_index(index as i32);
+    message.header.handle = INGRESS_MAJOR;
+    message.header.parent = TC_H_INGRESS;
+    message.nlas.push(tc::Nla::Kind("ingress".into()));
+    RtnlMessage::NewQueueDiscipline(message)
+}
+
+/// Removing the ingress qdisc takes the redirect filter with it
+pub fn delete_ingress(index: u32) -> RtnlMessage {
+    let mut message = TcMessage::with_index(index as i32);
+    message.header.handle = INGRESS_MAJOR;
+    message.header.parent = TC_H_INGRESS;
+    RtnlMessage::DelQueueDiscipline(message)
+}
+
+/// `tc filter add dev <index> parent ffff: protocol all prio 1 u32 match
+/// u32 0 0 action connmark action mirred egress redirect dev <ifb>`. The
+/// connection's mark is put back on each packet so the fw filter on the IFB
+/// device can classify it.
+pub fn add_redirect(index: u32, ifb: u32) -> RtnlMessage {
+    let mut message = filter(index, INGRESS_MAJOR, tc::u32::KIND);
+
+    // struct tc_connmark: tc_gen index, capab, action, refcnt, bindcnt and
+    // the conntrack zone, padded to 24 bytes
+    let mut connmark = Vec::with_capacity(24);
+    for field in [0, 0, TC_ACT_PIPE, 0, 0] {
+        connmark.extend_from_slice(&field.to_ne_bytes());
+    }
+    connmark.extend_from_slice(&[0; 4]);
+    let mut restore = Action::default();
+    restore.tab = 1;
+    restore.nlas = vec![
+        ActNla::Kind(CONNMARK.into()),
+        ActNla::Options(vec![ActOpt::Other(DefaultNla::new(
+            TCA_CONNMARK_PARMS,
+            connmark,
+        ))]),
+    ];
+
+    let mut mirred = tc::mirred::TcMirred::default();
+    mirred.action = TC_ACT_STOLEN;
+    mirred.eaction = TCA_EGRESS_REDIR;
+    mirred.ifindex = ifb;
+    let mut redirect = Action::default();
+    redirect.tab = 2;
+    redirect.nlas = vec![
+        ActNla::Kind(tc::mirred::KIND.into()),
+        ActNla::Options(vec![ActOpt::Mirred(tc::mirred::Nla::Parms(mirred))]),
+    ];
+
+    let mut selector = tc::u32::Sel::default();
+    selector.flags = TC_U32_TERMINAL;
+    selector.nkeys = 1;
+    selector.keys = vec![tc::u32::Key::default()];
+    message.nlas.push(tc::Nla::Options(vec![
+        TcOpt::U32(tc::u32::Nla::Sel(selector)),
+        TcOpt::U32(tc::u32::Nla::Act(vec![restore, redirect])),
+    ]));
+    RtnlMessage::NewTrafficFilter(message)
+}
+
+fn filter(index: u32, parent: u32, kind: &str) -> TcMessage {
+    let mut message = TcMessage::with_index(index as i32);
+    message.header.parent = parent;
+    message.header.info = FILTER_PRIO << 16 | ETH_P_ALL.to_be() as u32;
+    message.nlas.push(tc::Nla::Kind(kind.into()));
+    message
+}
+
+/// struct tc_ratespec: cell_log, linklayer, overhead, cell_align, mpu, rate
+fn ratespec(bytes: u64, overhead: &LinkOverhead) -> Vec<u8> {
+    let mut spec = Vec::with_capacity(12);
+    spec.push(0);
+    spec.push(TC_LINKLAYER_ETHERNET);
+    spec.extend_from_slice(&(overhead.overhead as u16).to_ne_bytes());
+    spec.extend_from_slice(&0i16.to_ne_bytes());
+    spec.extend_from_slice(&(overhead.mpu as u16).to_ne_bytes());
+    spec.extend_from_slice(&(bytes.min(u32::MAX as u64) as u32).to_ne_bytes());
+    spec
+}
+
+/// Time to send a burst of one tick's worth of data plus a full packet, in
+/// scheduler ticks, the way `tc` picks the buffer
+fn buffer_ticks(bytes: u64) -> u32 {
+    let bytes = bytes.max(1);
+    let burst = bytes / 1000 + DEFAULT_MTU;
+    let nanos = burst as u128 * 1_000_000_000 / bytes as u128;
+    (nanos >> PSCHED_SHIFT).min(u32::MAX as u128) as u32
+}
@@ -0,0 +1,446 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use log::{info, warn};
+
+use crate::{
+    Error,
+    platform::{default_interface, get_pids_by_name},
+};
+
+use super::{Pid, PinNamespace, Policy, RateController, RuleId, RuleRegistry, apply_transaction};
+
+/// What a rule limits
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// Every process with this name
+    Process(String),
+    /// The whole machine
+    Global,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Process(name) => write!(f, "{}", name),
+            Target::Global => write!(f, "global"),
+        }
+    }
+}
+
+/// Process lifecycle events the manager hands to the rule of the process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcessEvent {
+    Started { pid: Pid, name: String },
+    Exited { pid: Pid },
+}
+
+/// A managed rule as `ControllerManager::list` reports it
+#[derive(Debug, Clone)]
+pub struct RuleSummary {
+    pub id: RuleId,
+    pub target: Target,
+    pub policy: Policy,
+}
+
+struct Managed {
+    target: Target,
+    policy: Policy,
+    controller: Box<dyn RateController>,
+}
+
+struct State {
+    registry: RuleRegistry,
+    rules: BTreeMap<RuleId, Managed>,
+}
+
+/// Owns the controllers of every active rule, keyed by `RuleId`. All
+/// modifications go through one lock, so concurrent callers never apply
+/// and remove the same rule at once.
+pub struct ControllerManager {
+    pins: PinNamespace,
+    iface: Option<String>,
+    state: Mutex<State>,
+}
+
+impl ControllerManager {
+    /// HTB fallbacks go on the default route interface
+    pub fn new(registry: RuleRegistry, pins: PinNamespace) -> Self {
+        Self {
+            pins,
+            iface: default_interface().ok().flatten(),
+            state: Mutex::new(State {
+                registry,
+                rules: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub fn with_iface(mut self, iface: Option<String>) -> Self {
+        self.iface = iface;
+        self
+    }
+
+    /// Starts enforcing `policy` on `target` under a newly allocated id. A
+    /// rule the registry still holds from a run that didn't shut down keeps
+    /// its id, so its pinned links are taken over.
+    pub fn apply(&self, target: Target, policy: Policy) -> Result<RuleId, Error> {
+        let mut state = self.state();
+        let claim = state
+            .registry
+            .claim(&target, policy.algorithm(), std::process::id())?;
+        let id = claim.id;
+
+        let policy = policy.with_id(id);
+        let pids = match &target {
+            Target::Process(name) => get_pids_by_name(name).unwrap_or_else(|| {
+                warn!(
+                    "No process named {} yet, it is limited once it starts",
+                    name
+                );
+                Vec::new()
+            }),
+            Target::Global => Vec::new(),
+        };
+        let controller =
+            match apply_transaction(&target, &policy, &pids, self.iface.as_deref(), &self.pins) {
+                Ok(controller) => controller,
+                Err(err) => {
+                    if let Err(unclaim) = state.registry.unclaim(claim) {
+                        warn!("Could not give rule {} back: {}", id, unclaim);
+                    }
+                    return Err(err);
+                }
+            };
+
+        info!("Rule {} limits {}", id, target);
+        let recorded =
+            state
+                .registry
+                .set_policy(id, &policy)
+                .and_then(|()| match controller.cgroup() {
+                    Some(cgroup) => state.registry.set_cgroup(id, cgroup),
+                    None => Ok(()),
+                });
+        if let Err(err) = recorded {
+            warn!("Could not record rule {}: {}", id, err);
+        }
+        state.rules.insert(
+            id,
+            Managed {
+                target,
+                policy,
+                controller,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Lifts the rule and forgets its id. A rule that fails to close stays
+    /// managed, so it can be removed again.
+    pub fn remove(&self, id: RuleId) -> Result<(), Error> {
+        let mut state = self.state();
+        let managed = state
+            .rules
+            .get_mut(&id)
+            .ok_or(Error::PolicyNotFound { given: id })?;
+        managed.controller.close()?;
+        let target = managed.target.clone();
+        state.rules.remove(&id);
+        state.registry.remove(id)?;
+        info!("Rule {} on {} removed", id, target);
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<RuleSummary> {
+        self.state()
+            .rules
+            .iter()
+            .map(|(id, managed)| RuleSummary {
+                id: *id,
+                target: managed.target.clone(),
+                policy: managed.policy.clone(),
+            })
+            .collect()
+    }
+
+    /// Runs `f` on the controller of rule `id` while holding the lock
+    pub fn with_controller<T>(
+        &self,
+        id: RuleId,
+        f: impl FnOnce(&mut dyn RateController) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut state = self.state();
+        let managed = state
+            .rules
+            .get_mut(&id)
+            .ok_or(Error::PolicyNotFound { given: id })?;
+        f(managed.controller.as_mut())
+    }
+
+    /// Puts a process that just started under the rule for its name.
+    /// Returns the rule it was routed to, if any.
+    pub fn route(&self, event: &ProcessEvent) -> Result<Option<RuleId>, Error> {
+        let ProcessEvent::Started { pid, name } = event else {
+            // The kernel drops exited processes from their cgroup
+            return Ok(None);
+        };
+
+        let mut state = self.state();
+        let target = Target::Process(name.clone());
+        let Some((id, managed)) = state
+            .rules
+            .iter_mut()
+            .find(|(_, managed)| managed.target == target)
+        else {
+            return Ok(None);
+        };
+        managed.controller.add_task(*pid)?;
+        Ok(Some(*id))
+    }
+
+    /// Closes every rule, the newest first and the global rule last, since
+    /// process limits sit below the root cgroup it is attached to. Keeps
+    /// going past failures and returns the first one. Rules that failed to
+    /// close stay registered, so the next run takes their links over.
+    pub fn shutdown_all(&self) -> Result<(), Error> {
+        let mut state = self.state();
+        let mut result = Ok(());
+
+        while let Some((id, mut managed)) = state.rules.pop_last() {
+            if let Err(err) = managed.controller.close() {
+                warn!("Could not close rule {} on {}: {}", id, managed.target, err);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+                continue;
+            }
+            if let Err(err) = state.registry.remove(id) {
+                warn!("Could not unregister rule {}: {}", id, err);
+            }
+        }
+        result
+    }
+
+    /// Leaves every rule to its pinned links for the next rateforge process
+    /// to take over. Rules that can't be left running are closed instead,
+    /// the first of them failing to close is returned.
+    pub fn release_all(&self) -> Result<(), Error> {
+        let mut state = self.state();
+        let mut result = Ok(());
+
+        while let Some((id, mut managed)) = state.rules.pop_last() {
+            let released = managed.controller.release().and_then(|()| {
+                info!("Rule {} on {} left to its links", id, managed.target);
+                state.registry.set_owner(id, None)
+            });
+            let Err(err) = released else {
+                continue;
+            };
+            warn!("Could not release rule {}, lifting it: {}", id, err);
+            let closed = managed
+                .controller
+                .close()
+                .and_then(|()| state.registry.remove(id).map(|_| ()));
+            if let Err(err) = closed {
+                warn!("Could not close rule {} on {}: {}", id, managed.target, err);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Records what the manager asks of it, `close` fails while `stuck`
+    struct Fake {
+        id: RuleId,
+        stuck: bool,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Fake {
+        fn record(&self, call: &str) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", call, self.id));
+        }
+    }
+
+    impl RateController for Fake {
+        fn apply_policy(&mut self, _policy: Policy) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn close(&mut self) -> Result<(), Error> {
+            self.record("close");
+            match self.stuck {
+                true => Err(Error::General("stuck".into())),
+                false => Ok(()),
+            }
+        }
+
+        fn add_task(&mut self, pid: Pid) -> Result<(), Error> {
+            self.record(&format!("add {}", pid.0));
+            Ok(())
+        }
+    }
+
+    struct Setup {
+        manager: ControllerManager,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            Self {
+                manager: ControllerManager::new(RuleRegistry::new(), PinNamespace::default())
+                    .with_iface(None),
+                log: Arc::default(),
+            }
+        }
+
+        /// Manages a fake rule on `target` as `apply` would
+        fn manage(&self, target: Target, stuck: bool) -> RuleId {
+            let mut state = self.manager.state();
+            let id = state.registry.claim(&target, "fake", 1).unwrap().id;
+            let controller = Fake {
+                id,
+                stuck,
+                log: self.log.clone(),
+            };
+            state.rules.insert(
+                id,
+                Managed {
+                    target,
+                    policy: Policy::default().with_id(id),
+                    controller: Box::new(controller),
+                },
+            );
+            id
+        }
+
+        fn registered(&self, id: RuleId) -> bool {
+            self.manager.state().registry.get(id).is_ok()
+        }
+
+        fn log(&self) -> Vec<String> {
+            self.log.lock().unwrap().clone()
+        }
+    }
+
+    fn process(name: &str) -> Target {
+        Target::Process(name.into())
+    }
+
+    #[test]
+    fn remove_closes_and_forgets() {
+        let setup = Setup::new();
+        let id = setup.manage(process("curl"), false);
+
+        setup.manager.remove(id).unwrap();
+        assert_eq!(setup.log(), [format!("close {}", id)]);
+        assert!(setup.manager.list().is_empty());
+        assert!(!setup.registered(id));
+    }
+
+    #[test]
+    fn remove_keeps_a_rule_that_fails_to_close() {
+        let setup = Setup::new();
+        let id = setup.manage(process("curl"), true);
+
+        assert!(setup.manager.remove(id).is_err());
+        assert_eq!(setup.manager.list().len(), 1);
+        assert!(setup.registered(id));
+    }
+
+    #[test]
+    fn remove_unknown_rule() {
+        let setup = Setup::new();
+        assert!(matches!(
+            setup.manager.remove(RuleId(7)),
+            Err(Error::PolicyNotFound { given: RuleId(7) })
+        ));
+    }
+
+    #[test]
+    fn route_started_process_to_its_rule() {
+        let setup = Setup::new();
+        let id = setup.manage(process("curl"), false);
+        setup.manage(process("wget"), false);
+
+        let started = ProcessEvent::Started {
+            pid: Pid(42),
+            name: "curl".into(),
+        };
+        assert_eq!(setup.manager.route(&started).unwrap(), Some(id));
+        assert_eq!(setup.log(), [format!("add 42 {}", id)]);
+
+        let unlimited = ProcessEvent::Started {
+            pid: Pid(43),
+            name: "ssh".into(),
+        };
+        assert_eq!(setup.manager.route(&unlimited).unwrap(), None);
+        let exited = ProcessEvent::Exited { pid: Pid(42) };
+        assert_eq!(setup.manager.route(&exited).unwrap(), None);
+        assert_eq!(setup.log().len(), 1);
+    }
+
+    #[test]
+    fn shutdown_closes_the_global_rule_last() {
+        let setup = Setup::new();
+        let global = setup.manage(Target::Global, false);
+        let first = setup.manage(process("curl"), false);
+        let second = setup.manage(process("wget"), false);
+
+        setup.manager.shutdown_all().unwrap();
+        assert_eq!(
+            setup.log(),
+            [
+                format!("close {}", second),
+                format!("close {}", first),
+                format!("close {}", global),
+            ]
+        );
+        assert!(setup.manager.list().is_empty());
+        assert!(!setup.registered(first));
+        assert!(!setup.registered(global));
+    }
+
+    #[test]
+    fn shutdown_keeps_going_past_failures() {
+        let setup = Setup::new();
+        let stuck = setup.manage(process("curl"), true);
+        let closed = setup.manage(process("wget"), false);
+
+        assert!(setup.manager.shutdown_all().is_err());
+        assert_eq!(setup.log().len(), 2);
+        assert!(setup.registered(stuck));
+        assert!(!setup.registered(closed));
+    }
+
+    #[test]
+    fn release_lifts_what_it_cant_leave_running() {
+        let setup = Setup::new();
+        let id = setup.manage(process("curl"), false);
+
+        setup.manager.release_all().unwrap();
+        assert_eq!(setup.log(), [format!("close {}", id)]);
+        assert!(!setup.registered(id));
+    }
+}
@@ -1,7 +1,19 @@
+mod global;
+mod htb;
+mod manager;
+mod pinned;
 mod policy;
 mod process;
 mod rate_limiter;
+mod registry;
+mod transaction;
 
+pub use global::GlobalController;
+pub use htb::HtbController;
+pub use manager::*;
+pub use pinned::*;
 pub use policy::*;
 pub use process::Pid;
 pub use rate_limiter::*;
+pub use registry::*;
+pub use transaction::*;
@@ -0,0 +1,161 @@
+use std::{fmt, fs, io, path::PathBuf};
+
+use ebpf::{
+    attach::detach,
+    ebpf::CGROUP_ROOT,
+    stats::{STATS_PNAME, read_pinned_stats},
+};
+use log::{info, warn};
+
+use crate::Error;
+
+use super::{GLOBAL_RULE, Pid, PinNamespace, ProgramStats, RuleEntry};
+
+/// Signal `rateforge remove` asks a foreground limit to lift itself with.
+/// SIGTERM, which service managers stop processes with, leaves the limit to
+/// its pinned links instead.
+pub const LIFT_SIGNAL: libc::c_int = libc::SIGUSR1;
+
+/// How a registered rule is enforced right now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleState {
+    /// A rateforge process holds the rule in the foreground
+    Foreground(u32),
+    /// Only the pinned links keep the limit in place
+    Detached,
+    /// Nothing is attached anymore, the process applying it died early
+    Inactive,
+}
+
+impl fmt::Display for RuleState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleState::Foreground(pid) => write!(f, "foreground (pid {})", pid),
+            RuleState::Detached => write!(f, "detached"),
+            RuleState::Inactive => write!(f, "inactive"),
+        }
+    }
+}
+
+/// A registered rule as its pins and cgroup show it. Lets a rule be
+/// inspected and lifted without the process that applied it.
+#[derive(Debug, Clone)]
+pub struct PinnedRule {
+    pub entry: RuleEntry,
+    pins: PinNamespace,
+}
+
+impl PinnedRule {
+    pub fn new(entry: RuleEntry, pins: &PinNamespace) -> Self {
+        Self {
+            entry,
+            pins: pins.clone(),
+        }
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.entry.id == GLOBAL_RULE
+    }
+
+    pub fn pin_dir(&self) -> PathBuf {
+        self.entry.pin_dir(&self.pins)
+    }
+
+    /// Names of the cgroup links pinned for the rule
+    pub fn links(&self) -> Vec<String> {
+        let Ok(entries) = fs::read_dir(self.pin_dir()) else {
+            return Vec::new();
+        };
+        let mut links: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("link_"))
+            .collect();
+        links.sort();
+        links
+    }
+
+    pub fn state(&self) -> RuleState {
+        if let Some(owner) = self.entry.live_owner() {
+            return RuleState::Foreground(owner);
+        }
+        match self.links().is_empty() {
+            true => RuleState::Inactive,
+            false => RuleState::Detached,
+        }
+    }
+
+    /// The cgroup the rule's programs are attached to
+    pub fn cgroup(&self) -> PathBuf {
+        self.entry.cgroup_path()
+    }
+
+    /// Processes in the rule's cgroup, none for the global rule
+    pub fn processes(&self) -> Vec<Pid> {
+        self.entry.processes()
+    }
+
+    /// Counters the rule's program pinned next to its links
+    pub fn stats(&self) -> Result<ProgramStats, Error> {
+        let location = self.pins.location(self.entry.program_id(), STATS_PNAME);
+        Ok(read_pinned_stats(&location)?)
+    }
+
+    /// Asks the foreground process holding the rule to lift it and exit
+    pub fn stop_owner(&self) -> Result<(), Error> {
+        let Some(owner) = self.entry.live_owner() else {
+            return Ok(());
+        };
+        if unsafe { libc::kill(owner as libc::pid_t, LIFT_SIGNAL) } != 0 {
+            Err(io::Error::last_os_error())?
+        }
+        Ok(())
+    }
+
+    /// Detaches a rule nothing holds anymore. Unpins its links and
+    /// counters, moves its processes back to the root cgroup and removes
+    /// its cgroup. The registry entry is left to the caller.
+    pub fn lift(&self) -> Result<(), Error> {
+        if let RuleState::Foreground(owner) = self.state() {
+            Err(Error::General(format!(
+                "Rule {} is held by rateforge process {}",
+                self.entry.id, owner
+            )))?
+        }
+
+        let id = self.entry.program_id();
+        for link in self.links() {
+            detach(&self.pins.location(id, &link))?;
+        }
+        if let Ok(entries) = fs::read_dir(self.pin_dir()) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        self.pins.remove(id)?;
+
+        if !self.is_global() {
+            self.release_cgroup()?;
+        }
+        info!("Lifted rule {} on {}", self.entry.id, self.entry.target);
+        Ok(())
+    }
+
+    fn release_cgroup(&self) -> Result<(), Error> {
+        let cgroup = self.cgroup();
+        if !cgroup.exists() {
+            return Ok(());
+        }
+        let root = PathBuf::from(CGROUP_ROOT).join("cgroup.procs");
+        for pid in self.processes() {
+            match fs::write(&root, pid.0.to_string()) {
+                Ok(()) => (),
+                // Gone already
+                Err(err) if err.raw_os_error() == Some(libc::ESRCH) => (),
+                Err(err) => warn!("Could not move {} out of {:?}: {}", pid.0, cgroup, err),
+            }
+        }
+        fs::remove_dir(&cgroup)?;
+        Ok(())
+    }
+}
@@ -1,15 +1,25 @@
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
-use crate::util::generate_rid;
+use super::{LinkOverhead, TOKEN_BUCKET, TR_TCM};
 
-#[derive(Debug, Serialize, Deserialize, Default, Eq, PartialEq, Hash, Clone, Copy)]
+/// Handed out by the `RuleRegistry`
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy)]
 pub struct RuleId(pub u64);
 
+/// Id of a policy the `RuleRegistry` hasn't registered yet. It never names
+/// a rule, unlike `GLOBAL_RULE` which a zero id would be.
+pub const UNREGISTERED_RULE: RuleId = RuleId(u64::MAX);
+
+impl Default for RuleId {
+    fn default() -> Self {
+        UNREGISTERED_RULE
+    }
+}
+
 impl std::fmt::Display for RuleId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let val = format!("{:?}", self);
-        write!(f, "{}", val)
+        write!(f, "{}", self.0)
     }
 }
 impl From<u64> for RuleId {
@@ -22,41 +32,146 @@ impl From<&RuleId> for u64 {
         value.0
     }
 }
-#[derive(Debug, Hash, PartialEq, Eq, Default)]
+#[derive(Debug, Hash, PartialEq, Eq, Default, Clone, Serialize, Deserialize)]
 pub struct Policy {
     down: Option<Rate>,
     up: Option<Rate>,
+    committed_burst: Option<Burst>,
+    peak_down: Option<Rate>,
+    peak_up: Option<Rate>,
+    peak_burst: Option<Burst>,
+    algorithm: Option<String>,
+    dry_run: bool,
+    overhead: Option<LinkOverhead>,
+    interfaces: Vec<String>,
     id: RuleId,
 }
 
 impl Policy {
     pub fn new(down: Option<Rate>, up: Option<Rate>) -> Self {
-        let id = generate_rid(down.as_ref(), up.as_ref());
-        Policy { id, down, up }
+        Policy {
+            down,
+            up,
+            ..Default::default()
+        }
     }
 
+    /// The same policy under the id the `RuleRegistry` allocated for it,
+    /// `UNREGISTERED_RULE` until then
+    pub fn with_id(mut self, id: RuleId) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Committed download rate
     pub fn down(&self) -> Option<&Rate> {
         self.down.as_ref()
     }
 
+    /// Committed upload rate
     pub fn up(&self) -> Option<&Rate> {
         self.up.as_ref()
     }
 
+    pub fn committed_burst(&self) -> Option<&Burst> {
+        self.committed_burst.as_ref()
+    }
+
+    pub fn peak_down(&self) -> Option<&Rate> {
+        self.peak_down.as_ref()
+    }
+
+    pub fn peak_up(&self) -> Option<&Rate> {
+        self.peak_up.as_ref()
+    }
+
+    pub fn peak_burst(&self) -> Option<&Burst> {
+        self.peak_burst.as_ref()
+    }
+
+    /// A policy with a peak rate needs the two rate three color limiter
+    pub fn is_two_rate(&self) -> bool {
+        self.peak_down.is_some() || self.peak_up.is_some()
+    }
+
+    /// Name of the limit algorithm in the program registry. Defaults to the
+    /// token bucket, or trTCM when a peak rate is set.
+    pub fn algorithm(&self) -> &str {
+        match &self.algorithm {
+            Some(name) => name,
+            None if self.is_two_rate() => TR_TCM,
+            None => TOKEN_BUCKET,
+        }
+    }
+
+    /// Count the traffic the limit would drop without dropping it
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Framing charged on top of each packet's length
+    pub fn overhead(&self) -> Option<&LinkOverhead> {
+        self.overhead.as_ref()
+    }
+
+    /// Interfaces the limit is scoped to, all of them when empty
+    pub fn interfaces(&self) -> &[String] {
+        &self.interfaces
+    }
+
     pub fn id(&self) -> &RuleId {
         &self.id
     }
 }
 
-#[derive(Default, Debug, Hash, PartialEq, Eq, Clone, Copy)]
+#[derive(Default, Debug, Hash, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub struct Rate(pub u64);
 
+/// Burst size in kilobytes
+#[derive(Default, Debug, Hash, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct Burst(pub u64);
+
+impl Burst {
+    pub fn kbs(&self) -> u64 {
+        self.0
+    }
+    /// Saturates, parsing rejects sizes that don't fit
+    pub fn bytes(&self) -> u64 {
+        self.0.saturating_mul(1024)
+    }
+}
+
+impl From<u64> for Burst {
+    fn from(value: u64) -> Self {
+        Burst(value)
+    }
+}
+
+impl FromStr for Burst {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        kilobytes(s)
+            .map(Burst)
+            .map_err(|e| format!("Invalid burst: {}", e))
+    }
+}
+
 #[derive(Default)]
 pub struct PolicyBuilder {
     pub down: u64,
     pub up: u64,
+    pub committed_burst: u64,
+    pub peak_down: u64,
+    pub peak_up: u64,
+    pub peak_burst: u64,
     pub rid: Option<RuleId>,
     pub name: Option<String>,
+    pub algorithm: Option<String>,
+    pub dry_run: bool,
+    pub overhead: u32,
+    pub mpu: u32,
+    pub interfaces: Vec<String>,
 }
 
 impl PolicyBuilder {
@@ -77,6 +192,50 @@ impl PolicyBuilder {
         self
     }
 
+    pub fn committed_burst(mut self, burst: u64) -> PolicyBuilder {
+        self.committed_burst = burst;
+        self
+    }
+    pub fn peak_down(mut self, rate: u64) -> PolicyBuilder {
+        self.peak_down = rate;
+        self
+    }
+    pub fn peak_up(mut self, rate: u64) -> PolicyBuilder {
+        self.peak_up = rate;
+        self
+    }
+    pub fn peak_burst(mut self, burst: u64) -> PolicyBuilder {
+        self.peak_burst = burst;
+        self
+    }
+
+    pub fn algorithm(mut self, algorithm: String) -> PolicyBuilder {
+        self.algorithm = Some(algorithm);
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> PolicyBuilder {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Bytes of framing added to every packet
+    pub fn overhead(mut self, bytes: u32) -> PolicyBuilder {
+        self.overhead = bytes;
+        self
+    }
+    /// Minimum bytes every packet is charged
+    pub fn mpu(mut self, bytes: u32) -> PolicyBuilder {
+        self.mpu = bytes;
+        self
+    }
+
+    /// Scopes the limit to `iface`, can be given more than once
+    pub fn interface(mut self, iface: String) -> PolicyBuilder {
+        self.interfaces.push(iface);
+        self
+    }
+
     pub fn name(mut self, name: String) -> PolicyBuilder {
         self.name = Some(name);
         self
@@ -84,9 +243,22 @@ impl PolicyBuilder {
 
     pub fn build(self) -> Policy {
         let down = (self.down != 0).then_some(Rate(self.down));
-        let up = (self.down != 0).then_some(Rate(self.down));
-        let id = self.rid.unwrap_or(generate_rid(down.as_ref(), up.as_ref()));
-        Policy { down, up, id }
+        let up = (self.up != 0).then_some(Rate(self.up));
+        let id = self.rid.unwrap_or_default();
+        Policy {
+            down,
+            up,
+            committed_burst: (self.committed_burst != 0).then_some(Burst(self.committed_burst)),
+            peak_down: (self.peak_down != 0).then_some(Rate(self.peak_down)),
+            peak_up: (self.peak_up != 0).then_some(Rate(self.peak_up)),
+            peak_burst: (self.peak_burst != 0).then_some(Burst(self.peak_burst)),
+            algorithm: self.algorithm,
+            dry_run: self.dry_run,
+            overhead: (self.overhead != 0 || self.mpu != 0)
+                .then_some(LinkOverhead::new(self.overhead, self.mpu)),
+            interfaces: self.interfaces,
+            id,
+        }
     }
 }
 
@@ -95,16 +267,17 @@ impl Rate {
         self.0 * 8
     }
     pub fn mbits(&self) -> u64 {
-        self.0 / 8000
+        self.0 / 128
     }
     pub fn kbs(&self) -> u64 {
         self.0
     }
     pub fn mbs(&self) -> u64 {
-        self.0 / 1000
+        self.0 / 1024
     }
+    /// Saturates, parsing rejects sizes that don't fit
     pub fn bytes(&self) -> u64 {
-        self.0 * 1024
+        self.0.saturating_mul(1024)
     }
 }
 
@@ -118,8 +291,86 @@ impl FromStr for Rate {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        s.parse::<u64>()
+        kilobytes(s.trim_end_matches("/s"))
             .map(Rate)
             .map_err(|e| format!("Invalid rate: {}", e))
     }
 }
+
+/// Kilobytes in `s`, a number with an optional `KB`, `MB` or `GB` unit like
+/// `1.5MB`. A plain number is in kilobytes. Units are powers of 1024 like the
+/// bytes the kilobytes are turned into.
+fn kilobytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let scale = match unit.to_ascii_lowercase().as_str() {
+        "" | "k" | "kb" => 1.0,
+        "m" | "mb" => 1024.0,
+        "g" | "gb" => 1024.0 * 1024.0,
+        other => return Err(format!("unknown unit {:?}", other)),
+    };
+    let number: f64 = number.parse().map_err(|e| format!("{}", e))?;
+    let kilobytes = (number * scale).round();
+    // One past the limit is a power of two, which an f64 holds exactly. Also
+    // catches numbers too long for an f64, they parse as infinity.
+    if kilobytes >= (u64::MAX / 1024 + 1) as f64 {
+        return Err(format!("{} is too large", s));
+    }
+    Ok(kilobytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_numbers_are_kilobytes() {
+        assert_eq!("100".parse::<Rate>().unwrap(), Rate(100));
+        assert_eq!(" 64 ".parse::<Burst>().unwrap(), Burst(64));
+    }
+
+    #[test]
+    fn units_scale_in_powers_of_1024() {
+        assert_eq!(kilobytes("2KB"), Ok(2));
+        assert_eq!(kilobytes("2k"), Ok(2));
+        assert_eq!(kilobytes("1.5MB"), Ok(1536));
+        assert_eq!(kilobytes("1.5m"), Ok(1536));
+        assert_eq!(kilobytes("2Gb"), Ok(2 * 1024 * 1024));
+        assert_eq!(kilobytes("0.0004MB"), Ok(0));
+        assert_eq!(kilobytes("0.0006MB"), Ok(1));
+        assert_eq!("1MB".parse::<Burst>().unwrap().bytes(), 1024 * 1024);
+    }
+
+    #[test]
+    fn sizes_that_overflow_bytes_are_rejected() {
+        assert_eq!(kilobytes("17179869183GB"), Ok(17179869183 << 20));
+        assert!(kilobytes("17179869184GB").is_err());
+        assert!(kilobytes("20000000000000GB").is_err());
+        assert!(kilobytes("1e15GB").is_err());
+        assert!(kilobytes(&"9".repeat(400)).is_err());
+        assert_eq!(Rate(u64::MAX).bytes(), u64::MAX);
+    }
+
+    #[test]
+    fn rates_take_a_per_second_suffix() {
+        assert_eq!("1.5MB/s".parse::<Rate>().unwrap(), Rate(1536));
+        assert_eq!("300/s".parse::<Rate>().unwrap(), Rate(300));
+        assert!("64KB/s".parse::<Burst>().is_err());
+    }
+
+    #[test]
+    fn unknown_units_and_bad_numbers_are_rejected() {
+        assert_eq!(kilobytes("5TB"), Err("unknown unit \"tb\"".into()));
+        assert_eq!(
+            "5 bits".parse::<Rate>(),
+            Err("Invalid rate: unknown unit \" bits\"".into())
+        );
+        assert!(kilobytes("1.2.3MB").is_err());
+        assert!(kilobytes("MB").is_err());
+        assert!(kilobytes("").is_err());
+        assert!(kilobytes("-5").is_err());
+    }
+}
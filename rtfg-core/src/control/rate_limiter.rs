@@ -1,58 +1,369 @@
+pub use ebpf::config::{LinkOverhead, LogLevel};
 pub use ebpf::ebpf::CgroupName;
+pub use ebpf::events::{Direction, DropEventStream, DropRecord};
+pub use ebpf::factory::{BLOCK, EDT, GCRA, LEAKY_BUCKET, TOKEN_BUCKET, TR_TCM};
+pub use ebpf::pins::{DEFAULT_BPFFS, PinNamespace};
+pub use ebpf::stats::{BucketStats, ProgramStats};
 use ebpf::{
     ebpf::AttachmentKind,
-    factory::LimitProgramFactory,
-    tokenb::{TokenBucketProgram, TokenLimit},
+    factory::{EnforcementMode, LimitAlgorithm, LimitProgramFactory, LimitSpec, ProgramConfig},
 };
 
-use crate::Error;
+use std::path::Path;
 
-use super::Policy;
+use log::warn;
 
-pub trait RateController {
+use crate::{Error, platform::interface_index};
+
+use super::{ApplyStep, HtbController, Pid, Policy, Rate};
+
+pub trait RateController: Send {
     fn apply_policy(&mut self, policy: Policy) -> Result<(), Error>;
 
     fn close(&mut self) -> Result<(), Error>;
+
+    /// Leaves the limit in place for the next rateforge process to take
+    /// over, instead of lifting it like `close`
+    fn release(&mut self) -> Result<(), Error> {
+        Err(Error::General(
+            "Controller can't leave its limit running without rateforge".into(),
+        ))
+    }
+
+    /// The cgroup the limit applies to
+    fn cgroup(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Puts a process that started after the policy was applied under the
+    /// limit
+    fn add_task(&mut self, pid: Pid) -> Result<(), Error> {
+        Err(Error::General(format!(
+            "Controller can't take over process {}",
+            pid.0
+        )))
+    }
+
+    /// Sampled stream of packets dropped by the limit
+    fn drop_events(&mut self) -> Result<DropEventStream, Error> {
+        Err(Error::General(
+            "Controller does not report drop events".into(),
+        ))
+    }
+
+    /// Packet and byte counters of the limit, including what a dry run
+    /// would have dropped
+    fn stats(&mut self) -> Result<ProgramStats, Error> {
+        Err(Error::General("Controller does not report stats".into()))
+    }
+
+    /// Resolves the policy's interface names again, after one of them was
+    /// renamed or plugged in
+    fn refresh_interfaces(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Kernel side log verbosity of this controller's programs only
+    fn set_log_level(&mut self, level: LogLevel) -> Result<(), Error> {
+        Err(Error::General(format!(
+            "Controller has no kernel logging to set to {:?}",
+            level
+        )))
+    }
+
+    /// Moves the current policy over to another algorithm without a moment
+    /// where the cgroup is unlimited
+    fn switch_algorithm(&mut self, algorithm: &str) -> Result<(), Error> {
+        Err(Error::General(format!(
+            "Controller can't switch to {}",
+            algorithm
+        )))
+    }
 }
 
+/// Runs any algorithm registered in a `LimitProgramFactory`
 #[derive(Debug)]
-pub struct TokenBucketController {
-    program: TokenBucketProgram,
+pub struct EbpfController {
+    program: Box<dyn LimitAlgorithm>,
+    interfaces: Vec<String>,
+    factory: LimitProgramFactory,
+    config: ProgramConfig,
+    policy: Option<Policy>,
 }
 
-impl TokenBucketController {
-    pub fn new(cgroup: CgroupName) -> Result<Self, ebpf::Error> {
-        let program = LimitProgramFactory::token_bucket(0.into(), cgroup)?;
-        Ok(Self { program })
+impl EbpfController {
+    pub fn new(
+        cgroup: CgroupName,
+        algorithm: &str,
+        iface: Option<&str>,
+    ) -> Result<Self, ebpf::Error> {
+        Self::with_factory(&LimitProgramFactory::default(), cgroup, algorithm, iface)
     }
-}
 
-impl RateController for TokenBucketController {
-    fn apply_policy(&mut self, policy: Policy) -> Result<(), Error> {
-        let burst = 10_000; //ns
+    pub fn with_factory(
+        factory: &LimitProgramFactory,
+        cgroup: CgroupName,
+        algorithm: &str,
+        iface: Option<&str>,
+    ) -> Result<Self, ebpf::Error> {
+        let mut config = ProgramConfig::new(0.into(), cgroup);
+        if let Some(iface) = iface {
+            config = config.iface(iface.to_string());
+        }
+        Self::with_config(factory, algorithm, config)
+    }
+
+    pub fn with_config(
+        factory: &LimitProgramFactory,
+        algorithm: &str,
+        config: ProgramConfig,
+    ) -> Result<Self, ebpf::Error> {
+        let program = factory.create(algorithm, config.clone())?;
+        Ok(Self {
+            program,
+            interfaces: Vec::new(),
+            factory: factory.clone(),
+            config,
+            policy: None,
+        })
+    }
+
+    pub fn algorithm(&self) -> &'static str {
+        self.program.name()
+    }
+
+    fn spec(policy: &Policy, rate: &Rate, peak: Option<&Rate>) -> LimitSpec {
+        LimitSpec {
+            id: policy.id().into(),
+            rate: rate.bytes(),
+            burst: policy.committed_burst().map(|b| b.bytes()).unwrap_or(0),
+            peak_rate: peak.map(|r| r.bytes()).unwrap_or(0),
+            peak_burst: policy.peak_burst().map(|b| b.bytes()).unwrap_or(0),
+            mode: match policy.dry_run() {
+                true => EnforcementMode::Observe,
+                false => EnforcementMode::Enforce,
+            },
+        }
+    }
 
+    /// Everything `policy` sets on a program before it is loaded
+    fn configure(
+        program: &mut dyn LimitAlgorithm,
+        policy: &Policy,
+        ifindexes: Option<&[u32]>,
+    ) -> Result<(), Error> {
         if let Some(rate) = policy.down() {
-            self.program
-                .apply_rate(AttachmentKind::Ingress(TokenLimit::new(
-                    policy.id().into(),
-                    rate.bytes(),
-                    burst,
-                )))?;
+            let spec = Self::spec(policy, rate, policy.peak_down());
+            program.apply(AttachmentKind::Ingress(spec))?;
         }
         if let Some(rate) = policy.up() {
-            self.program
-                .apply_rate(AttachmentKind::Egress(TokenLimit::new(
-                    policy.id().into(),
-                    rate.bytes(),
-                    burst,
-                )))?;
+            let spec = Self::spec(policy, rate, policy.peak_up());
+            program.apply(AttachmentKind::Egress(spec))?;
+        }
+        if let Some(overhead) = policy.overhead() {
+            program.set_overhead(*overhead)?;
+        }
+        if let Some(ifindexes) = ifindexes {
+            program.set_interfaces(Some(ifindexes))?;
+        }
+        Ok(())
+    }
+
+    /// Indexes of the policy's interfaces, `None` when it isn't scoped
+    fn ifindexes(&self) -> Option<Vec<u32>> {
+        if self.interfaces.is_empty() {
+            return None;
         }
 
-        self.program.load()?;
+        // Interfaces that are missing right now, like an unplugged tether,
+        // are skipped until they show up
+        let ifindexes = self
+            .interfaces
+            .iter()
+            .filter_map(|name| match interface_index(name) {
+                Ok(index) => Some(index),
+                Err(err) => {
+                    warn!("Interface {} is not available: {}", name, err);
+                    None
+                }
+            })
+            .collect();
+        Some(ifindexes)
+    }
+}
+
+impl RateController for EbpfController {
+    /// The limits only live in the maps of the program until it is
+    /// attached, so a failed write leaves nothing behind. A failed attach is
+    /// taken back by the program itself: directions it attached are
+    /// detached and links it took over run their previous program again.
+    fn apply_policy(&mut self, policy: Policy) -> Result<(), Error> {
+        self.interfaces = policy.interfaces().to_vec();
+        let ifindexes = self.ifindexes();
+        Self::configure(self.program.as_mut(), &policy, ifindexes.as_deref())
+            .map_err(|err| Error::apply_failed(ApplyStep::WriteMaps, err))?;
+
+        match self.program.load() {
+            Ok(()) => (),
+            Err(ebpf::Error::RollbackFailed { source, rollback }) => {
+                Err(Error::apply_failed(ApplyStep::Attach, Error::from(*source))
+                    .rollback_failed(Error::from(*rollback)))?
+            }
+            Err(err) => Err(Error::apply_failed(ApplyStep::Attach, err.into()))?,
+        }
+        self.policy = Some(policy);
         Ok(())
     }
     fn close(&mut self) -> Result<(), Error> {
         self.program.close()?;
         Ok(())
     }
+
+    /// The links stay pinned and keep running the program, whose bucket
+    /// levels the next program loaded for the policy starts from
+    fn release(&mut self) -> Result<(), Error> {
+        Ok(self.program.release()?)
+    }
+
+    fn cgroup(&self) -> Option<&Path> {
+        Some(self.program.cgroup().as_ref())
+    }
+
+    fn add_task(&mut self, pid: Pid) -> Result<(), Error> {
+        Ok(self.program.cgroup_mut().add_task(pid.into())?)
+    }
+
+    fn drop_events(&mut self) -> Result<DropEventStream, Error> {
+        Ok(self.program.drop_events()?)
+    }
+
+    fn stats(&mut self) -> Result<ProgramStats, Error> {
+        Ok(self.program.stats()?)
+    }
+
+    fn refresh_interfaces(&mut self) -> Result<(), Error> {
+        if let Some(ifindexes) = self.ifindexes() {
+            self.program.set_interfaces(Some(&ifindexes))?;
+        }
+        Ok(())
+    }
+
+    fn set_log_level(&mut self, level: LogLevel) -> Result<(), Error> {
+        Ok(self.program.set_log_level(level)?)
+    }
+
+    /// The new program takes over the pinned cgroup links in one
+    /// `BPF_LINK_UPDATE` and starts from the old program's bucket levels
+    fn switch_algorithm(&mut self, algorithm: &str) -> Result<(), Error> {
+        let Some(policy) = &self.policy else {
+            return Err(Error::General(format!(
+                "No policy applied yet to carry over to {}",
+                algorithm
+            )));
+        };
+        if algorithm == EDT || self.algorithm() == EDT {
+            Err(Error::General(
+                "EDT attaches to an interface and can't take over cgroup links".into(),
+            ))?
+        }
+
+        let mut program = self.factory.create(algorithm, self.config.clone())?;
+        let ifindexes = self.ifindexes();
+        Self::configure(program.as_mut(), policy, ifindexes.as_deref())?;
+        match self.program.levels() {
+            Ok(levels) => program.seed(levels)?,
+            Err(err) => warn!("{} starts with full buckets: {}", algorithm, err),
+        }
+        program.load()?;
+
+        // The links run the new program now, dropping the old one releases
+        // it without detaching anything
+        self.program = program;
+        Ok(())
+    }
+}
+
+/// Picks the eBPF controller for the policy's algorithm, pinned under the
+/// policy's id in `pins`. Falls back to HTB when the kernel refuses to set up
+/// the eBPF program, see `htb_fallback`.
+pub fn controller_for(
+    cgroup: CgroupName,
+    policy: &Policy,
+    iface: Option<&str>,
+    pins: &PinNamespace,
+) -> Result<Box<dyn RateController>, Error> {
+    let mut config =
+        ProgramConfig::new(u64::from(policy.id()).into(), cgroup.clone()).pins(pins.clone());
+    if let Some(iface) = iface {
+        config = config.iface(iface.to_string());
+    }
+    let factory = LimitProgramFactory::default();
+    match EbpfController::with_config(&factory, policy.algorithm(), config) {
+        Ok(controller) => Ok(Box::new(controller)),
+        Err(err) => match htb_fallback(&cgroup, policy, iface) {
+            Some(htb) => {
+                warn!(
+                    "eBPF unavailable ({}), falling back to HTB on {}",
+                    err,
+                    htb.iface()
+                );
+                Ok(Box::new(htb))
+            }
+            None => Err(err.into()),
+        },
+    }
+}
+
+/// Creates the controller for `policy` and applies it. When the kernel
+/// refuses to load or attach the eBPF program, the policy goes to HTB
+/// instead, just like when the program can't be created at all.
+pub fn apply_controller(
+    cgroup: CgroupName,
+    policy: &Policy,
+    iface: Option<&str>,
+    pins: &PinNamespace,
+) -> Result<Box<dyn RateController>, Error> {
+    let mut controller = controller_for(cgroup.clone(), policy, iface, pins)
+        .map_err(|err| Error::apply_failed(ApplyStep::CreateProgram, err))?;
+    let err = match controller.apply_policy(policy.clone()) {
+        Ok(()) => return Ok(controller),
+        Err(err) => Error::apply_failed(ApplyStep::WriteMaps, err),
+    };
+
+    let refused = matches!(
+        err,
+        Error::ApplyFailed {
+            step: ApplyStep::Attach,
+            rollback: None,
+            ..
+        }
+    );
+    match htb_fallback(&cgroup, policy, iface) {
+        Some(mut htb) if refused => {
+            warn!(
+                "eBPF program refused ({}), falling back to HTB on {}",
+                err,
+                htb.iface()
+            );
+            htb.apply_policy(policy.clone())
+                .map_err(|err| Error::apply_failed(ApplyStep::Attach, err))?;
+            Ok(Box::new(htb))
+        }
+        _ => Err(err),
+    }
+}
+
+/// HTB can take over a plain token bucket on `iface`, unless it is a dry run
+/// which HTB can't do
+fn htb_fallback(
+    cgroup: &CgroupName,
+    policy: &Policy,
+    iface: Option<&str>,
+) -> Option<HtbController> {
+    match iface {
+        Some(iface) if policy.algorithm() == TOKEN_BUCKET && !policy.dry_run() => {
+            Some(HtbController::new(cgroup.clone(), iface))
+        }
+        _ => None,
+    }
 }
@@ -0,0 +1,490 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use ebpf::{
+    ebpf::{CGROUP_ROOT, ProgramId},
+    pins::PinNamespace,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error,
+    util::{load_file, write_file},
+};
+
+use super::{Pid, Policy, RuleId, Target};
+
+/// Rule id of the `GlobalController`, the registry never hands it out
+pub const GLOBAL_RULE: RuleId = RuleId(0);
+
+/// Target name the global rule is registered under
+pub const GLOBAL_TARGET: &str = "global";
+
+/// Where instances keep their state unless told otherwise
+pub const STATE_DIR: &str = "/var/lib/rateforge";
+
+/// What the registry knows about a rule
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleEntry {
+    pub id: RuleId,
+    /// The process name the rule limits, `global` for the global rule
+    pub target: String,
+    pub algorithm: String,
+    /// What the rule enforces, so it can be shown without its process
+    #[serde(default)]
+    pub policy: Option<Policy>,
+    /// The rateforge process enforcing the rule in the foreground. Detached
+    /// rules are only kept in place by their pinned links.
+    #[serde(default)]
+    pub owner: Option<u32>,
+    /// The cgroup the rule's programs are attached to, unknown for rules
+    /// recorded before it was
+    #[serde(default)]
+    pub cgroup: Option<PathBuf>,
+}
+
+impl RuleEntry {
+    /// Programs of the rule are created with this id
+    pub fn program_id(&self) -> ProgramId {
+        self.id.0.into()
+    }
+
+    /// Directory the programs and links of the rule are pinned in
+    pub fn pin_dir(&self, pins: &PinNamespace) -> PathBuf {
+        pins.policy_dir(self.program_id())
+    }
+
+    /// The owner, if it is still running
+    pub fn live_owner(&self) -> Option<u32> {
+        let owner = self.owner?;
+        Path::new("/proc")
+            .join(owner.to_string())
+            .exists()
+            .then_some(owner)
+    }
+
+    /// The cgroup the rule's programs are attached to. Rules recorded
+    /// without it are on the cgroup named after their target.
+    pub fn cgroup_path(&self) -> PathBuf {
+        if let Some(cgroup) = &self.cgroup {
+            return cgroup.clone();
+        }
+        match self.id == GLOBAL_RULE {
+            true => PathBuf::from(CGROUP_ROOT),
+            false => PathBuf::from(CGROUP_ROOT).join(&self.target),
+        }
+    }
+
+    /// Processes in the rule's cgroup. The global rule covers every process,
+    /// so none are listed for it.
+    pub fn processes(&self) -> Vec<Pid> {
+        if self.id == GLOBAL_RULE {
+            return Vec::new();
+        }
+        std::fs::read_to_string(self.cgroup_path().join("cgroup.procs"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| Pid::try_from(line).ok())
+            .collect()
+    }
+}
+
+/// A rule `RuleRegistry::claim` handed to a rateforge process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Claim {
+    pub id: RuleId,
+    /// The id was allocated for the claim, rather than taken over from a
+    /// rule left behind
+    pub allocated: bool,
+}
+
+/// Hands out rule ids that are never reused while the state file is kept,
+/// so a restarted daemon finds the pins of its rules under the same ids.
+///
+/// Every rateforge process of an instance shares the file, so changes are
+/// made to what is on disk rather than to what was read when opening, while
+/// holding a lock on `rules.lock` next to it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RuleRegistry {
+    next_id: u64,
+    rules: BTreeMap<RuleId, RuleEntry>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl RuleRegistry {
+    /// Registry that only lives in memory
+    pub fn new() -> Self {
+        Self {
+            next_id: GLOBAL_RULE.0 + 1,
+            ..Default::default()
+        }
+    }
+
+    /// `<STATE_DIR>/<instance>/rules.json`
+    pub fn path_for(instance: &str) -> PathBuf {
+        Path::new(STATE_DIR).join(instance).join("rules.json")
+    }
+
+    /// Loads the registry saved at `path`, or starts an empty one there
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut registry = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|err| {
+                Error::General(format!("Corrupt rule registry {:?}: {}", path, err))
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::new(),
+            Err(err) => Err(err)?,
+        };
+        registry.path = Some(path.to_path_buf());
+        Ok(registry)
+    }
+
+    /// Makes `owner` the rateforge process enforcing the rule of `target`,
+    /// in one step so two processes can't both claim it. A target only has
+    /// one rule, a rule left behind by a rateforge process that is gone keeps
+    /// its id so its pinned links are taken over. The global rule always has
+    /// `GLOBAL_RULE` as its id.
+    pub fn claim(&mut self, target: &Target, algorithm: &str, owner: u32) -> Result<Claim, Error> {
+        let _lock = self.lock()?;
+        self.reload()?;
+        let (existing, name) = match target {
+            Target::Process(name) => (self.find(name).map(|rule| rule.id), name.as_str()),
+            Target::Global => (
+                self.rules.contains_key(&GLOBAL_RULE).then_some(GLOBAL_RULE),
+                GLOBAL_TARGET,
+            ),
+        };
+
+        let claim = match existing {
+            Some(id) => {
+                self.check_unowned(id, name)?;
+                Claim {
+                    id,
+                    allocated: false,
+                }
+            }
+            None => {
+                let id = match target {
+                    Target::Process(_) => {
+                        self.next_id += 1;
+                        RuleId(self.next_id - 1)
+                    }
+                    Target::Global => GLOBAL_RULE,
+                };
+                self.rules.insert(
+                    id,
+                    RuleEntry {
+                        id,
+                        target: name.into(),
+                        algorithm: algorithm.into(),
+                        policy: None,
+                        owner: None,
+                        cgroup: None,
+                    },
+                );
+                Claim {
+                    id,
+                    allocated: true,
+                }
+            }
+        };
+        if let Some(rule) = self.rules.get_mut(&claim.id) {
+            rule.algorithm = algorithm.into();
+            rule.owner = Some(owner);
+        }
+        self.save()?;
+        Ok(claim)
+    }
+
+    /// Gives a claim back after applying its rule failed. An allocated id is
+    /// forgotten, a rule that was taken over is left to its links again.
+    pub fn unclaim(&mut self, claim: Claim) -> Result<(), Error> {
+        match claim.allocated {
+            true => self.remove(claim.id).map(|_| ()),
+            false => self.set_owner(claim.id, None),
+        }
+    }
+
+    pub fn get(&self, id: RuleId) -> Result<&RuleEntry, Error> {
+        self.rules
+            .get(&id)
+            .ok_or(Error::PolicyNotFound { given: id })
+    }
+
+    /// The rule of the process `target`, never the global rule
+    pub fn find(&self, target: &str) -> Option<&RuleEntry> {
+        self.rules
+            .values()
+            .find(|rule| rule.id != GLOBAL_RULE && rule.target == target)
+    }
+
+    /// Looks a rule up by id, by process name, or `global`
+    pub fn resolve(&self, key: &str) -> Result<&RuleEntry, Error> {
+        if key == GLOBAL_TARGET {
+            return self.get(GLOBAL_RULE);
+        }
+        if let Ok(id) = key.parse::<u64>() {
+            return self.get(RuleId(id));
+        }
+        self.find(key)
+            .ok_or(Error::TargetNotLimited { target: key.into() })
+    }
+
+    /// Records that the rule now runs `algorithm`
+    pub fn set_algorithm(&mut self, id: RuleId, algorithm: &str) -> Result<(), Error> {
+        self.update(id, |rule| rule.algorithm = algorithm.into())
+    }
+
+    pub fn set_policy(&mut self, id: RuleId, policy: &Policy) -> Result<(), Error> {
+        self.update(id, |rule| {
+            rule.algorithm = policy.algorithm().into();
+            rule.policy = Some(policy.clone());
+        })
+    }
+
+    pub fn set_cgroup(&mut self, id: RuleId, cgroup: &Path) -> Result<(), Error> {
+        self.update(id, |rule| rule.cgroup = Some(cgroup.to_path_buf()))
+    }
+
+    /// `None` once the rule is detached from the process that applied it
+    pub fn set_owner(&mut self, id: RuleId, owner: Option<u32>) -> Result<(), Error> {
+        self.update(id, |rule| rule.owner = owner)
+    }
+
+    /// Forgets the rule, its id is not handed out again
+    pub fn remove(&mut self, id: RuleId) -> Result<RuleEntry, Error> {
+        let _lock = self.lock()?;
+        self.reload()?;
+        let rule = self
+            .rules
+            .remove(&id)
+            .ok_or(Error::PolicyNotFound { given: id })?;
+        self.save()?;
+        Ok(rule)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RuleEntry> {
+        self.rules.values()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn update(&mut self, id: RuleId, f: impl FnOnce(&mut RuleEntry)) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        self.reload()?;
+        let rule = self
+            .rules
+            .get_mut(&id)
+            .ok_or(Error::PolicyNotFound { given: id })?;
+        f(rule);
+        self.save()
+    }
+
+    fn check_unowned(&self, id: RuleId, target: &str) -> Result<(), Error> {
+        let owned = self
+            .rules
+            .get(&id)
+            .is_some_and(|rule| rule.live_owner().is_some());
+        if owned {
+            Err(Error::PolicyAlreadyExists {
+                message: format!("{} is already limited by rule {}", target, id),
+            })?
+        }
+        Ok(())
+    }
+
+    /// Exclusive lock on the registry until the file is dropped, so another
+    /// process can't change it between reading and saving
+    fn lock(&self) -> Result<Option<File>, Error> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        let file = load_file(&path.with_extension("lock"))?;
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+                return Ok(Some(file));
+            }
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                Err(err)?
+            }
+        }
+    }
+
+    /// Picks up what other processes changed since the file was read
+    fn reload(&mut self) -> Result<(), Error> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        *self = Self::open(path)?;
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let bytes = serde_json::to_vec_pretty(self)
+            .map_err(|err| Error::General(format!("Could not save rule registry: {}", err)))?;
+        // Readers in other processes never see a half written file
+        let staged = path.with_extension("json.tmp");
+        write_file(&staged, &bytes)?;
+        std::fs::rename(staged, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{GCRA, TOKEN_BUCKET};
+
+    /// No process has this pid
+    const GONE: u32 = u32::MAX;
+
+    fn process(name: &str) -> Target {
+        Target::Process(name.into())
+    }
+
+    fn live() -> u32 {
+        std::process::id()
+    }
+
+    #[test]
+    fn claim_allocates_ids_after_the_global_rule() {
+        let mut registry = RuleRegistry::new();
+        let curl = registry
+            .claim(&process("curl"), TOKEN_BUCKET, live())
+            .unwrap();
+        let wget = registry
+            .claim(&process("wget"), TOKEN_BUCKET, live())
+            .unwrap();
+        let global = registry
+            .claim(&Target::Global, TOKEN_BUCKET, live())
+            .unwrap();
+
+        assert_eq!(
+            curl,
+            Claim {
+                id: RuleId(1),
+                allocated: true
+            }
+        );
+        assert_eq!(wget.id, RuleId(2));
+        assert_eq!(
+            global,
+            Claim {
+                id: GLOBAL_RULE,
+                allocated: true
+            }
+        );
+        assert_eq!(registry.get(curl.id).unwrap().owner, Some(live()));
+    }
+
+    #[test]
+    fn claim_refuses_a_rule_with_a_live_owner() {
+        let mut registry = RuleRegistry::new();
+        registry
+            .claim(&process("curl"), TOKEN_BUCKET, live())
+            .unwrap();
+        assert!(matches!(
+            registry.claim(&process("curl"), TOKEN_BUCKET, live()),
+            Err(Error::PolicyAlreadyExists { .. })
+        ));
+    }
+
+    #[test]
+    fn claim_takes_over_a_rule_left_behind() {
+        let mut registry = RuleRegistry::new();
+        let left = registry
+            .claim(&process("curl"), TOKEN_BUCKET, GONE)
+            .unwrap();
+        let taken = registry.claim(&process("curl"), GCRA, live()).unwrap();
+
+        assert_eq!(
+            taken,
+            Claim {
+                id: left.id,
+                allocated: false
+            }
+        );
+        let rule = registry.get(taken.id).unwrap();
+        assert_eq!(rule.owner, Some(live()));
+        assert_eq!(rule.algorithm, GCRA);
+    }
+
+    #[test]
+    fn unclaim_only_forgets_allocated_ids() {
+        let mut registry = RuleRegistry::new();
+        let left = registry
+            .claim(&process("curl"), TOKEN_BUCKET, GONE)
+            .unwrap();
+        let taken = registry
+            .claim(&process("curl"), TOKEN_BUCKET, live())
+            .unwrap();
+        registry.unclaim(taken).unwrap();
+        assert_eq!(registry.get(left.id).unwrap().owner, None);
+
+        let fresh = registry
+            .claim(&process("wget"), TOKEN_BUCKET, live())
+            .unwrap();
+        registry.unclaim(fresh).unwrap();
+        assert!(registry.get(fresh.id).is_err());
+        // Ids are never handed out twice
+        let next = registry
+            .claim(&process("wget"), TOKEN_BUCKET, live())
+            .unwrap();
+        assert_eq!(next.id, RuleId(3));
+    }
+
+    #[test]
+    fn resolve_by_id_name_or_global() {
+        let mut registry = RuleRegistry::new();
+        let curl = registry
+            .claim(&process("curl"), TOKEN_BUCKET, live())
+            .unwrap();
+        registry
+            .claim(&Target::Global, TOKEN_BUCKET, live())
+            .unwrap();
+
+        assert_eq!(registry.resolve("1").unwrap().id, curl.id);
+        assert_eq!(registry.resolve("curl").unwrap().id, curl.id);
+        assert_eq!(registry.resolve("global").unwrap().id, GLOBAL_RULE);
+        assert!(matches!(
+            registry.resolve("wget"),
+            Err(Error::TargetNotLimited { .. })
+        ));
+        // A process called global is never confused with the global rule
+        assert!(registry.find(GLOBAL_TARGET).is_none());
+    }
+
+    #[test]
+    fn changes_go_through_the_file() {
+        let dir = std::env::temp_dir().join(format!("rtfg-registry-{}", std::process::id()));
+        let path = dir.join("rules.json");
+        let mut first = RuleRegistry::open(&path).unwrap();
+        let mut second = RuleRegistry::open(&path).unwrap();
+
+        let curl = first.claim(&process("curl"), TOKEN_BUCKET, GONE).unwrap();
+        let wget = second.claim(&process("wget"), TOKEN_BUCKET, GONE).unwrap();
+        assert_ne!(curl.id, wget.id);
+        second
+            .set_cgroup(curl.id, Path::new("/sys/fs/cgroup/curl"))
+            .unwrap();
+
+        let reopened = RuleRegistry::open(&path).unwrap();
+        assert_eq!(reopened.iter().count(), 2);
+        assert_eq!(
+            reopened.get(curl.id).unwrap().cgroup.as_deref(),
+            Some(Path::new("/sys/fs/cgroup/curl"))
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use ebpf::ebpf::CGROUP_ROOT;
+use log::warn;
+
+use crate::Error;
+
+use super::{
+    CgroupName, GlobalController, Pid, PinNamespace, Policy, RateController, Target,
+    UNREGISTERED_RULE, apply_controller,
+};
+
+/// Steps of applying a policy, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyStep {
+    CreateCgroup,
+    MoveTasks,
+    CreateProgram,
+    WriteMaps,
+    Attach,
+}
+
+impl fmt::Display for ApplyStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let step = match self {
+            ApplyStep::CreateCgroup => "creating the cgroup",
+            ApplyStep::MoveTasks => "moving processes into the cgroup",
+            ApplyStep::CreateProgram => "creating the limit program",
+            ApplyStep::WriteMaps => "writing the limits",
+            ApplyStep::Attach => "attaching the program",
+        };
+        write!(f, "{}", step)
+    }
+}
+
+/// The cgroup half of applying a policy. Remembers where every process it
+/// moved came from, so a later failure can put them back.
+#[derive(Debug)]
+pub struct StagedCgroup {
+    cgroup: CgroupName,
+    created: bool,
+    moved: Vec<(Pid, PathBuf)>,
+}
+
+impl StagedCgroup {
+    /// Creates the cgroup for `name`, or opens it if a previous run left it
+    /// behind. Only a cgroup created here is removed on rollback.
+    pub fn create(name: &str) -> Result<Self, Error> {
+        let created = !Path::new(CGROUP_ROOT).join(name).exists();
+        let cgroup = CgroupName::new(name)
+            .map_err(|err| Error::apply_failed(ApplyStep::CreateCgroup, err.into()))?;
+        Ok(Self {
+            cgroup,
+            created,
+            moved: Vec::new(),
+        })
+    }
+
+    /// Moves `pids` into the cgroup. Processes that exited in the meantime
+    /// are skipped.
+    pub fn move_tasks(&mut self, pids: &[Pid]) -> Result<(), Error> {
+        for pid in pids {
+            let origin = match current_cgroup(*pid) {
+                Ok(origin) => origin,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => Err(Error::apply_failed(ApplyStep::MoveTasks, err.into()))?,
+            };
+            self.cgroup
+                .add_task((*pid).into())
+                .map_err(|err| Error::apply_failed(ApplyStep::MoveTasks, err.into()))?;
+            self.moved.push((*pid, origin));
+        }
+        Ok(())
+    }
+
+    pub fn cgroup(&self) -> &CgroupName {
+        &self.cgroup
+    }
+
+    /// Keeps every change
+    pub fn commit(self) -> CgroupName {
+        self.cgroup
+    }
+
+    /// Moves the processes back in reverse order and removes the cgroup if
+    /// it was created here. Tries every step even when one fails.
+    pub fn rollback(self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for (pid, origin) in self.moved.iter().rev() {
+            match move_to(*pid, origin) {
+                Ok(()) => (),
+                // Gone already, nothing to restore
+                Err(err) if err.raw_os_error() == Some(libc::ESRCH) => (),
+                Err(err) => {
+                    warn!("Could not move {} back to {:?}: {}", pid.0, origin, err);
+                    result = Err(err.into());
+                }
+            }
+        }
+        let removed = match self.created {
+            true => self.cgroup.delete(),
+            false => Ok(()),
+        };
+        if let Err(err) = removed {
+            warn!(
+                "Could not remove cgroup {:?}: {}",
+                self.cgroup.as_ref(),
+                err
+            );
+            result = Err(err.into());
+        }
+        result
+    }
+}
+
+/// Applies `policy` to `target` as one step. `pids` are moved into a
+/// process target's cgroup first. On failure every completed step is
+/// undone and the error names the step that failed.
+pub fn apply_transaction(
+    target: &Target,
+    policy: &Policy,
+    pids: &[Pid],
+    iface: Option<&str>,
+    pins: &PinNamespace,
+) -> Result<Box<dyn RateController>, Error> {
+    let name = match target {
+        Target::Process(name) => name,
+        Target::Global => {
+            // A failed apply takes back what it attached, a global limit left
+            // pinned before keeps running
+            let mut controller = GlobalController::with_pins(pins.clone())
+                .map_err(|err| Error::apply_failed(ApplyStep::CreateProgram, err))?;
+            controller
+                .apply_policy(policy.clone())
+                .map_err(|err| Error::apply_failed(ApplyStep::WriteMaps, err))?;
+            return Ok(Box::new(controller));
+        }
+    };
+
+    if *policy.id() == UNREGISTERED_RULE {
+        Err(Error::UnregisteredPolicy)?
+    }
+    let mut staged = StagedCgroup::create(name)?;
+    let applied = staged
+        .move_tasks(pids)
+        .and_then(|()| apply_controller(staged.cgroup().clone(), policy, iface, pins));
+
+    match applied {
+        Ok(controller) => {
+            staged.commit();
+            Ok(controller)
+        }
+        Err(err) => match staged.rollback() {
+            Ok(()) => Err(err),
+            Err(rollback) => Err(err.rollback_failed(rollback)),
+        },
+    }
+}
+
+/// Cgroup the process is in right now, from the cgroup2 line of
+/// `/proc/<pid>/cgroup`
+fn current_cgroup(pid: Pid) -> io::Result<PathBuf> {
+    let content = fs::read_to_string(format!("/proc/{}/cgroup", pid.0))?;
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Process {} is not on the cgroup2 hierarchy", pid.0),
+        ))
+}
+
+fn move_to(pid: Pid, cgroup: &Path) -> io::Result<()> {
+    fs::write(cgroup.join("cgroup.procs"), pid.0.to_string())
+}
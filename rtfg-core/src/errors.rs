@@ -1,4 +1,6 @@
-use crate::control::RuleId;
+use crate::control::{ApplyStep, RuleId};
+pub use ebpf::ErrorKind;
+use ebpf::pins::PinError;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -6,19 +8,34 @@ pub enum Error {
     #[error("Process {name:?} was not found")]
     ProcessNotFound { name: String },
 
+    #[error("No process with pid {pid} is running")]
+    PidNotFound { pid: usize },
+
     #[error("I/O error occurred: {0}")]
     Io(#[from] std::io::Error),
 
-    #[cfg(target_os = "linux")]
-    #[error("tc error: {0}")]
-    Cgroup(String),
-
     #[error("Policy with Id {given} does not exist")]
     PolicyNotFound { given: RuleId },
 
+    #[error("The policy has no rule id, register it first")]
+    UnregisteredPolicy,
+
+    #[error("No rule limits {target}")]
+    TargetNotLimited { target: String },
+
     #[error("{message}")]
     PolicyAlreadyExists { message: String },
 
+    #[error("Failed {step}, {}: {source}", match .rollback {
+        None => "every completed step was undone".to_string(),
+        Some(err) => format!("undoing the completed steps failed too ({})", err),
+    })]
+    ApplyFailed {
+        step: ApplyStep,
+        source: Box<Error>,
+        rollback: Option<Box<Error>>,
+    },
+
     #[error("Error occured: {0}")]
     General(String),
 
@@ -28,3 +45,104 @@ pub enum Error {
     #[error("Unexpected Error occured")]
     Unknown,
 }
+
+impl Error {
+    /// `source` failed at `step` and whatever ran before it was undone. An
+    /// error that already names its step keeps it.
+    pub fn apply_failed(step: ApplyStep, source: Error) -> Self {
+        match source {
+            Error::ApplyFailed { .. } => source,
+            source => Error::ApplyFailed {
+                step,
+                source: Box::new(source),
+                rollback: None,
+            },
+        }
+    }
+
+    /// Groups the error by what the user can do about it
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io(err) => ErrorKind::from_io(err),
+            Error::ApplyFailed { source, .. } => source.kind(),
+            Error::ProgramError(err) => err.kind(),
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// What the user can do about the error, if anything
+    pub fn hint(&self) -> Option<&'static str> {
+        self.kind().hint()
+    }
+
+    /// Records that undoing the steps before a failure failed as well
+    pub fn rollback_failed(self, rollback: Error) -> Self {
+        match self {
+            Error::ApplyFailed { step, source, .. } => Error::ApplyFailed {
+                step,
+                source,
+                rollback: Some(Box::new(rollback)),
+            },
+            err => err,
+        }
+    }
+}
+
+impl From<PinError> for Error {
+    fn from(err: PinError) -> Self {
+        Error::ProgramError(err.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errno(code: i32) -> Error {
+        std::io::Error::from_raw_os_error(code).into()
+    }
+
+    #[test]
+    fn kind_follows_the_errno() {
+        assert_eq!(errno(libc::EPERM).kind(), ErrorKind::MissingCapability);
+        assert_eq!(errno(libc::EACCES).kind(), ErrorKind::PermissionDenied);
+        assert_eq!(errno(libc::ENOENT).kind(), ErrorKind::Other);
+        assert_eq!(Error::Unknown.kind(), ErrorKind::Other);
+        assert!(Error::Unknown.hint().is_none());
+    }
+
+    #[test]
+    fn failed_steps_keep_the_kind_of_their_cause() {
+        let err = Error::apply_failed(ApplyStep::MoveTasks, errno(libc::EPERM));
+        assert_eq!(err.kind(), ErrorKind::MissingCapability);
+
+        let err = err.rollback_failed(errno(libc::EACCES));
+        assert_eq!(err.kind(), ErrorKind::MissingCapability);
+        assert!(matches!(
+            err,
+            Error::ApplyFailed {
+                rollback: Some(_),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn the_first_failed_step_is_kept() {
+        let err = Error::apply_failed(ApplyStep::MoveTasks, errno(libc::EPERM));
+        let err = Error::apply_failed(ApplyStep::Attach, err);
+        assert!(matches!(
+            err,
+            Error::ApplyFailed {
+                step: ApplyStep::MoveTasks,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn only_failed_steps_record_a_rollback() {
+        let err = Error::Unknown.rollback_failed(errno(libc::EPERM));
+        assert!(matches!(err, Error::Unknown));
+    }
+}
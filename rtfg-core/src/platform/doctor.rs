@@ -0,0 +1,375 @@
+use std::{collections::BTreeSet, env, fmt, fs, path::Path};
+
+use ebpf::{
+    ebpf::{CGROUP_ROOT, cgroup2_mounted},
+    pins::verify_bpffs,
+    probe::{KernelFeatures, Support},
+};
+use serde::Serialize;
+
+use crate::{
+    ErrorKind,
+    control::{GLOBAL_RULE, RuleRegistry},
+};
+
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_BPF: u32 = 39;
+
+/// Kernels from 5.11 charge bpf memory to the cgroup instead of the memlock
+/// rlimit
+const MEMCG_ACCOUNTING: (u32, u32) = (5, 11);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but something may need attention
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckStatus::Pass => f.pad("pass"),
+            CheckStatus::Warn => f.pad("warn"),
+            CheckStatus::Fail => f.pad("FAIL"),
+        }
+    }
+}
+
+/// One prerequisite and how to fix it when it isn't met
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: String) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Pass,
+            detail,
+            fix: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: String, fix: &str) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Warn,
+            detail,
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn fail(name: &'static str, detail: String, fix: &str) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Fail,
+            detail,
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// Everything rateforge assumes about the machine, checked up front
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnosis {
+    pub checks: Vec<Check>,
+    pub kernel: KernelFeatures,
+}
+
+impl Diagnosis {
+    /// Checks the pins of `instance` under the bpffs mounted at `bpffs`,
+    /// which doesn't have to be a bpffs mount for the other checks to run
+    pub fn run(bpffs: &Path, instance: &str) -> Self {
+        let kernel = KernelFeatures::probe();
+        let checks = vec![
+            check_cgroup2(),
+            check_bpffs(bpffs),
+            check_capabilities(),
+            check_tools(),
+            check_memlock(&kernel.release),
+            check_kernel(&kernel),
+            check_stale_pins(&bpffs.join("rateforge").join(instance), instance),
+        ];
+        Self { checks, kernel }
+    }
+
+    /// No check failed, warnings don't stop rateforge from working
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.status != CheckStatus::Fail)
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "[{}] {}: {}", check.status, check.name, check.detail)?;
+            if let Some(fix) = &check.fix {
+                writeln!(f, "       fix: {}", fix)?;
+            }
+        }
+        writeln!(f)?;
+        write!(f, "{}", self.kernel)
+    }
+}
+
+fn hint(kind: ErrorKind) -> &'static str {
+    kind.hint().unwrap_or_default()
+}
+
+fn check_cgroup2() -> Check {
+    const NAME: &str = "cgroup v2";
+    match cgroup2_mounted() {
+        true => Check::pass(NAME, format!("mounted at {}", CGROUP_ROOT)),
+        false => Check::fail(
+            NAME,
+            format!("{} is not a cgroup2 mount", CGROUP_ROOT),
+            hint(ErrorKind::CgroupV1Only),
+        ),
+    }
+}
+
+fn check_bpffs(bpffs: &Path) -> Check {
+    const NAME: &str = "bpffs";
+    match verify_bpffs(bpffs) {
+        Ok(()) => Check::pass(NAME, format!("mounted at {}", bpffs.display())),
+        Err(err) => Check::fail(NAME, err.to_string(), hint(ErrorKind::BpffsNotMounted)),
+    }
+}
+
+/// Loading needs `CAP_BPF`, or `CAP_SYS_ADMIN` on kernels that predate it,
+/// attaching to cgroups needs `CAP_NET_ADMIN` and creating cgroups and
+/// moving processes into them needs `CAP_SYS_ADMIN`
+fn check_capabilities() -> Check {
+    const NAME: &str = "capabilities";
+    let Some(effective) = effective_capabilities() else {
+        return Check::warn(
+            NAME,
+            "can't read CapEff from /proc/self/status".into(),
+            hint(ErrorKind::MissingCapability),
+        );
+    };
+    let has = |cap: u32| effective & (1 << cap) != 0;
+
+    let mut missing = Vec::new();
+    if !has(CAP_BPF) && !has(CAP_SYS_ADMIN) {
+        missing.push("CAP_BPF");
+    }
+    if !has(CAP_NET_ADMIN) {
+        missing.push("CAP_NET_ADMIN");
+    }
+    if !has(CAP_SYS_ADMIN) {
+        missing.push("CAP_SYS_ADMIN");
+    }
+    match missing.is_empty() {
+        true => Check::pass(
+            NAME,
+            "CAP_BPF, CAP_NET_ADMIN and CAP_SYS_ADMIN are effective".into(),
+        ),
+        false => Check::fail(
+            NAME,
+            format!("missing {}", missing.join(", ")),
+            hint(ErrorKind::MissingCapability),
+        ),
+    }
+}
+
+fn effective_capabilities() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(caps.trim(), 16).ok()
+}
+
+/// The edt algorithm sets up its `fq` qdisc with `tc`. HTB limits are set up
+/// over netlink, `ip` and `nft` only show what they did.
+fn check_tools() -> Check {
+    const NAME: &str = "tools";
+    let missing: Vec<_> = ["tc", "ip", "nft"]
+        .into_iter()
+        .filter(|tool| !on_path(tool))
+        .collect();
+    if missing.is_empty() {
+        return Check::pass(NAME, "tc, ip and nft are installed".into());
+    }
+
+    let detail = format!("{} not found in PATH", missing.join(", "));
+    match missing.contains(&"tc") {
+        true => Check::warn(
+            NAME,
+            format!("{}, the edt algorithm can't be used", detail),
+            "Install iproute2 for tc, every other algorithm works without it",
+        ),
+        false => Check::pass(
+            NAME,
+            format!("{}, only needed to inspect HTB limits", detail),
+        ),
+    }
+}
+
+fn on_path(tool: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(tool).is_file()))
+}
+
+/// Programs are loaded after raising the soft limit, so only a low hard
+/// limit on a kernel without memcg accounting is a problem
+fn check_memlock(release: &str) -> Check {
+    const NAME: &str = "memlock rlimit";
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+        return Check::warn(
+            NAME,
+            std::io::Error::last_os_error().to_string(),
+            "Check the limit with `ulimit -l`",
+        );
+    }
+
+    if limit.rlim_max == libc::RLIM_INFINITY {
+        return Check::pass(NAME, "unlimited".into());
+    }
+    let detail = format!("hard limit is {} KiB", limit.rlim_max / 1024);
+    match kernel_version(release) >= Some(MEMCG_ACCOUNTING) {
+        true => Check::pass(NAME, format!("{}, unused by this kernel", detail)),
+        false => Check::warn(
+            NAME,
+            detail,
+            "Run with `ulimit -l unlimited` or `LimitMEMLOCK=infinity` in the systemd unit",
+        ),
+    }
+}
+
+fn kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+fn check_kernel(kernel: &KernelFeatures) -> Check {
+    const NAME: &str = "kernel features";
+    let missing: Vec<_> = kernel.missing().map(|feature| feature.name).collect();
+    if !missing.is_empty() {
+        return Check::fail(
+            NAME,
+            format!("{} lacks {}", kernel.release, missing.join(", ")),
+            hint(ErrorKind::KernelTooOld),
+        );
+    }
+
+    let unknown = kernel
+        .features
+        .iter()
+        .any(|feature| feature.required && feature.support == Support::Unknown);
+    match unknown {
+        true => Check::warn(
+            NAME,
+            format!("{}, some features could not be probed", kernel.release),
+            hint(ErrorKind::MissingCapability),
+        ),
+        false => Check::pass(NAME, format!("{} has everything needed", kernel.release)),
+    }
+}
+
+/// Policy directories no registered rule owns are left over from a run that
+/// didn't exit cleanly. Rules whose cgroup emptied keep their pins until
+/// rateforge runs for the process again.
+fn check_stale_pins(dir: &Path, instance: &str) -> Check {
+    const NAME: &str = "stale pins";
+    let policies: BTreeSet<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => return Check::pass(NAME, format!("nothing pinned under {}", dir.display())),
+    };
+
+    let registry = match RuleRegistry::open(RuleRegistry::path_for(instance)) {
+        Ok(registry) => registry,
+        Err(err) => {
+            return Check::warn(
+                NAME,
+                format!("can't open the rule registry: {}", err),
+                "Run as root, the registry lives under /var/lib/rateforge",
+            );
+        }
+    };
+
+    let mut stale: Vec<String> = policies
+        .iter()
+        .filter(|policy| **policy != GLOBAL_RULE.to_string())
+        .filter(|policy| registry.iter().all(|rule| rule.id.to_string() != **policy))
+        .map(|policy| dir.join(policy).display().to_string())
+        .collect();
+    stale.extend(
+        registry
+            .iter()
+            .filter(|rule| rule.id != GLOBAL_RULE && policies.contains(&rule.id.to_string()))
+            .filter(|rule| rule.live_owner().is_none())
+            // Rules on pids or trees are named after them rather than after
+            // a process, only their cgroup tells whether they still limit
+            .filter(|rule| rule.processes().is_empty())
+            .map(|rule| {
+                let pin_dir = dir.join(rule.id.to_string());
+                format!(
+                    "{} (no process left in {})",
+                    pin_dir.display(),
+                    rule.cgroup_path().display()
+                )
+            }),
+    );
+
+    match stale.is_empty() {
+        true => Check::pass(NAME, format!("every pin under {} is in use", dir.display())),
+        false => Check::warn(
+            NAME,
+            stale.join(", "),
+            "Stale links keep limiting their cgroups, remove them with `rm -r <dir>`",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_version_of_a_release() {
+        assert_eq!(kernel_version("6.8.0-45-generic"), Some((6, 8)));
+        assert_eq!(
+            kernel_version("5.15.167.4-microsoft-standard-WSL2"),
+            Some((5, 15))
+        );
+        assert_eq!(kernel_version("4.19"), Some((4, 19)));
+        assert_eq!(kernel_version("6.10-rc1"), Some((6, 10)));
+    }
+
+    #[test]
+    fn kernel_version_needs_major_and_minor() {
+        assert_eq!(kernel_version("6"), None);
+        assert_eq!(kernel_version("linux-6.8"), None);
+        assert_eq!(kernel_version(""), None);
+    }
+
+    #[test]
+    fn memcg_accounting_starts_at_5_11() {
+        assert!(kernel_version("5.10.220") < Some(MEMCG_ACCOUNTING));
+        assert!(kernel_version("5.11.0") >= Some(MEMCG_ACCOUNTING));
+        assert!(kernel_version("6.1.0") >= Some(MEMCG_ACCOUNTING));
+        // Unknown releases are treated as lacking it
+        assert!(kernel_version("unknown") < Some(MEMCG_ACCOUNTING));
+    }
+}
@@ -1,5 +1,14 @@
 use std::ffi::OsString;
 
+mod doctor;
+mod net;
+mod netlink;
+mod process;
+pub use doctor::*;
+pub use net::*;
+pub use netlink::*;
+pub use process::*;
+
 use sysinfo::{RefreshKind, System};
 
 use crate::control::Pid;
@@ -0,0 +1,205 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    os::fd::{AsRawFd, RawFd},
+};
+
+use netlink_packet_core::{NetlinkMessage, NetlinkPayload};
+use netlink_packet_route::{RtnlMessage, constants::RTNLGRP_LINK};
+use netlink_sys::Socket;
+
+use super::{NETLINK_ROUTE, NetlinkSocket, link_name};
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+/// Name of the interface carrying the IPv4 default route
+pub fn default_interface() -> io::Result<Option<String>> {
+    let routes = fs::read_to_string("/proc/net/route")?;
+
+    // Iface Destination Gateway ... with the destination in hex
+    let iface = routes.lines().skip(1).find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let iface = fields.next()?;
+        let destination = fields.next()?;
+        (destination == "00000000").then(|| iface.to_string())
+    });
+
+    Ok(iface)
+}
+
+/// Kernel index of the interface called `name`
+pub fn interface_index(name: &str) -> io::Result<u32> {
+    let index = fs::read_to_string(format!("{}/{}/ifindex", SYS_CLASS_NET, name))?;
+    index
+        .trim()
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Every interface currently present, by index
+pub fn interfaces() -> io::Result<BTreeMap<u32, String>> {
+    let mut found = BTreeMap::new();
+    for entry in fs::read_dir(SYS_CLASS_NET)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        // The interface can disappear between listing and reading its index
+        if let Ok(index) = interface_index(&name) {
+            found.insert(index, name);
+        }
+    }
+    Ok(found)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterfaceEvent {
+    Added {
+        index: u32,
+        name: String,
+    },
+    Removed {
+        index: u32,
+        name: String,
+    },
+    Renamed {
+        index: u32,
+        from: String,
+        to: String,
+    },
+}
+
+impl InterfaceEvent {
+    /// Whether the event changes what any of `names` resolves to
+    pub fn concerns(&self, names: &[String]) -> bool {
+        match self {
+            InterfaceEvent::Added { name, .. } | InterfaceEvent::Removed { name, .. } => {
+                names.contains(name)
+            }
+            InterfaceEvent::Renamed { from, to, .. } => names.contains(from) || names.contains(to),
+        }
+    }
+}
+
+/// Follows interfaces by index, so a renamed interface is told apart from one
+/// that was unplugged and another that was plugged in. Changes arrive as the
+/// kernel's link notifications, the socket never blocks and can be waited on
+/// for readability through its raw fd.
+#[derive(Debug)]
+pub struct InterfaceWatcher {
+    socket: Socket,
+    known: BTreeMap<u32, String>,
+}
+
+impl InterfaceWatcher {
+    pub fn new() -> io::Result<Self> {
+        let mut socket = Socket::new(NETLINK_ROUTE)?;
+        socket.bind_auto()?;
+        socket.add_membership(RTNLGRP_LINK)?;
+        socket.set_non_blocking(true)?;
+
+        // Listed after subscribing, so no change can fall in between. One
+        // that is in both is seen as no change.
+        Ok(Self {
+            socket,
+            known: NetlinkSocket::new(NETLINK_ROUTE)?.links()?,
+        })
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<u32> {
+        self.known
+            .iter()
+            .find_map(|(index, known)| (known == name).then_some(*index))
+    }
+
+    /// Changes the kernel reported since the last poll
+    pub fn poll(&mut self) -> io::Result<Vec<InterfaceEvent>> {
+        let mut events = Vec::new();
+        loop {
+            match self.socket.recv_from_full() {
+                Ok((received, _)) => self.notified(&received, &mut events)?,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(events),
+                // The kernel dropped notifications, so list everything again
+                Err(err) if err.raw_os_error() == Some(libc::ENOBUFS) => {
+                    let current = NetlinkSocket::new(NETLINK_ROUTE)?.links()?;
+                    events.extend(self.resync(current));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn notified(&mut self, received: &[u8], events: &mut Vec<InterfaceEvent>) -> io::Result<()> {
+        let mut offset = 0;
+        while offset < received.len() {
+            let message = NetlinkMessage::<RtnlMessage>::deserialize(&received[offset..])
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+            if message.header.length == 0 {
+                break;
+            }
+            // Messages are 4 byte aligned
+            offset += (message.header.length as usize + 3) & !3;
+
+            match message.payload {
+                NetlinkPayload::InnerMessage(RtnlMessage::NewLink(link)) => {
+                    let index = link.header.index;
+                    let Some(name) = link_name(&link) else {
+                        continue;
+                    };
+                    match self.known.insert(index, name.clone()) {
+                        None => events.push(InterfaceEvent::Added { index, name }),
+                        Some(from) if from != name => events.push(InterfaceEvent::Renamed {
+                            index,
+                            from,
+                            to: name,
+                        }),
+                        // Only its state changed
+                        Some(_) => (),
+                    }
+                }
+                NetlinkPayload::InnerMessage(RtnlMessage::DelLink(link)) => {
+                    let index = link.header.index;
+                    if let Some(name) = self.known.remove(&index) {
+                        events.push(InterfaceEvent::Removed { index, name });
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes between what was known and `current`
+    fn resync(&mut self, current: BTreeMap<u32, String>) -> Vec<InterfaceEvent> {
+        let mut events = Vec::new();
+
+        for (index, name) in &self.known {
+            match current.get(index) {
+                None => events.push(InterfaceEvent::Removed {
+                    index: *index,
+                    name: name.clone(),
+                }),
+                Some(to) if to != name => events.push(InterfaceEvent::Renamed {
+                    index: *index,
+                    from: name.clone(),
+                    to: to.clone(),
+                }),
+                Some(_) => (),
+            }
+        }
+        for (index, name) in &current {
+            if !self.known.contains_key(index) {
+                events.push(InterfaceEvent::Added {
+                    index: *index,
+                    name: name.clone(),
+                });
+            }
+        }
+
+        self.known = current;
+        events
+    }
+}
+
+impl AsRawFd for InterfaceWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io,
+    os::fd::AsRawFd,
+    path::Path,
+};
+
+use netlink_packet_core::{
+    NLM_F_ACK, NLM_F_DUMP, NLM_F_REQUEST, NetlinkDeserializable, NetlinkHeader, NetlinkMessage,
+    NetlinkPayload, NetlinkSerializable,
+};
+use netlink_packet_route::{
+    LinkMessage, RtnlMessage,
+    link::nlas::{Info, InfoKind, Nla as LinkNla},
+};
+use netlink_sys::{Socket, SocketAddr};
+
+pub use netlink_sys::protocols::{NETLINK_NETFILTER, NETLINK_ROUTE};
+
+/// Where `ip netns add` keeps its namespaces
+const NETNS_DIR: &str = "/run/netns";
+
+/// A netlink socket that sends requests and waits for the kernel to answer
+/// every one of them, the way `ip` and `tc` do
+#[derive(Debug)]
+pub struct NetlinkSocket {
+    socket: Socket,
+    sequence: u32,
+}
+
+impl NetlinkSocket {
+    pub fn new(protocol: isize) -> io::Result<Self> {
+        let mut socket = Socket::new(protocol)?;
+        socket.bind_auto()?;
+        socket.connect(&SocketAddr::new(0, 0))?;
+        Ok(Self {
+            socket,
+            sequence: 0,
+        })
+    }
+
+    /// Opens the socket inside the namespace `netns` made by `ip netns add`,
+    /// or in the current one. A socket stays in the namespace it was opened
+    /// in, so only the opening switches namespaces.
+    pub fn in_netns(protocol: isize, netns: Option<&str>) -> io::Result<Self> {
+        match netns {
+            Some(netns) => with_netns(&Path::new(NETNS_DIR).join(netns), || Self::new(protocol)),
+            None => Self::new(protocol),
+        }
+    }
+
+    /// Sends `messages` in one go and collects what the kernel answers until
+    /// every message that asked for an ack or a dump is done. The first
+    /// error the kernel reports is returned.
+    pub fn request<I>(&mut self, messages: Vec<NetlinkMessage<I>>) -> io::Result<Vec<I>>
+    where
+        I: NetlinkSerializable + NetlinkDeserializable,
+    {
+        let mut pending = HashSet::new();
+        let mut buffer = Vec::new();
+        for mut message in messages {
+            self.sequence = self.sequence.wrapping_add(1);
+            message.header.sequence_number = self.sequence;
+            message.header.flags |= NLM_F_REQUEST;
+            if message.header.flags & (NLM_F_ACK | NLM_F_DUMP) != 0 {
+                pending.insert(self.sequence);
+            }
+            message.finalize();
+
+            let start = buffer.len();
+            buffer.resize(start + message.buffer_len(), 0);
+            message.serialize(&mut buffer[start..]);
+        }
+        self.socket.send(&buffer, 0)?;
+
+        let mut answers = Vec::new();
+        while !pending.is_empty() {
+            let (received, _) = self.socket.recv_from_full()?;
+            let mut offset = 0;
+            while offset < received.len() {
+                let message = NetlinkMessage::<I>::deserialize(&received[offset..])
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+                // Messages are 4 byte aligned
+                offset += (message.header.length as usize + 3) & !3;
+                if message.header.length == 0 {
+                    break;
+                }
+
+                let sequence = message.header.sequence_number;
+                if !pending.contains(&sequence) {
+                    // Left over from an earlier request that failed early
+                    continue;
+                }
+                match message.payload {
+                    NetlinkPayload::InnerMessage(answer) => answers.push(answer),
+                    NetlinkPayload::Done(_) => {
+                        pending.remove(&sequence);
+                    }
+                    NetlinkPayload::Error(err) if err.code.is_some() => return Err(err.to_io()),
+                    NetlinkPayload::Error(_) => {
+                        pending.remove(&sequence);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(answers)
+    }
+
+    /// Index of the interface called `name`, as seen from the socket's
+    /// namespace
+    pub fn link_index(&mut self, name: &str) -> io::Result<u32> {
+        let mut link = LinkMessage::default();
+        link.nlas.push(LinkNla::IfName(name.to_string()));
+        let answers = self.request(vec![message(RtnlMessage::GetLink(link), NLM_F_ACK)])?;
+        answers
+            .into_iter()
+            .find_map(|answer| match answer {
+                RtnlMessage::NewLink(link) => Some(link.header.index),
+                _ => None,
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, name.to_string()))
+    }
+
+    /// Creates an interface called `name` of `kind` and sets it up.
+    /// Returns whether it was created, an interface that exists already is
+    /// left as it is.
+    pub fn add_link(&mut self, name: &str, kind: InfoKind) -> io::Result<bool> {
+        let mut link = LinkMessage::default();
+        link.nlas.push(LinkNla::IfName(name.to_string()));
+        link.nlas.push(LinkNla::Info(vec![Info::Kind(kind)]));
+        link.header.flags = libc::IFF_UP as u32;
+        link.header.change_mask = libc::IFF_UP as u32;
+
+        let flags = NLM_F_ACK | libc::NLM_F_CREATE as u16 | libc::NLM_F_EXCL as u16;
+        match self.request(vec![message(RtnlMessage::NewLink(link), flags)]) {
+            Ok(_) => Ok(true),
+            Err(err) if err.raw_os_error() == Some(libc::EEXIST) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Every interface in the socket's namespace, by index
+    pub fn links(&mut self) -> io::Result<BTreeMap<u32, String>> {
+        let dump = message(RtnlMessage::GetLink(LinkMessage::default()), NLM_F_DUMP);
+        Ok(self
+            .request(vec![dump])?
+            .iter()
+            .filter_map(|answer| match answer {
+                RtnlMessage::NewLink(link) => Some((link.header.index, link_name(link)?)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    pub fn delete_link(&mut self, index: u32) -> io::Result<()> {
+        let mut link = LinkMessage::default();
+        link.header.index = index;
+        self.request(vec![message(RtnlMessage::DelLink(link), NLM_F_ACK)])?;
+        Ok(())
+    }
+}
+
+/// Name the kernel reports for `link`
+pub fn link_name(link: &LinkMessage) -> Option<String> {
+    link.nlas.iter().find_map(|nla| match nla {
+        LinkNla::IfName(name) => Some(name.clone()),
+        _ => None,
+    })
+}
+
+/// A request with `flags`, the sequence number is set when it is sent
+pub fn message<I>(inner: I, flags: u16) -> NetlinkMessage<I>
+where
+    I: NetlinkSerializable,
+{
+    let mut message = NetlinkMessage::new(
+        NetlinkHeader::default(),
+        NetlinkPayload::InnerMessage(inner),
+    );
+    message.header.flags = flags;
+    message
+}
+
+/// Runs `open` with the calling thread in the network namespace at `path`
+/// and switches back afterwards
+fn with_netns<T>(path: &Path, open: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    let current = File::open("/proc/thread-self/ns/net")?;
+    let target = File::open(path)?;
+    if unsafe { libc::setns(target.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let opened = open();
+    if unsafe { libc::setns(current.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        // The thread would keep running in the wrong namespace
+        panic!(
+            "Can't return to the original network namespace: {}",
+            io::Error::last_os_error()
+        );
+    }
+    opened
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+};
+
+use crate::{Error, control::Pid};
+
+pub fn pid_running(pid: Pid) -> bool {
+    Path::new("/proc").join(pid.0.to_string()).exists()
+}
+
+/// Parent of `pid` from `/proc/<pid>/stat`
+fn parent_of(pid: Pid) -> Option<Pid> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid.0)).ok()?;
+    parent_in_stat(&stat)
+}
+
+fn parent_in_stat(stat: &str) -> Option<Pid> {
+    // The command name before the state may hold spaces and parentheses
+    let (_, fields) = stat.rsplit_once(')')?;
+    let ppid = fields.split_whitespace().nth(1)?;
+    Pid::try_from(ppid).ok()
+}
+
+/// `root` followed by every process descended from it
+pub fn descendants(root: Pid) -> Vec<Pid> {
+    let mut children: HashMap<Pid, Vec<Pid>> = HashMap::new();
+    let entries = fs::read_dir("/proc").into_iter().flatten();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let Ok(pid) = Pid::try_from(entry.file_name().to_string_lossy().as_ref()) else {
+            continue;
+        };
+        if let Some(parent) = parent_of(pid) {
+            children.entry(parent).or_default().push(pid);
+        }
+    }
+
+    let mut tree = vec![root];
+    let mut next = 0;
+    while let Some(pid) = tree.get(next) {
+        if let Some(children) = children.get(pid) {
+            tree.extend(children.iter().copied());
+        }
+        next += 1;
+    }
+    tree
+}
+
+/// A process and its descendants. Children inherit the cgroup of their
+/// parent, so polling only turns up the ones forked while the tree was
+/// being moved.
+#[derive(Debug)]
+pub struct ProcessTree {
+    root: Pid,
+    /// Readable once the root exits, after which its pid may belong to an
+    /// unrelated process
+    pidfd: OwnedFd,
+    known: HashSet<Pid>,
+}
+
+impl ProcessTree {
+    pub fn new(root: Pid) -> Result<Self, Error> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, root.0, 0) };
+        if fd < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ESRCH) {
+                Err(Error::PidNotFound { pid: root.0 })?
+            }
+            Err(err)?
+        }
+        Ok(Self {
+            root,
+            pidfd: unsafe { OwnedFd::from_raw_fd(fd as i32) },
+            known: descendants(root).into_iter().collect(),
+        })
+    }
+
+    pub fn root(&self) -> Pid {
+        self.root
+    }
+
+    pub fn pids(&self) -> Vec<Pid> {
+        self.known.iter().copied().collect()
+    }
+
+    /// Descendants that appeared since the last poll. Orphans are reparented
+    /// away from the tree, but stay in the cgroup they were moved to, as do
+    /// the processes left once the root exits, which ends the polling.
+    pub fn poll(&mut self) -> Vec<Pid> {
+        let current: HashSet<Pid> = descendants(self.root).into_iter().collect();
+        // Checked after the lookup, so it can't have gone through a reused pid
+        if self.root_exited() {
+            return Vec::new();
+        }
+        let new = current.difference(&self.known).copied().collect();
+        self.known = current;
+        new
+    }
+
+    fn root_exited(&self) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd: self.pidfd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pollfd, 1, 0) != 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parent_follows_the_command_name() {
+        let stat = "4242 (bash) S 4100 4242 4242 34816 4242 4194304 1 0 0 0";
+        assert_eq!(parent_in_stat(stat), Some(Pid(4100)));
+    }
+
+    #[test]
+    fn command_names_may_hold_spaces_and_parentheses() {
+        let stat = "77 (a) b (c)) R 12 77 77 0 -1 4194560";
+        assert_eq!(parent_in_stat(stat), Some(Pid(12)));
+    }
+
+    #[test]
+    fn truncated_stat_has_no_parent() {
+        assert_eq!(parent_in_stat("77 (init) S"), None);
+        assert_eq!(parent_in_stat("77 init S 1"), None);
+        assert_eq!(parent_in_stat(""), None);
+    }
+
+    #[test]
+    fn tree_of_this_process() {
+        let me = Pid::from(std::process::id());
+        assert_eq!(
+            parent_of(me),
+            Some(Pid::from(std::os::unix::process::parent_id()))
+        );
+        let tree = ProcessTree::new(me).unwrap();
+        assert!(!tree.root_exited());
+        assert!(tree.pids().contains(&me));
+    }
+
+    #[test]
+    fn polling_stops_once_the_root_exits() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("5")
+            .spawn()
+            .unwrap();
+        let mut tree = ProcessTree::new(Pid::from(child.id())).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(tree.root_exited());
+        assert!(tree.poll().is_empty());
+    }
+}
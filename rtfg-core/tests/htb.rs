@@ -0,0 +1,108 @@
+//! Runs the HTB controller on a veth pair inside a network namespace of its
+//! own. It needs root, cgroup2 and the htb, ifb, fw, connmark and mirred tc
+//! modules, so it only runs when asked for:
+//! `cargo test -p rtfg-core --test htb -- --ignored`
+
+use std::process::Command;
+
+use rtfg_core::control::{CgroupName, HtbController, PolicyBuilder, RateController};
+
+const NETNS: &str = "rtfg-htb-test";
+const VETH: &str = "veth0";
+
+/// Runs `ip` or `tc` inside the test namespace
+fn run(program: &str, args: &[&str]) -> String {
+    let output = Command::new(program)
+        .args(["-n", NETNS])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{} {}: {}",
+        program,
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+/// The namespace with a veth pair in it, deleted with everything inside
+/// when dropped
+struct Namespace;
+
+impl Namespace {
+    fn new() -> Self {
+        let _ = Command::new("ip").args(["netns", "del", NETNS]).status();
+        let created = Command::new("ip")
+            .args(["netns", "add", NETNS])
+            .status()
+            .unwrap();
+        assert!(created.success());
+        run(
+            "ip",
+            &["link", "add", VETH, "type", "veth", "peer", "name", "veth1"],
+        );
+        run("ip", &["link", "set", VETH, "up"]);
+        Self
+    }
+}
+
+impl Drop for Namespace {
+    fn drop(&mut self) {
+        let _ = Command::new("ip").args(["netns", "del", NETNS]).status();
+    }
+}
+
+fn controller(name: &str) -> HtbController {
+    HtbController::new(CgroupName::new(name).unwrap(), VETH).in_netns(NETNS)
+}
+
+#[test]
+#[ignore = "needs root and a kernel with htb, ifb and the tc filters it uses"]
+fn classes_are_shared_and_removed_with_the_last_controller() {
+    let _netns = Namespace::new();
+    let policy = || PolicyBuilder::new().id(1).down(1000).up(500).build();
+
+    let mut first = controller("rtfg-htb-first");
+    first.apply_policy(policy()).unwrap();
+    // The same rule id must not land on the same class
+    let mut second = controller("rtfg-htb-second");
+    second.apply_policy(policy()).unwrap();
+
+    let classes = run("tc", &["class", "show", "dev", VETH]);
+    assert!(classes.contains("1:1"), "{}", classes);
+    assert!(classes.contains("1:2"), "{}", classes);
+    let classes = run("tc", &["class", "show", "dev", "rtfg-ifb"]);
+    assert!(
+        classes.contains("1:1") && classes.contains("1:2"),
+        "{}",
+        classes
+    );
+    let redirect = run("tc", &["filter", "show", "dev", VETH, "ingress"]);
+    assert!(redirect.contains("rtfg-ifb"), "{}", redirect);
+
+    first.close().unwrap();
+    let classes = run("tc", &["class", "show", "dev", VETH]);
+    assert!(!classes.contains("1:1") && classes.contains("1:2"));
+    assert!(run("ip", &["link", "show"]).contains("rtfg-ifb"));
+
+    second.close().unwrap();
+    let qdiscs = run("tc", &["qdisc", "show", "dev", VETH]);
+    assert!(!qdiscs.contains("htb"), "{}", qdiscs);
+    assert!(!qdiscs.contains("ingress"), "{}", qdiscs);
+    assert!(!run("ip", &["link", "show"]).contains("rtfg-ifb"));
+}
+
+#[test]
+#[ignore = "needs root and a kernel with htb, ifb and the tc filters it uses"]
+fn failed_apply_leaves_nothing_behind() {
+    let _netns = Namespace::new();
+    let mut htb = HtbController::new(CgroupName::new("rtfg-htb-missing").unwrap(), "missing0")
+        .in_netns(NETNS);
+    let policy = PolicyBuilder::new().id(1).down(1000).build();
+
+    assert!(htb.apply_policy(policy).is_err());
+    assert!(!run("ip", &["link", "show"]).contains("rtfg-ifb"));
+    htb.close().unwrap();
+}